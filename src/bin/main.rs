@@ -1,16 +1,109 @@
-use once_cell;
-use papaya::HashMap;
-use std::collections::HashSet;
 use thiserror::Error;
+use std::collections::{HashMap, VecDeque};
 use std::io::ErrorKind;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use bytes::{Buf as _, Bytes, BytesMut};
 use wdis::buffer::buf;
+use wdis::cmd_type::{self, Cmd, CommandSpec, COMMANDS};
+use wdis::resp::{self, Value, Version};
+use wdis::glob::glob_match;
+use wdis::keyspace::{now_ms, End, Entry, Keyspace, Kind, Writer};
+use wdis::options::{CompactionStrategy, Durability, EvictionPolicy, Options, ReadOptions};
+use wdis::reply::Reply;
+
+const DATA_DIR: &str = "wdis-data";
+
+/// Keys examined by one `scan` call when the client gives no `COUNT`.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// How often expired keys are reclaimed in the background.
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Most keys one active expiry cycle deletes before yielding.
+const ACTIVE_EXPIRE_LIMIT: usize = 200;
+
+/// Server settings taken from the command line:
+/// `main [--dir <path>] [--appendfsync always|everysec|no]
+/// [--compaction leveled|size-tiered] [--maxmemory <bytes>[kb|mb|gb]]
+/// [--maxmemory-policy <policy>] [--max-write-group-size <batches>]`.
+struct Config {
+    dir: String,
+    durability: Durability,
+    compaction_strategy: CompactionStrategy,
+    maxmemory: usize,
+    eviction_policy: EvictionPolicy,
+    max_write_group_size: usize,
+}
+
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Config> {
+        let mut config = Config {
+            dir: DATA_DIR.to_string(),
+            durability: Durability::EverySec,
+            compaction_strategy: CompactionStrategy::Leveled,
+            maxmemory: 0,
+            eviction_policy: EvictionPolicy::NoEviction,
+            max_write_group_size: Options::default().max_write_group_size,
+        };
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or(ServerError::InvalidArguments)?;
+            match flag.as_str() {
+                "--dir" => config.dir = value,
+                "--appendfsync" => {
+                    config.durability = value.parse().map_err(ServerError::Config)?
+                }
+                "--compaction" => {
+                    config.compaction_strategy = value.parse().map_err(ServerError::Config)?
+                }
+                "--maxmemory" => config.maxmemory = parse_memory(&value)?,
+                "--maxmemory-policy" => {
+                    config.eviction_policy = value.parse().map_err(ServerError::Config)?
+                }
+                "--max-write-group-size" => {
+                    config.max_write_group_size = match value.parse() {
+                        Ok(n) if n > 0 => n,
+                        _ => {
+                            return Err(ServerError::Config(format!(
+                                "invalid write group size: {}",
+                                value
+                            )))
+                        }
+                    }
+                }
+                _ => return Err(ServerError::Config(format!("unknown option: {}", flag))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Parses a byte count with an optional `k`, `kb`, `m`, `mb`, `g` or `gb`
+/// suffix, in powers of 1024.
+fn parse_memory(value: &str) -> Result<usize> {
+    let lower = value.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit: usize = match &lower[digits.len()..] {
+        "" => 1,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        "g" | "gb" => 1 << 30,
+        _ => return Err(ServerError::Config(format!("invalid memory size: {}", value))),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| ServerError::Config(format!("invalid memory size: {}", value)))
+}
+
 #[derive(Debug)]
 struct ClientMessage {
-    data: String,
+    data: Reply,
     response_sender: mpsc::Sender<Vec<u8>>,
 }
 
@@ -24,22 +117,168 @@ enum ServerError {
     IoError(#[from] std::io::Error),
     #[error("UTF-8 error: {0}")]
     Utf8Error(#[from] std::string::FromUtf8Error),
+    #[error("Invalid config: {0}")]
+    Config(String),
+    #[error("Value is not an integer or out of range")]
+    NotAnInteger,
+    #[error("Unsupported protocol version")]
+    NoProto,
+    #[error("Invalid expire time")]
+    InvalidExpireTime,
+    #[error("command not allowed when used memory > 'maxmemory'")]
+    OutOfMemory,
+    #[error("Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("timeout is not a float or out of range")]
+    InvalidTimeout,
 }
 
 type Result<T> = std::result::Result<T, ServerError>;
 
-const CMD_TYPES: once_cell::sync::Lazy<HashSet<&str>> = once_cell::sync::Lazy::new(|| {
-    ["get", "set", "del", "incr", "decr", "mget", "setnx"]
-        .iter()
-        .cloned()
-        .collect()
-});
+impl ServerError {
+    /// The error reply sent to the client.
+    fn reply(&self) -> Reply {
+        match self {
+            ServerError::NoProto => Reply::error("NOPROTO", "unsupported protocol version"),
+            ServerError::OutOfMemory => Reply::error("OOM", self.to_string()),
+            ServerError::WrongType => Reply::error("WRONGTYPE", self.to_string()),
+            e => Reply::error("ERR", e.to_string()),
+        }
+    }
+}
+
+/// What running a request yields: a reply, or for a blocking pop that found
+/// nothing to pop, a reply that comes later.
+enum Outcome {
+    Ready(Reply),
+    Suspended(Suspended),
+}
+
+/// A client parked by `WaitQueues::park` until it is served an element or
+/// `deadline` passes.
+struct Suspended {
+    id: u64,
+    end: End,
+    receiver: oneshot::Receiver<(Vec<u8>, Vec<u8>)>,
+    deadline: Option<Instant>,
+}
+
+/// Clients blocked in `blpop` or `brpop`, parked in a queue per key they wait
+/// on and served in the order they came.
+#[derive(Default)]
+struct WaitQueues {
+    state: Mutex<WaitState>,
+}
+
+#[derive(Default)]
+struct WaitState {
+    next_id: u64,
+    queues: HashMap<Vec<u8>, VecDeque<u64>>,
+    waiters: HashMap<u64, Waiter>,
+}
+
+struct Waiter {
+    keys: Vec<Vec<u8>>,
+    end: End,
+    /// Takes the key and the element popped for the client.
+    sender: oneshot::Sender<(Vec<u8>, Vec<u8>)>,
+}
+
+impl WaitState {
+    fn remove(&mut self, id: u64) -> Option<Waiter> {
+        let waiter = self.waiters.remove(&id)?;
+        for key in &waiter.keys {
+            if let Some(queue) = self.queues.get_mut(key) {
+                queue.retain(|&queued| queued != id);
+                if queue.is_empty() {
+                    self.queues.remove(key);
+                }
+            }
+        }
+        Some(waiter)
+    }
+}
+
+impl WaitQueues {
+    /// Parks a client on the queues of `keys`. Called with the `Writer` that
+    /// found them all empty, so that no push comes in between.
+    fn park(&self, keys: &[Bytes], end: End, deadline: Option<Instant>) -> Suspended {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        for key in keys {
+            state.queues.entry(key.to_vec()).or_default().push_back(id);
+        }
+        let (sender, receiver) = oneshot::channel();
+        let keys = keys.iter().map(|key| key.to_vec()).collect();
+        state.waiters.insert(id, Waiter { keys, end, sender });
+        Suspended {
+            id,
+            end,
+            receiver,
+            deadline,
+        }
+    }
+
+    /// Pops elements of the list at `key` for the clients parked on it, first
+    /// come first served, while there are any. Called with the `Writer` that
+    /// pushed them.
+    fn serve(&self, key: &[u8], writer: &mut Writer) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while let Some(&id) = state.queues.get(key).and_then(VecDeque::front) {
+            let end = state.waiters[&id].end;
+            let Some(value) = writer.pop(key, end)? else {
+                break;
+            };
+            let waiter = state.remove(id).unwrap();
+            if let Err((_, value)) = waiter.sender.send((key.to_vec(), value)) {
+                writer.push(key, &[&value], end)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Unparks a client. Returns false if it was served meanwhile, in which
+    /// case its element is in its receiver.
+    fn cancel(&self, id: u64) -> bool {
+        self.state.lock().unwrap().remove(id).is_some()
+    }
+}
 
 /// Handles client connections and processes incoming commands
+///
+/// The protocol is told apart by the first byte: a framed request starts with
+/// a big-endian argument count, whose high byte is zero for any real request,
+/// while a RESP request starts with `*` or, inline, with a command name.
 async fn producer(
+    stream: TcpStream,
+    sender: mpsc::Sender<ClientMessage>,
+    store: Arc<Keyspace>,
+    blocked: Arc<WaitQueues>,
+) -> Result<()> {
+    let mut first = [0; 1];
+    match stream.peek(&mut first).await {
+        Ok(0) => {
+            println!("Client disconnected");
+            Ok(())
+        }
+        Ok(_) if first[0] == 0 => framed_producer(stream, sender, store, blocked).await,
+        Ok(_) => resp_producer(stream, store, blocked).await,
+        Err(e) => {
+            eprintln!("Read error: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// Serves a client speaking the framed protocol: requests are a u32 argument
+/// count followed by u32 length-prefixed arguments, replies are framed
+/// `Reply`s.
+async fn framed_producer(
     mut stream: TcpStream,
     sender: mpsc::Sender<ClientMessage>,
-    map: Arc<HashMap<String, String>>,
+    store: Arc<Keyspace>,
+    blocked: Arc<WaitQueues>,
 ) -> Result<()> {
     let (response_tx, mut response_rx) = mpsc::channel(32);
 
@@ -74,59 +313,290 @@ async fn producer(
             eprintln!("Failed to read data: {}", e);
             return Ok(());
         }
-            cmd.push(String::from_utf8_lossy(&buf.data).to_string());
+            cmd.push(Bytes::from(buf.data.into_vec()));
         }
 
-        if !CMD_TYPES.contains(&cmd[0].as_str()) {
-            println!("Invalid command");
+        let Some(reply) = respond(&cmd, &stream, &store, &blocked).await else {
+            println!("Client disconnected");
+            return Ok(());
+        };
+        if !send_reply(&mut stream, &sender, &response_tx, &mut response_rx, reply).await {
             return Ok(());
         }
-        let map = map.clone();
-        
-        let response = match cmd[0].as_str() {
-            "get" => get(cmd[1].as_str(), map.clone()).await,
-            "set" => set(cmd[1].as_str(), cmd[2].as_str(), map.clone()).await,
-            _ => {
-                println!("Invalid number of arguments");
+    }
+}
+
+/// Serves a client speaking RESP. The connection starts in RESP2 and
+/// switches with `HELLO`.
+async fn resp_producer(
+    mut stream: TcpStream,
+    store: Arc<Keyspace>,
+    blocked: Arc<WaitQueues>,
+) -> Result<()> {
+    let mut buf = BytesMut::with_capacity(4096);
+    let mut version = Version::Resp2;
+
+    loop {
+        let args = match resp::parse_command(&buf) {
+            Ok(Some((args, n))) => {
+                buf.advance(n);
+                args
+            }
+            Ok(None) => {
+                match stream.read_buf(&mut buf).await {
+                    Ok(0) => {
+                        println!("Client disconnected");
+                        return Ok(());
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        eprintln!("Read error: {}", e);
+                        return Ok(());
+                    }
+                }
+                continue;
+            }
+            // The stream cannot be resynchronized; report and hang up.
+            Err(e) => {
+                let mut out = Vec::new();
+                Value::Error(format!("ERR {}", e)).encode(version, &mut out);
+                let _ = stream.write_all(&out).await;
                 return Ok(());
             }
         };
+        if args.is_empty() {
+            continue;
+        }
+        let cmd: Vec<Bytes> = args.into_iter().map(Bytes::from).collect();
 
-        let msg = ClientMessage {
-            data: response,
-            response_sender: response_tx.clone(),
+        let reply = match cmd_type::lookup(&cmd[0]) {
+            Some(spec) if spec.cmd == Cmd::Hello => match hello(&cmd[1..], version) {
+                Ok((new_version, reply)) => {
+                    version = new_version;
+                    reply
+                }
+                Err(e) => e.reply().into(),
+            },
+            _ => match respond(&cmd, &stream, &store, &blocked).await {
+                Some(reply) => reply.into(),
+                None => {
+                    println!("Client disconnected");
+                    return Ok(());
+                }
+            },
         };
 
-        if let Err(e) = sender.send(msg).await {
-            eprintln!("Failed to send message to consumer: {}", e);
+        let mut out = Vec::new();
+        reply.encode(version, &mut out);
+        if let Err(e) = stream.write_all(&out).await {
+            eprintln!("Failed to send response: {}", e);
             return Ok(());
         }
+    }
+}
 
-        // Wait for response from consumer
-        if let Some(response) = response_rx.recv().await {
-            let response_len = response.len() as u32;
-            let len_bytes = response_len.to_be_bytes();
+/// Runs one request and waits out a suspended reply. Returns `None` if the
+/// client disconnected meanwhile.
+async fn respond(
+    cmd: &[Bytes],
+    stream: &TcpStream,
+    store: &Arc<Keyspace>,
+    blocked: &Arc<WaitQueues>,
+) -> Option<Reply> {
+    match dispatch(cmd, store, blocked).await {
+        Outcome::Ready(reply) => Some(reply),
+        Outcome::Suspended(suspended) => wait(suspended, stream, store, blocked).await,
+    }
+}
 
-            if let Err(e) = stream.write_all(&len_bytes).await {
-                eprintln!("Failed to send response length: {}", e);
-                return Ok(());
+/// Runs one request, the command name followed by its arguments. The command
+/// runs on the blocking thread pool, see `blocking`.
+async fn dispatch(cmd: &[Bytes], store: &Arc<Keyspace>, blocked: &Arc<WaitQueues>) -> Outcome {
+    let Some(spec) = cmd.first().and_then(|name| cmd_type::lookup(name)) else {
+        return Outcome::Ready(ServerError::InvalidCommand.reply());
+    };
+    let (args, store, blocked) = (cmd[1..].to_vec(), store.clone(), blocked.clone());
+    match blocking(move || execute(spec, &args, &store, &blocked)).await {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            if let ServerError::IoError(e) = &e {
+                eprintln!("Storage error: {}", e);
             }
+            Outcome::Ready(e.reply())
+        }
+    }
+}
 
-            if let Err(e) = stream.write_all(&response).await {
-                eprintln!("Failed to send response: {}", e);
-                return Ok(());
+/// Runs `f` on the blocking thread pool, for work that reads or writes the
+/// store or takes a lock held while the store is written. Storage I/O, a log
+/// sync and waiting for the write lock would otherwise stall every connection
+/// served by the same runtime worker.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(value) => value,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Waits until a parked client is served, its timeout passes or it
+/// disconnects. In the last case, `None` is returned and an element it was
+/// served meanwhile goes back to its list.
+async fn wait(
+    mut suspended: Suspended,
+    stream: &TcpStream,
+    store: &Arc<Keyspace>,
+    blocked: &Arc<WaitQueues>,
+) -> Option<Reply> {
+    let deadline = suspended.deadline;
+    let timeout = async move {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(timeout);
+    // Watch for a disconnect until the client sends more requests, which then
+    // wait their turn.
+    let mut watch = true;
+    let mut probe = [0; 1];
+    loop {
+        tokio::select! {
+            served = &mut suspended.receiver => {
+                return Some(served.map_or(Reply::Nil, |(key, value)| popped(key, value)));
             }
+            _ = &mut timeout => break,
+            read = stream.peek(&mut probe), if watch => match read {
+                Ok(0) | Err(_) => {
+                    let (store, blocked) = (store.clone(), blocked.clone());
+                    blocking(move || abandon(suspended, &store, &blocked)).await;
+                    return None;
+                }
+                Ok(_) => watch = false,
+            },
+        }
+    }
+    let (id, waiting) = (suspended.id, blocked.clone());
+    if blocking(move || waiting.cancel(id)).await {
+        return Some(Reply::Nil);
+    }
+    Some(
+        suspended
+            .receiver
+            .try_recv()
+            .map_or(Reply::Nil, |(key, value)| popped(key, value)),
+    )
+}
+
+/// Unparks a client that disconnected, pushing an element it was served
+/// meanwhile back to its list.
+fn abandon(mut suspended: Suspended, store: &Keyspace, blocked: &WaitQueues) {
+    if blocked.cancel(suspended.id) {
+        return;
+    }
+    if let Ok((key, value)) = suspended.receiver.try_recv() {
+        if let Err(e) = give_back(&key, &value, suspended.end, store, blocked) {
+            eprintln!("Failed to give back a popped element: {}", e);
+        }
+    }
+}
+
+/// Pushes an element served to a client that is gone back where it came
+/// from, failing with `WrongType` if the key has changed kind since.
+fn give_back(
+    key: &[u8],
+    value: &[u8],
+    end: End,
+    store: &Keyspace,
+    blocked: &WaitQueues,
+) -> Result<()> {
+    let mut writer = store.write();
+    check_kind(writer.get(key)?, Kind::List)?;
+    writer.push(key, &[value], end)?;
+    blocked.serve(key, &mut writer)
+}
+
+/// Runs one command, given its arguments after the name.
+fn execute(
+    spec: &CommandSpec,
+    args: &[Bytes],
+    store: &Keyspace,
+    blocked: &WaitQueues,
+) -> Result<Outcome> {
+    let reply = match spec.cmd {
+        _ if !spec.arity.accepts(args.len() + 1) => Err(ServerError::InvalidArguments),
+        Cmd::Get => get(&args[0], store),
+        Cmd::Set => set(args, store),
+        Cmd::Del => del(args, store),
+        Cmd::Incr => incr_by(&args[0], 1, store),
+        Cmd::Decr => incr_by(&args[0], -1, store),
+        Cmd::Mget => mget(args, store),
+        Cmd::Setnx => setnx(&args[0], &args[1], store),
+        Cmd::Scan => scan(args, store),
+        Cmd::Expire => expire(&args[0], &args[1], 1000, store),
+        Cmd::Pexpire => expire(&args[0], &args[1], 1, store),
+        Cmd::Ttl => ttl(&args[0], 1000, store),
+        Cmd::Pttl => ttl(&args[0], 1, store),
+        Cmd::Persist => persist(&args[0], store),
+        Cmd::Command => command(args),
+        Cmd::Info => info(args, store),
+        Cmd::Hset => hset(args, store),
+        Cmd::Hget => hget(&args[0], &args[1], store),
+        Cmd::Hdel => hdel(args, store),
+        Cmd::Hgetall => hgetall(&args[0], store),
+        Cmd::Hincrby => hincr_by(&args[0], &args[1], &args[2], store),
+        Cmd::Hscan => hscan(args, store),
+        Cmd::Lpush => push(args, End::Left, store, blocked),
+        Cmd::Rpush => push(args, End::Right, store, blocked),
+        Cmd::Lpop => pop(&args[0], End::Left, store),
+        Cmd::Rpop => pop(&args[0], End::Right, store),
+        Cmd::Lrange => lrange(&args[0], &args[1], &args[2], store),
+        Cmd::Llen => llen(&args[0], store),
+        Cmd::Ltrim => ltrim(&args[0], &args[1], &args[2], store),
+        Cmd::Blpop => return blocking_pop(args, End::Left, store, blocked),
+        Cmd::Brpop => return blocking_pop(args, End::Right, store, blocked),
+        // Only a RESP connection can switch protocols.
+        Cmd::Hello => Err(ServerError::NoProto),
+    };
+    reply.map(Outcome::Ready)
+}
+
+/// Sends one reply through the consumer and writes what it returns to the
+/// client. Returns false once the connection should be dropped.
+async fn send_reply(
+    stream: &mut TcpStream,
+    sender: &mpsc::Sender<ClientMessage>,
+    response_tx: &mpsc::Sender<Vec<u8>>,
+    response_rx: &mut mpsc::Receiver<Vec<u8>>,
+    reply: Reply,
+) -> bool {
+    let msg = ClientMessage {
+        data: reply,
+        response_sender: response_tx.clone(),
+    };
+
+    if let Err(e) = sender.send(msg).await {
+        eprintln!("Failed to send message to consumer: {}", e);
+        return false;
+    }
+
+    // Wait for response from consumer
+    if let Some(response) = response_rx.recv().await {
+        if let Err(e) = stream.write_all(&response).await {
+            eprintln!("Failed to send response: {}", e);
+            return false;
         }
     }
+    true
 }
 
 /// Processes messages from producers and sends responses back
 async fn consumer(mut receiver: mpsc::Receiver<ClientMessage>) {
     while let Some(msg) = receiver.recv().await {
         // Process message
-        let response = format!("Server received: {}", msg.data);
+        let mut response = Vec::new();
+        msg.data.encode_frame(&mut response);
         // Send response back to producer
-        if let Err(e) = msg.response_sender.send(response.into_bytes()).await {
+        if let Err(e) = msg.response_sender.send(response).await {
             eprintln!("{}", e);
         }
     }
@@ -134,7 +604,20 @@ async fn consumer(mut receiver: mpsc::Receiver<ClientMessage>) {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let map: Arc<HashMap<String, String>> = Arc::new(HashMap::new());
+    let config = Config::from_args(std::env::args().skip(1))?;
+    let options = Options {
+        durability: config.durability,
+        compaction_strategy: config.compaction_strategy,
+        maxmemory: config.maxmemory,
+        eviction_policy: config.eviction_policy,
+        max_write_group_size: config.max_write_group_size,
+        ..Options::default()
+    };
+    let store = Arc::new(Keyspace::open_with(&config.dir, options)?);
+
+    tokio::spawn(active_expire(store.clone()));
+
+    let blocked = Arc::new(WaitQueues::default());
 
     let (tx, rx) = mpsc::channel(32);
 
@@ -147,37 +630,1035 @@ async fn main() -> Result<()> {
     loop {
         let (stream, _) = listener.accept().await.unwrap();
         let sender = tx.clone();
-        let map = map.clone();
+        let store = store.clone();
+        let blocked = blocked.clone();
         tokio::spawn(async move {
-            producer(stream, sender, map).await.unwrap();
+            producer(stream, sender, store, blocked).await.unwrap();
         });
     }
 }
 
 
+/// Reclaims expired keys in the background, so keys that are never read
+/// again do not take up space forever.
+async fn active_expire(store: Arc<Keyspace>) {
+    let mut interval = tokio::time::interval(ACTIVE_EXPIRE_INTERVAL);
+    loop {
+        interval.tick().await;
+        // Keep going while whole batches expire, yielding in between.
+        loop {
+            let cycle = store.clone();
+            match blocking(move || cycle.expire_cycle(now_ms(), ACTIVE_EXPIRE_LIMIT)).await {
+                Ok(ACTIVE_EXPIRE_LIMIT) => tokio::task::yield_now().await,
+                Ok(_) => break,
+                Err(e) => {
+                    eprintln!("Storage error: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
 /// Get value by key from the data store
-async fn get(key: &str, map: Arc<HashMap<String, String>>) -> String {
-    let map = map.pin_owned();
-    match map.get(key) {
-        Some(value) => value.clone(),
-        None => "error".to_string()
+fn get(key: &[u8], store: &Keyspace) -> Result<Reply> {
+    match check_kind(store.get(key)?, Kind::String)? {
+        Some(entry) => Ok(Reply::Bulk(entry.value)),
+        None => Ok(Reply::Nil),
+    }
+}
+
+/// Set key-value pair in the data store: `set key value [ex seconds|px ms]`.
+/// Without `ex` or `px` the key no longer expires.
+fn set(args: &[Bytes], store: &Keyspace) -> Result<Reply> {
+    let expires_at = match &args[2..] {
+        [] => None,
+        [unit, amount] => {
+            let scale = match unit.to_ascii_lowercase().as_slice() {
+                b"ex" => 1000,
+                b"px" => 1,
+                _ => return Err(ServerError::InvalidArguments),
+            };
+            let amount = parse_int(amount).ok_or(ServerError::NotAnInteger)?;
+            match expires_at(amount, scale)? {
+                Some(at) => Some(at),
+                None => return Err(ServerError::InvalidExpireTime),
+            }
+        }
+        _ => return Err(ServerError::InvalidArguments),
+    };
+    write_with_room(store)?.put(&args[0], &Entry::new(&args[1][..], expires_at))?;
+    Ok(Reply::Ok)
+}
+
+/// Delete keys from the data store: `del key [key ...]`. Replies with the
+/// number of keys that existed.
+fn del(keys: &[Bytes], store: &Keyspace) -> Result<Reply> {
+    let mut writer = store.write();
+    let mut deleted = 0;
+    for key in keys {
+        if writer.get(key)?.is_some() {
+            writer.delete(key)?;
+            deleted += 1;
+        }
+    }
+    Ok(Reply::Integer(deleted))
+}
+
+/// Add `delta` to the integer stored at key, treating a missing key as 0, and
+/// reply with the new value. Fails without writing if the stored value is not
+/// a decimal `i64` or the result would overflow. The expiry time is kept.
+fn incr_by(key: &[u8], delta: i64, store: &Keyspace) -> Result<Reply> {
+    let mut writer = write_with_room(store)?;
+    let (current, expires_at) = match check_kind(writer.get(key)?, Kind::String)? {
+        Some(entry) => (
+            parse_int::<i64>(&entry.value).ok_or(ServerError::NotAnInteger)?,
+            entry.expires_at,
+        ),
+        None => (0, None),
+    };
+    let value = current.checked_add(delta).ok_or(ServerError::NotAnInteger)?;
+    writer.put(key, &Entry::new(value.to_string(), expires_at))?;
+    Ok(Reply::Integer(value))
+}
+
+/// Get the values of several keys, one array element per key in request
+/// order, nil for a key that is missing or does not hold a string.
+fn mget(keys: &[Bytes], store: &Keyspace) -> Result<Reply> {
+    let snapshot = store.db().snapshot();
+    let options = ReadOptions {
+        snapshot: Some(snapshot),
+        ..ReadOptions::default()
+    };
+    keys.iter()
+        .map(|key| match store.get_with(&options, key)? {
+            Some(entry) if entry.kind == Kind::String => Ok(Reply::Bulk(entry.value)),
+            _ => Ok(Reply::Nil),
+        })
+        .collect::<Result<_>>()
+        .map(Reply::Array)
+}
+
+/// Set key only if it does not exist yet. Replies 1 if it was set, else 0.
+fn setnx(key: &[u8], value: &[u8], store: &Keyspace) -> Result<Reply> {
+    let mut writer = write_with_room(store)?;
+    if writer.get(key)?.is_some() {
+        return Ok(Reply::Integer(0));
+    }
+    writer.put(key, &Entry::new(value, None))?;
+    Ok(Reply::Integer(1))
+}
+
+/// Make key expire `amount` units of `scale` milliseconds from now:
+/// `expire key seconds` or `pexpire key ms`. A time that is not in the future
+/// deletes the key. Replies 1 if the key exists, else 0.
+fn expire(key: &[u8], amount: &[u8], scale: i64, store: &Keyspace) -> Result<Reply> {
+    let amount = parse_int(amount).ok_or(ServerError::InvalidArguments)?;
+    let expires_at = expires_at(amount, scale)?;
+    let mut writer = store.write();
+    let Some(entry) = writer.get(key)? else {
+        return Ok(Reply::Integer(0));
+    };
+    match expires_at {
+        Some(at) => writer.put(
+            key,
+            &Entry {
+                expires_at: Some(at),
+                ..entry
+            },
+        )?,
+        None => writer.delete(key)?,
     }
+    Ok(Reply::Integer(1))
 }
 
-/// Set key-value pair in the data store
-async fn set(key: &str, value: &str, map: Arc<HashMap<String, String>>) -> String {
-    let map = map.pin_owned();
-    match map.insert(key.to_string(), value.to_string()) {
-        Some(_) => "already exists".to_string(),
-        None => "OK".to_string()
+/// Time to live of key in units of `scale` milliseconds, rounded to the
+/// nearest: `ttl key` or `pttl key`. Replies -2 if the key does not exist and
+/// -1 if it does not expire.
+fn ttl(key: &[u8], scale: u64, store: &Keyspace) -> Result<Reply> {
+    let ttl = match store.get(key)? {
+        None => -2,
+        Some(Entry {
+            expires_at: None, ..
+        }) => -1,
+        Some(Entry {
+            expires_at: Some(at),
+            ..
+        }) => ((at.saturating_sub(now_ms()) + scale / 2) / scale) as i64,
+    };
+    Ok(Reply::Integer(ttl))
+}
+
+/// Remove the expiry time of key. Replies 1 if it had one, else 0.
+fn persist(key: &[u8], store: &Keyspace) -> Result<Reply> {
+    let mut writer = store.write();
+    match writer.get(key)? {
+        Some(entry) if entry.expires_at.is_some() => {
+            writer.put(
+                key,
+                &Entry {
+                    expires_at: None,
+                    ..entry
+                },
+            )?;
+            Ok(Reply::Integer(1))
+        }
+        _ => Ok(Reply::Integer(0)),
     }
 }
 
-/// Delete key from the data store
-async fn del(key: &str, map: Arc<HashMap<String, String>>) -> String {
-    let map = map.pin_owned();
-    match map.remove(key) {
-        Some(_) => "OK".to_string(),
-        None => "error".to_string()
+/// The absolute time `amount * scale` milliseconds from now, or `None` if
+/// that is not in the future.
+fn expires_at(amount: i64, scale: i64) -> Result<Option<u64>> {
+    let ms = amount
+        .checked_mul(scale)
+        .ok_or(ServerError::InvalidExpireTime)?;
+    if ms <= 0 {
+        return Ok(None);
+    }
+    now_ms()
+        .checked_add(ms as u64)
+        .map(Some)
+        .ok_or(ServerError::InvalidExpireTime)
+}
+
+/// Walk the keyspace: `scan cursor [match pattern] [count n]`.
+///
+/// Examines up to `count` keys starting at `cursor` and replies with the next
+/// cursor and an array of the examined keys that match `pattern`. The cursor
+/// `0` starts a scan, and a reply cursor of `0` ends it.
+///
+/// Any other cursor is the hex-encoded key the next call starts at, so a scan
+/// resumes in place however the keyspace changed in between: every key present
+/// for the whole scan is returned exactly once, and keys written or deleted
+/// meanwhile may or may not be.
+fn scan(args: &[Bytes], store: &Keyspace) -> Result<Reply> {
+    let (cursor, options) = args.split_first().ok_or(ServerError::InvalidArguments)?;
+    let (pattern, count) = scan_options(options)?;
+
+    let mut iter = store.iter(ReadOptions::default());
+    match decode_cursor(cursor)? {
+        None => iter.seek_to_first(),
+        Some(key) => iter.seek(&key),
+    }
+    let mut keys = Vec::new();
+    for _ in 0..count {
+        if !iter.valid() {
+            break;
+        }
+        if pattern.is_none_or(|p| glob_match(p, iter.key())) {
+            keys.push(Reply::bulk(iter.key()));
+        }
+        iter.next();
+    }
+    iter.status()?;
+
+    // At least one key was examined, so the next one is never the empty key
+    // and its encoding has an even, non-zero length, unlike the cursor `0`.
+    let next = match iter.valid() {
+        true => encode_cursor(iter.key()),
+        false => "0".to_string(),
+    };
+    Ok(Reply::Array(vec![Reply::bulk(next), Reply::Array(keys)]))
+}
+
+/// Set fields of the hash at key, creating it if needed:
+/// `hset key field value [field value ...]`. Replies with the number of
+/// fields that are new.
+fn hset(args: &[Bytes], store: &Keyspace) -> Result<Reply> {
+    let (key, pairs) = args.split_first().ok_or(ServerError::InvalidArguments)?;
+    if !pairs.len().is_multiple_of(2) {
+        return Err(ServerError::InvalidArguments);
+    }
+    let pairs: Vec<(&[u8], &[u8])> = pairs
+        .chunks(2)
+        .map(|pair| (&pair[0][..], &pair[1][..]))
+        .collect();
+    let mut writer = write_with_room(store)?;
+    check_kind(writer.get(key)?, Kind::Hash)?;
+    Ok(Reply::Integer(writer.hset(key, &pairs)? as i64))
+}
+
+/// Get the value of a field of the hash at key.
+fn hget(key: &[u8], field: &[u8], store: &Keyspace) -> Result<Reply> {
+    let options = ReadOptions {
+        snapshot: Some(store.db().snapshot()),
+        ..ReadOptions::default()
+    };
+    if check_kind(store.get_with(&options, key)?, Kind::Hash)?.is_none() {
+        return Ok(Reply::Nil);
+    }
+    match store.get_field_with(&options, key, field)? {
+        Some(value) => Ok(Reply::Bulk(value)),
+        None => Ok(Reply::Nil),
+    }
+}
+
+/// Delete fields of the hash at key: `hdel key field [field ...]`. The hash
+/// goes with its last field. Replies with the number of fields deleted.
+fn hdel(args: &[Bytes], store: &Keyspace) -> Result<Reply> {
+    let (key, fields) = args.split_first().ok_or(ServerError::InvalidArguments)?;
+    let fields: Vec<&[u8]> = fields.iter().map(|field| &field[..]).collect();
+    let mut writer = store.write();
+    check_kind(writer.get(key)?, Kind::Hash)?;
+    Ok(Reply::Integer(writer.hdel(key, &fields)? as i64))
+}
+
+/// Get all fields and values of the hash at key, as a flat array of fields
+/// each followed by its value, in field order.
+fn hgetall(key: &[u8], store: &Keyspace) -> Result<Reply> {
+    let options = ReadOptions {
+        snapshot: Some(store.db().snapshot()),
+        ..ReadOptions::default()
+    };
+    let mut items = Vec::new();
+    if check_kind(store.get_with(&options, key)?, Kind::Hash)?.is_none() {
+        return Ok(Reply::Array(items));
+    }
+    let mut iter = store.fields(options, key);
+    iter.seek_to_first();
+    while iter.valid() {
+        items.push(Reply::bulk(iter.field()));
+        items.push(Reply::bulk(iter.value()));
+        iter.next();
+    }
+    iter.status()?;
+    Ok(Reply::Array(items))
+}
+
+/// Add `delta` to the integer in a field of the hash at key, treating a
+/// missing field as 0, and reply with the new value. Fails without writing
+/// if the field does not hold a decimal `i64` or the result would overflow.
+fn hincr_by(key: &[u8], field: &[u8], delta: &[u8], store: &Keyspace) -> Result<Reply> {
+    let delta: i64 = parse_int(delta).ok_or(ServerError::NotAnInteger)?;
+    let mut writer = write_with_room(store)?;
+    let current: i64 = match check_kind(writer.get(key)?, Kind::Hash)? {
+        Some(_) => match writer.get_field(key, field)? {
+            Some(value) => parse_int(&value).ok_or(ServerError::NotAnInteger)?,
+            None => 0,
+        },
+        None => 0,
+    };
+    let value = current.checked_add(delta).ok_or(ServerError::NotAnInteger)?;
+    writer.hset(key, &[(field, value.to_string().as_bytes())])?;
+    Ok(Reply::Integer(value))
+}
+
+/// Walk the fields of the hash at key:
+/// `hscan key cursor [match pattern] [count n]`. Works like `scan` over the
+/// fields, replying with the next cursor and a flat array of the matching
+/// fields each followed by its value.
+fn hscan(args: &[Bytes], store: &Keyspace) -> Result<Reply> {
+    let [key, cursor, options @ ..] = args else {
+        return Err(ServerError::InvalidArguments);
+    };
+    let (pattern, count) = scan_options(options)?;
+    let start = decode_cursor(cursor)?;
+
+    let options = ReadOptions {
+        snapshot: Some(store.db().snapshot()),
+        ..ReadOptions::default()
+    };
+    let mut items = Vec::new();
+    if check_kind(store.get_with(&options, key)?, Kind::Hash)?.is_none() {
+        return Ok(Reply::Array(vec![Reply::bulk("0"), Reply::Array(items)]));
+    }
+    let mut iter = store.fields(options, key);
+    match start {
+        None => iter.seek_to_first(),
+        Some(field) => iter.seek(&field),
+    }
+    for _ in 0..count {
+        if !iter.valid() {
+            break;
+        }
+        if pattern.is_none_or(|p| glob_match(p, iter.field())) {
+            items.push(Reply::bulk(iter.field()));
+            items.push(Reply::bulk(iter.value()));
+        }
+        iter.next();
+    }
+    iter.status()?;
+
+    // As in `scan`, the next field is never the empty one.
+    let next = match iter.valid() {
+        true => encode_cursor(iter.field()),
+        false => "0".to_string(),
+    };
+    Ok(Reply::Array(vec![Reply::bulk(next), Reply::Array(items)]))
+}
+
+/// Push values onto an end of the list at key, one at a time, creating the
+/// list if needed: `lpush key value [value ...]` or `rpush`. Replies with the
+/// length of the list, then hands elements to clients blocked on it.
+fn push(args: &[Bytes], end: End, store: &Keyspace, blocked: &WaitQueues) -> Result<Reply> {
+    let (key, values) = args.split_first().ok_or(ServerError::InvalidArguments)?;
+    let values: Vec<&[u8]> = values.iter().map(|value| &value[..]).collect();
+    let mut writer = write_with_room(store)?;
+    check_kind(writer.get(key)?, Kind::List)?;
+    let len = writer.push(key, &values, end)?;
+    blocked.serve(key, &mut writer)?;
+    Ok(Reply::Integer(len as i64))
+}
+
+/// Pop the element at an end of the list at key: `lpop key` or `rpop key`.
+/// The list goes with its last element.
+fn pop(key: &[u8], end: End, store: &Keyspace) -> Result<Reply> {
+    let mut writer = store.write();
+    check_kind(writer.get(key)?, Kind::List)?;
+    match writer.pop(key, end)? {
+        Some(value) => Ok(Reply::Bulk(value)),
+        None => Ok(Reply::Nil),
+    }
+}
+
+/// Get the elements of the list at key from index `start` to `stop`
+/// inclusive: `lrange key start stop`. Negative indexes count from the end,
+/// -1 being the last element.
+fn lrange(key: &[u8], start: &[u8], stop: &[u8], store: &Keyspace) -> Result<Reply> {
+    let start = parse_int(start).ok_or(ServerError::NotAnInteger)?;
+    let stop = parse_int(stop).ok_or(ServerError::NotAnInteger)?;
+    let options = ReadOptions {
+        snapshot: Some(store.db().snapshot()),
+        ..ReadOptions::default()
+    };
+    if check_kind(store.get_with(&options, key)?, Kind::List)?.is_none() {
+        return Ok(Reply::Array(Vec::new()));
+    }
+    let values = store.list_range(options, key, start, stop)?;
+    Ok(Reply::Array(values.into_iter().map(Reply::Bulk).collect()))
+}
+
+/// Get the length of the list at key, 0 if it does not exist.
+fn llen(key: &[u8], store: &Keyspace) -> Result<Reply> {
+    let len = match check_kind(store.get(key)?, Kind::List)? {
+        Some(entry) => entry.item_count()?,
+        None => 0,
+    };
+    Ok(Reply::Integer(len as i64))
+}
+
+/// Keep only the elements of the list at key from index `start` to `stop`
+/// inclusive, counted as in `lrange`: `ltrim key start stop`.
+fn ltrim(key: &[u8], start: &[u8], stop: &[u8], store: &Keyspace) -> Result<Reply> {
+    let start = parse_int(start).ok_or(ServerError::NotAnInteger)?;
+    let stop = parse_int(stop).ok_or(ServerError::NotAnInteger)?;
+    let mut writer = store.write();
+    check_kind(writer.get(key)?, Kind::List)?;
+    writer.trim(key, start, stop)?;
+    Ok(Reply::Ok)
+}
+
+/// Pop from the first non-empty list among keys, or wait for an element to
+/// be pushed to one: `blpop key [key ...] timeout` or `brpop`. The timeout is
+/// in seconds, possibly fractional, and 0 waits for good. Replies with the key
+/// and the element, or nil once the timeout passes.
+fn blocking_pop(
+    args: &[Bytes],
+    end: End,
+    store: &Keyspace,
+    blocked: &WaitQueues,
+) -> Result<Outcome> {
+    let (timeout, keys) = args.split_last().ok_or(ServerError::InvalidArguments)?;
+    let timeout = std::str::from_utf8(timeout)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or(ServerError::InvalidTimeout)?;
+    let deadline = match timeout.is_zero() {
+        true => None,
+        false => Some(
+            Instant::now()
+                .checked_add(timeout)
+                .ok_or(ServerError::InvalidTimeout)?,
+        ),
+    };
+    let mut writer = store.write();
+    for key in keys {
+        check_kind(writer.get(key)?, Kind::List)?;
+        if let Some(value) = writer.pop(key, end)? {
+            return Ok(Outcome::Ready(popped(key.to_vec(), value)));
+        }
+    }
+    Ok(Outcome::Suspended(blocked.park(keys, end, deadline)))
+}
+
+/// The reply of a blocking pop that got an element.
+fn popped(key: Vec<u8>, value: Vec<u8>) -> Reply {
+    Reply::Array(vec![Reply::Bulk(key), Reply::Bulk(value)])
+}
+
+/// Parses the `[match pattern] [count n]` options of a scan.
+fn scan_options(options: &[Bytes]) -> Result<(Option<&[u8]>, usize)> {
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in options.chunks(2) {
+        match (option[0].to_ascii_lowercase().as_slice(), option.get(1)) {
+            (b"match", Some(value)) => pattern = Some(&value[..]),
+            (b"count", Some(value)) => {
+                count = parse_int(value)
+                    .filter(|&n| n > 0)
+                    .ok_or(ServerError::InvalidArguments)?
+            }
+            _ => return Err(ServerError::InvalidArguments),
+        }
+    }
+    Ok((pattern, count))
+}
+
+fn encode_cursor(key: &[u8]) -> String {
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The key a scan resumes at, `None` for the cursor `0` that starts one.
+fn decode_cursor(cursor: &[u8]) -> Result<Option<Vec<u8>>> {
+    if cursor == b"0" {
+        return Ok(None);
+    }
+    if !cursor.len().is_multiple_of(2) {
+        return Err(ServerError::InvalidArguments);
+    }
+    cursor
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect::<Option<_>>()
+        .map(Some)
+        .ok_or(ServerError::InvalidArguments)
+}
+
+/// Starts a write for a `denyoom` command, failing with `OutOfMemory` if
+/// memory use is over the limit and nothing can be evicted.
+fn write_with_room(store: &Keyspace) -> Result<Writer<'_>> {
+    store.write_with_room()?.ok_or(ServerError::OutOfMemory)
+}
+
+/// Fails with `WrongType` if key exists but does not hold `kind`.
+fn check_kind(entry: Option<Entry>, kind: Kind) -> Result<Option<Entry>> {
+    match entry {
+        Some(entry) if entry.kind != kind => Err(ServerError::WrongType),
+        entry => Ok(entry),
+    }
+}
+
+/// Parses a decimal argument.
+fn parse_int<T: std::str::FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// Describe the command table: `command` lists every command and
+/// `command info name...` the named ones, nil for an unknown name. Each entry
+/// is `[name, arity, [flag...], first-key, last-key, key-step]`, with arity and key
+/// positions encoded as Redis does.
+fn command(args: &[Bytes]) -> Result<Reply> {
+    let entries = match args.split_first() {
+        None => COMMANDS.iter().map(describe_command).collect(),
+        Some((sub, names)) if sub.eq_ignore_ascii_case(b"info") => names
+            .iter()
+            .map(|name| cmd_type::lookup(name).map_or(Reply::Nil, describe_command))
+            .collect(),
+        Some(_) => return Err(ServerError::InvalidArguments),
+    };
+    Ok(Reply::Array(entries))
+}
+
+fn describe_command(spec: &CommandSpec) -> Reply {
+    let mut flags = vec![if spec.write { "write" } else { "readonly" }];
+    if spec.denyoom {
+        flags.push("denyoom");
+    }
+    Reply::Array(vec![
+        Reply::bulk(spec.name),
+        Reply::Integer(spec.arity.to_redis()),
+        Reply::Array(flags.into_iter().map(Reply::bulk).collect()),
+        Reply::Integer(spec.first_key as i64),
+        Reply::Integer(spec.last_key as i64),
+        Reply::Integer(spec.key_step as i64),
+    ])
+}
+
+/// Report server statistics as `field:value` lines under `# Section` headers,
+/// as Redis does: `info [section]`. The only section is `writes`, which shows
+/// how group commit has merged writes; an unknown section is empty.
+fn info(args: &[Bytes], store: &Keyspace) -> Result<Reply> {
+    match args {
+        [] => {}
+        [section] if section.eq_ignore_ascii_case(b"writes") => {}
+        [_] => return Ok(Reply::bulk("")),
+        _ => return Err(ServerError::InvalidArguments),
+    }
+    let stats = store.db().write_stats();
+    // Bucket `i` counts groups of up to `2^i` batches; the last one has no
+    // upper bound.
+    let last = stats.histogram.len() - 1;
+    let histogram = stats
+        .histogram
+        .iter()
+        .enumerate()
+        .map(|(i, count)| match i {
+            i if i == last => format!(">{}={}", 1 << (i - 1), count),
+            i => format!("{}={}", 1 << i, count),
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let fields = [
+        ("groups", stats.groups.to_string()),
+        ("batches", stats.batches.to_string()),
+        ("largest_group", stats.largest_group.to_string()),
+        ("avg_group_size", format!("{:.2}", stats.average_group_size())),
+        ("group_histogram", histogram),
+    ];
+    let mut text = String::from("# Writes\r\n");
+    for (field, value) in fields {
+        text.push_str(&format!("{}:{}\r\n", field, value));
+    }
+    Ok(Reply::bulk(text))
+}
+
+/// Negotiate the protocol: `hello [protover]`. Replies with the server
+/// properties and the version the connection speaks from now on, which is
+/// `current` unless `protover` is given.
+fn hello(args: &[Bytes], current: Version) -> Result<(Version, Value)> {
+    let version = match args {
+        [] => None,
+        [protover] => Some(protover),
+        _ => return Err(ServerError::InvalidArguments),
+    };
+    let version = match version.map(|v| parse_int::<i64>(v)) {
+        None => current,
+        Some(Some(2)) => Version::Resp2,
+        Some(Some(3)) => Version::Resp3,
+        Some(Some(_)) => return Err(ServerError::NoProto),
+        Some(None) => return Err(ServerError::InvalidArguments),
+    };
+    let reply = Value::Map(vec![
+        (Value::bulk("server"), Value::bulk("wdis")),
+        (Value::bulk("version"), Value::bulk(env!("CARGO_PKG_VERSION"))),
+        (Value::bulk("proto"), Value::Integer(version.number())),
+        (Value::bulk("mode"), Value::bulk("standalone")),
+        (Value::bulk("role"), Value::bulk("master")),
+        (Value::bulk("modules"), Value::Array(Vec::new())),
+    ]);
+    Ok((version, reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<Bytes> {
+        args.iter().map(|arg| Bytes::from(arg.to_string())).collect()
+    }
+
+    fn open(name: &str) -> Arc<Keyspace> {
+        let dir = std::env::temp_dir().join(format!("wdis-main-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        Arc::new(Keyspace::open_with(&dir, Options::default()).unwrap())
+    }
+
+    /// Reopens the keyspace that `open(name)` created.
+    fn reopen(name: &str, options: Options) -> Arc<Keyspace> {
+        let dir = std::env::temp_dir().join(format!("wdis-main-{}-{}", name, std::process::id()));
+        Arc::new(Keyspace::open_with(&dir, options).unwrap())
+    }
+
+    /// Runs a request that does not block.
+    async fn run(store: &Arc<Keyspace>, cmd: &[&str]) -> Reply {
+        run_with(store, &Arc::default(), cmd).await
+    }
+
+    /// Runs a request that does not block, serving the clients in `blocked`.
+    async fn run_with(store: &Arc<Keyspace>, blocked: &Arc<WaitQueues>, cmd: &[&str]) -> Reply {
+        match dispatch(&args(cmd), store, blocked).await {
+            Outcome::Ready(reply) => reply,
+            Outcome::Suspended(_) => panic!("{:?} blocked", cmd),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_string_commands() {
+        let store = open("string_commands");
+        let not_an_integer = ServerError::NotAnInteger.reply();
+        assert_eq!(run(&store, &["set", "k", "v"]).await, Reply::Ok);
+        assert_eq!(run(&store, &["get", "k"]).await, Reply::bulk("v"));
+        assert_eq!(run(&store, &["incr", "k"]).await, not_an_integer);
+        assert_eq!(run(&store, &["decr", "k"]).await, not_an_integer);
+        assert_eq!(run(&store, &["get", "k"]).await, Reply::bulk("v"));
+
+        assert_eq!(run(&store, &["incr", "n"]).await, Reply::Integer(1));
+        assert_eq!(run(&store, &["decr", "n"]).await, Reply::Integer(0));
+        // Overflow fails and leaves the value as it was.
+        let max = i64::MAX.to_string();
+        run(&store, &["set", "n", &max]).await;
+        assert_eq!(run(&store, &["incr", "n"]).await, not_an_integer);
+        assert_eq!(run(&store, &["get", "n"]).await, Reply::bulk(max));
+        let min = i64::MIN.to_string();
+        run(&store, &["set", "n", &min]).await;
+        assert_eq!(run(&store, &["decr", "n"]).await, not_an_integer);
+        assert_eq!(run(&store, &["get", "n"]).await, Reply::bulk(min));
+
+        assert_eq!(
+            run(&store, &["mget", "k", "missing", "k"]).await,
+            Reply::Array(vec![Reply::bulk("v"), Reply::Nil, Reply::bulk("v")])
+        );
+        assert_eq!(
+            run(&store, &["mget", "missing"]).await,
+            Reply::Array(vec![Reply::Nil])
+        );
+
+        assert_eq!(run(&store, &["setnx", "s", "a"]).await, Reply::Integer(1));
+        assert_eq!(run(&store, &["setnx", "s", "b"]).await, Reply::Integer(0));
+        assert_eq!(run(&store, &["get", "s"]).await, Reply::bulk("a"));
+        assert_eq!(run(&store, &["del", "s"]).await, Reply::Integer(1));
+        assert_eq!(run(&store, &["del", "s"]).await, Reply::Integer(0));
+        assert_eq!(run(&store, &["get", "s"]).await, Reply::Nil);
+        run(&store, &["set", "s", "a"]).await;
+        assert_eq!(
+            run(&store, &["del", "s", "k", "missing", "s"]).await,
+            Reply::Integer(2)
+        );
+        assert_eq!(run(&store, &["get", "k"]).await, Reply::Nil);
+
+        assert_eq!(run(&store, &["set", "k", "v", "ex", "x"]).await, not_an_integer);
+        assert_eq!(run(&store, &["set", "k", "v", "px", "1.5"]).await, not_an_integer);
+    }
+
+    #[tokio::test]
+    async fn test_store_off_runtime() {
+        let store = open("store_off_runtime");
+        // A request stuck on the write lock leaves the runtime, which has a
+        // single thread here, free to run other tasks.
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let holder = std::thread::spawn({
+            let store = store.clone();
+            move || {
+                let _writer = store.write();
+                locked_tx.send(()).unwrap();
+                let _ = release_rx.recv();
+            }
+        });
+        locked_rx.recv().unwrap();
+        let request = tokio::spawn({
+            let store = store.clone();
+            async move { run(&store, &["set", "k", "v"]).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!request.is_finished());
+        release_tx.send(()).unwrap();
+        assert_eq!(request.await.unwrap(), Reply::Ok);
+        holder.join().unwrap();
+        assert_eq!(run(&store, &["get", "k"]).await, Reply::bulk("v"));
+    }
+
+    #[tokio::test]
+    async fn test_out_of_memory() {
+        let store = open("out_of_memory");
+        run(&store, &["set", "k", "v"]).await;
+        run(&store, &["hset", "h", "f", "v"]).await;
+        run(&store, &["rpush", "l", "a", "b"]).await;
+        drop(store);
+        let options = Options {
+            maxmemory: 1,
+            eviction_policy: EvictionPolicy::NoEviction,
+            ..Options::default()
+        };
+        let store = reopen("out_of_memory", options);
+
+        let refused: &[&[&str]] = &[
+            &["set", "k", "w"],
+            &["incr", "n"],
+            &["decr", "n"],
+            &["setnx", "n", "1"],
+            &["hset", "h", "f", "w"],
+            &["hincrby", "h", "n", "1"],
+            &["lpush", "l", "c"],
+            &["rpush", "l", "c"],
+        ];
+        let mut names: Vec<&str> = refused.iter().map(|cmd| cmd[0]).collect();
+        names.dedup();
+        let denyoom: Vec<&str> = COMMANDS
+            .iter()
+            .filter(|spec| spec.denyoom)
+            .map(|spec| spec.name)
+            .collect();
+        assert_eq!(names, denyoom);
+        for cmd in refused {
+            assert_eq!(run(&store, cmd).await, ServerError::OutOfMemory.reply());
+        }
+        assert_eq!(run(&store, &["get", "k"]).await, Reply::bulk("v"));
+        assert_eq!(run(&store, &["get", "n"]).await, Reply::Nil);
+        assert_eq!(run(&store, &["hget", "h", "f"]).await, Reply::bulk("v"));
+        assert_eq!(run(&store, &["llen", "l"]).await, Reply::Integer(2));
+
+        // Commands that can only shrink the keyspace still run.
+        assert_eq!(run(&store, &["lpop", "l"]).await, Reply::bulk("a"));
+        assert_eq!(run(&store, &["hdel", "h", "f"]).await, Reply::Integer(1));
+        assert_eq!(run(&store, &["del", "k"]).await, Reply::Integer(1));
+        assert_eq!(
+            run(&store, &["set", "k", "w"]).await,
+            ServerError::OutOfMemory.reply()
+        );
+        assert_eq!(run(&store, &["del", "l"]).await, Reply::Integer(1));
+        // The memtables count too, so with every key gone the limit is still
+        // reached until they are flushed.
+        assert_eq!(
+            run(&store, &["set", "k", "w"]).await,
+            ServerError::OutOfMemory.reply()
+        );
+    }
+
+    /// Runs a scan, returning the next cursor and the keys.
+    async fn scan_page(store: &Arc<Keyspace>, cmd: &[&str]) -> (String, Vec<Vec<u8>>) {
+        let reply = run(store, cmd).await;
+        let Reply::Array(parts) = reply else {
+            panic!("{:?} replied {:?}", cmd, reply);
+        };
+        let [Reply::Bulk(cursor), Reply::Array(keys)] = &parts[..] else {
+            panic!("{:?} replied {:?}", cmd, parts);
+        };
+        let keys = keys
+            .iter()
+            .map(|key| match key {
+                Reply::Bulk(key) => key.clone(),
+                other => panic!("{:?} in keys", other),
+            })
+            .collect();
+        (String::from_utf8(cursor.clone()).unwrap(), keys)
+    }
+
+    #[tokio::test]
+    async fn test_scan() {
+        let store = open("scan");
+        for key in ["a", "b", "c", "d", "e"] {
+            run(&store, &["set", key, "v"]).await;
+        }
+        let (cursor, keys) = scan_page(&store, &["scan", "0", "count", "2"]).await;
+        assert_eq!(keys, [b"a", b"b"]);
+        assert_eq!(cursor, encode_cursor(b"c"));
+
+        // The scan resumes after the keys it returned, whatever changed.
+        run(&store, &["del", "c"]).await;
+        run(&store, &["set", "bb", "v"]).await;
+        run(&store, &["set", "cc", "v"]).await;
+        let (cursor, keys) = scan_page(&store, &["scan", &cursor, "count", "2"]).await;
+        assert_eq!(keys, [&b"cc"[..], b"d"]);
+        let (cursor, keys) = scan_page(&store, &["scan", &cursor, "count", "10"]).await;
+        assert_eq!(keys, [b"e"]);
+        assert_eq!(cursor, "0");
+
+        // Options go in either order; COUNT counts examined keys, matching or not.
+        let page = scan_page(&store, &["scan", "0", "match", "?", "count", "3"]).await;
+        assert_eq!(page.1, [&b"a"[..], b"b"]);
+        assert_eq!(
+            scan_page(&store, &["scan", "0", "COUNT", "3", "MATCH", "?"]).await,
+            page
+        );
+
+        let invalid = ServerError::InvalidArguments.reply();
+        let requests: &[&[&str]] = &[
+            &["scan", "zz"],
+            &["scan", "abc"],
+            &["scan", "1"],
+            &["scan", "0", "count", "0"],
+            &["scan", "0", "count", "-1"],
+            &["scan", "0", "count"],
+            &["scan", "0", "match"],
+            &["scan", "0", "limit", "1"],
+        ];
+        for request in requests {
+            assert_eq!(run(&store, request).await, invalid, "{:?}", request);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_arity() {
+        let store = open("arity");
+        let invalid = ServerError::InvalidArguments.reply();
+        let requests: &[&[&str]] = &[
+            &["get"],
+            &["get", "k", "x"],
+            &["set", "k"],
+            &["del"],
+            &["incr"],
+            &["incr", "k", "x"],
+            &["decr"],
+            &["mget"],
+            &["setnx", "k"],
+            &["setnx", "k", "v", "x"],
+        ];
+        for request in requests {
+            assert_eq!(run(&store, request).await, invalid, "{:?}", request);
+        }
+        assert_eq!(run(&store, &["get", "k"]).await, Reply::Nil);
+        assert_eq!(
+            run(&store, &["nosuchcommand"]).await,
+            ServerError::InvalidCommand.reply()
+        );
+    }
+
+    #[test]
+    fn test_hello() {
+        let proto = |value: &Value| match value {
+            Value::Map(pairs) => pairs
+                .iter()
+                .find(|(key, _)| *key == Value::bulk("proto"))
+                .map(|(_, proto)| proto.clone()),
+            _ => None,
+        };
+        let (version, reply) = hello(&args(&["3"]), Version::Resp2).unwrap();
+        assert_eq!(version, Version::Resp3);
+        assert_eq!(proto(&reply), Some(Value::Integer(3)));
+        // Without a version, the connection keeps the one it speaks.
+        let (version, reply) = hello(&[], Version::Resp3).unwrap();
+        assert_eq!(version, Version::Resp3);
+        assert_eq!(proto(&reply), Some(Value::Integer(3)));
+        assert_eq!(hello(&[], Version::Resp2).unwrap().0, Version::Resp2);
+        assert!(matches!(
+            hello(&args(&["4"]), Version::Resp2),
+            Err(ServerError::NoProto)
+        ));
+        assert!(matches!(
+            hello(&args(&["x"]), Version::Resp2),
+            Err(ServerError::InvalidArguments)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_info() {
+        let store = open("info");
+        run(&store, &["set", "a", "1"]).await;
+        run(&store, &["set", "b", "2"]).await;
+        let text = match run(&store, &["info", "WRITES"]).await {
+            Reply::Bulk(text) => String::from_utf8(text).unwrap(),
+            reply => panic!("info replied {:?}", reply),
+        };
+        let lines: Vec<&str> = text.split("\r\n").collect();
+        assert_eq!(lines[0], "# Writes");
+        assert!(lines.contains(&"groups:2"));
+        assert!(lines.contains(&"largest_group:1"));
+        let histogram = lines.iter().find(|line| line.starts_with("group_histogram:"));
+        assert!(histogram.unwrap().starts_with("group_histogram:1=2,2=0,4=0,"));
+        assert!(histogram.unwrap().ends_with(",1024=0,>1024=0"));
+        assert_eq!(run(&store, &["info", "memory"]).await, Reply::bulk(""));
+
+        let config = |flags: &[&str]| Config::from_args(flags.iter().map(|f| f.to_string()));
+        let config_ok = config(&["--max-write-group-size", "8"]).unwrap();
+        assert_eq!(config_ok.max_write_group_size, 8);
+        assert!(config(&["--max-write-group-size", "0"]).is_err());
+        assert!(config(&["--max-write-group-size", "x"]).is_err());
+    }
+
+    /// Runs a blocking pop that finds nothing to pop.
+    async fn park(store: &Arc<Keyspace>, blocked: &Arc<WaitQueues>, cmd: &[&str]) -> Suspended {
+        match dispatch(&args(cmd), store, blocked).await {
+            Outcome::Suspended(suspended) => suspended,
+            Outcome::Ready(reply) => panic!("{:?} replied {:?}", cmd, reply),
+        }
+    }
+
+    /// The server end of a connection, along with the client end.
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        (listener.accept().await.unwrap().0, client)
+    }
+
+    fn lrange(store: &Keyspace, key: &[u8]) -> Vec<Vec<u8>> {
+        store
+            .list_range(ReadOptions::default(), key, 0, -1)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pop_order() {
+        let store = open("blocking_pop_order");
+        let blocked = Arc::new(WaitQueues::default());
+        let (stream, _client) = connection().await;
+        let first = park(&store, &blocked, &["blpop", "l", "0"]).await;
+        let second = park(&store, &blocked, &["blpop", "other", "l", "0"]).await;
+
+        // The client that came first is served first, and the other one
+        // stays parked on both its keys.
+        assert_eq!(
+            run_with(&store, &blocked, &["rpush", "l", "a"]).await,
+            Reply::Integer(1)
+        );
+        assert_eq!(
+            wait(first, &stream, &store, &blocked).await,
+            Some(popped(b"l".to_vec(), b"a".to_vec()))
+        );
+        assert_eq!(blocked.state.lock().unwrap().waiters.len(), 1);
+        assert_eq!(blocked.state.lock().unwrap().queues.len(), 2);
+
+        assert_eq!(
+            run_with(&store, &blocked, &["rpush", "l", "b", "c"]).await,
+            Reply::Integer(2)
+        );
+        assert_eq!(
+            wait(second, &stream, &store, &blocked).await,
+            Some(popped(b"l".to_vec(), b"b".to_vec()))
+        );
+        assert!(blocked.state.lock().unwrap().waiters.is_empty());
+        assert!(blocked.state.lock().unwrap().queues.is_empty());
+        assert_eq!(lrange(&store, b"l"), vec![b"c".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pop_timeout() {
+        let store = open("blocking_pop_timeout");
+        let blocked = Arc::new(WaitQueues::default());
+        let (stream, _client) = connection().await;
+        let suspended = park(&store, &blocked, &["brpop", "l", "m", "0.05"]).await;
+        assert_eq!(blocked.state.lock().unwrap().queues.len(), 2);
+        assert_eq!(
+            wait(suspended, &stream, &store, &blocked).await,
+            Some(Reply::Nil)
+        );
+        // The client is gone from every queue, so a later push stays put.
+        assert!(blocked.state.lock().unwrap().waiters.is_empty());
+        assert!(blocked.state.lock().unwrap().queues.is_empty());
+        assert_eq!(
+            run_with(&store, &blocked, &["rpush", "l", "a"]).await,
+            Reply::Integer(1)
+        );
+        assert_eq!(lrange(&store, b"l"), vec![b"a".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pop_disconnect() {
+        let store = open("blocking_pop_disconnect");
+        let blocked = Arc::new(WaitQueues::default());
+
+        // A client that is served, then found to have disconnected, gives its
+        // element back to the head of the list.
+        let gone = park(&store, &blocked, &["blpop", "l", "0"]).await;
+        run_with(&store, &blocked, &["rpush", "l", "a", "b"]).await;
+        assert_eq!(lrange(&store, b"l"), vec![b"b".to_vec()]);
+        abandon(gone, &store, &blocked);
+        assert_eq!(lrange(&store, b"l"), vec![b"a".to_vec(), b"b".to_vec()]);
+        run_with(&store, &blocked, &["del", "l"]).await;
+
+        // The element goes to the next client waiting for it instead.
+        let gone = park(&store, &blocked, &["blpop", "l", "0"]).await;
+        let next = park(&store, &blocked, &["blpop", "l", "0"]).await;
+        run_with(&store, &blocked, &["rpush", "l", "a"]).await;
+        abandon(gone, &store, &blocked);
+        let (stream, _client) = connection().await;
+        assert_eq!(
+            wait(next, &stream, &store, &blocked).await,
+            Some(popped(b"l".to_vec(), b"a".to_vec()))
+        );
+        assert_eq!(lrange(&store, b"l"), Vec::<Vec<u8>>::new());
+
+        // A client that disconnects before it is served is just unparked.
+        let (stream, client) = connection().await;
+        let suspended = park(&store, &blocked, &["blpop", "l", "0"]).await;
+        drop(client);
+        assert_eq!(wait(suspended, &stream, &store, &blocked).await, None);
+        assert!(blocked.state.lock().unwrap().waiters.is_empty());
+        run_with(&store, &blocked, &["rpush", "l", "a"]).await;
+        assert_eq!(lrange(&store, b"l"), vec![b"a".to_vec()]);
     }
 }
@@ -1,3 +1,4 @@
+#[allow(non_camel_case_types)]
 pub struct buf {
     pub size: usize,
     pub data: Box<[u8]>,
@@ -7,7 +8,7 @@ pub struct buf {
 impl buf {
     pub fn new(size: usize) -> buf {
         buf {
-            size: size,
+            size,
             data: vec![0; size].into_boxed_slice(),
         }
     }
@@ -1,9 +1,210 @@
-enum cmd{
-            Get,
-            Set,
-            Del,
-            Incr,
-            Decr,
-            Mget,
-            Setnx   
-}
\ No newline at end of file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cmd {
+    Get,
+    Set,
+    Del,
+    Incr,
+    Decr,
+    Mget,
+    Setnx,
+    Scan,
+    Expire,
+    Pexpire,
+    Ttl,
+    Pttl,
+    Persist,
+    Command,
+    Hello,
+    Info,
+    Hset,
+    Hget,
+    Hdel,
+    Hgetall,
+    Hincrby,
+    Hscan,
+    Lpush,
+    Rpush,
+    Lpop,
+    Rpop,
+    Lrange,
+    Llen,
+    Ltrim,
+    Blpop,
+    Brpop,
+}
+
+/// How many arguments a command takes, counting the command name itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arity {
+    Exact(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, argc: usize) -> bool {
+        match *self {
+            Arity::Exact(n) => argc == n,
+            Arity::AtLeast(n) => argc >= n,
+        }
+    }
+
+    /// The Redis encoding: `n` for exactly `n` arguments, `-n` for at least `n`.
+    pub fn to_redis(&self) -> i64 {
+        match *self {
+            Arity::Exact(n) => n as i64,
+            Arity::AtLeast(n) => -(n as i64),
+        }
+    }
+}
+
+/// One entry of the command table shared by the server and the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub cmd: Cmd,
+    pub arity: Arity,
+    /// Whether the command may modify the keyspace.
+    pub write: bool,
+    /// Whether the command is refused while memory use is over the limit and
+    /// nothing can be evicted. Writes that can only shrink the keyspace are
+    /// still allowed, so clients can free memory themselves.
+    pub denyoom: bool,
+    /// Position of the first key argument, or 0 if the command takes no keys.
+    pub first_key: usize,
+    /// Position of the last key argument. Negative positions count from the
+    /// end, so -1 is the last argument.
+    pub last_key: isize,
+    /// Distance between consecutive key arguments.
+    pub key_step: usize,
+}
+
+impl CommandSpec {
+    const fn new(name: &'static str, cmd: Cmd, arity: Arity, write: bool) -> Self {
+        CommandSpec {
+            name,
+            cmd,
+            arity,
+            write,
+            denyoom: write,
+            first_key: 1,
+            last_key: 1,
+            key_step: 1,
+        }
+    }
+
+    const fn keys(self, first_key: usize, last_key: isize, key_step: usize) -> Self {
+        CommandSpec {
+            first_key,
+            last_key,
+            key_step,
+            ..self
+        }
+    }
+
+    const fn allow_oom(self) -> Self {
+        CommandSpec {
+            denyoom: false,
+            ..self
+        }
+    }
+
+    /// Positions of the key arguments in a request of `argc` arguments,
+    /// counting the command name as position 0.
+    pub fn key_positions(&self, argc: usize) -> impl Iterator<Item = usize> {
+        let last = match self.last_key {
+            n if n < 0 => argc.checked_sub(n.unsigned_abs()),
+            n => Some(n as usize),
+        };
+        let range = match (self.first_key, last) {
+            (0, _) | (_, None) => 0..0,
+            (first, Some(last)) => first..(last + 1).min(argc),
+        };
+        range.step_by(self.key_step.max(1))
+    }
+}
+
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec::new("get", Cmd::Get, Arity::Exact(2), false),
+    CommandSpec::new("set", Cmd::Set, Arity::AtLeast(3), true),
+    CommandSpec::new("del", Cmd::Del, Arity::AtLeast(2), true)
+        .keys(1, -1, 1)
+        .allow_oom(),
+    CommandSpec::new("incr", Cmd::Incr, Arity::Exact(2), true),
+    CommandSpec::new("decr", Cmd::Decr, Arity::Exact(2), true),
+    CommandSpec::new("mget", Cmd::Mget, Arity::AtLeast(2), false).keys(1, -1, 1),
+    CommandSpec::new("setnx", Cmd::Setnx, Arity::Exact(3), true),
+    CommandSpec::new("scan", Cmd::Scan, Arity::AtLeast(2), false).keys(0, 0, 0),
+    CommandSpec::new("expire", Cmd::Expire, Arity::Exact(3), true).allow_oom(),
+    CommandSpec::new("pexpire", Cmd::Pexpire, Arity::Exact(3), true).allow_oom(),
+    CommandSpec::new("ttl", Cmd::Ttl, Arity::Exact(2), false),
+    CommandSpec::new("pttl", Cmd::Pttl, Arity::Exact(2), false),
+    CommandSpec::new("persist", Cmd::Persist, Arity::Exact(2), true).allow_oom(),
+    CommandSpec::new("command", Cmd::Command, Arity::AtLeast(1), false).keys(0, 0, 0),
+    CommandSpec::new("hello", Cmd::Hello, Arity::AtLeast(1), false).keys(0, 0, 0),
+    CommandSpec::new("info", Cmd::Info, Arity::AtLeast(1), false).keys(0, 0, 0),
+    CommandSpec::new("hset", Cmd::Hset, Arity::AtLeast(4), true),
+    CommandSpec::new("hget", Cmd::Hget, Arity::Exact(3), false),
+    CommandSpec::new("hdel", Cmd::Hdel, Arity::AtLeast(3), true).allow_oom(),
+    CommandSpec::new("hgetall", Cmd::Hgetall, Arity::Exact(2), false),
+    CommandSpec::new("hincrby", Cmd::Hincrby, Arity::Exact(4), true),
+    CommandSpec::new("hscan", Cmd::Hscan, Arity::AtLeast(3), false),
+    CommandSpec::new("lpush", Cmd::Lpush, Arity::AtLeast(3), true),
+    CommandSpec::new("rpush", Cmd::Rpush, Arity::AtLeast(3), true),
+    CommandSpec::new("lpop", Cmd::Lpop, Arity::Exact(2), true).allow_oom(),
+    CommandSpec::new("rpop", Cmd::Rpop, Arity::Exact(2), true).allow_oom(),
+    CommandSpec::new("lrange", Cmd::Lrange, Arity::Exact(4), false),
+    CommandSpec::new("llen", Cmd::Llen, Arity::Exact(2), false),
+    CommandSpec::new("ltrim", Cmd::Ltrim, Arity::Exact(4), true).allow_oom(),
+    CommandSpec::new("blpop", Cmd::Blpop, Arity::AtLeast(3), true)
+        .keys(1, -2, 1)
+        .allow_oom(),
+    CommandSpec::new("brpop", Cmd::Brpop, Arity::AtLeast(3), true)
+        .keys(1, -2, 1)
+        .allow_oom(),
+];
+
+/// Finds a command by name, ignoring ASCII case.
+pub fn lookup(name: impl AsRef<[u8]>) -> Option<&'static CommandSpec> {
+    let name = name.as_ref();
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name.as_bytes().eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let set = lookup("SET").unwrap();
+        assert_eq!(set.cmd, Cmd::Set);
+        assert!(set.write);
+        assert!(set.denyoom);
+        assert!(!lookup("del").unwrap().denyoom);
+        assert!(set.arity.accepts(3));
+        assert!(!set.arity.accepts(2));
+        assert_eq!(set.arity.to_redis(), -3);
+        assert_eq!(lookup("mget").unwrap().arity.to_redis(), -2);
+        assert!(lookup("flushall").is_none());
+    }
+
+    #[test]
+    fn test_key_positions() {
+        let keys = |name, argc| {
+            lookup(name)
+                .unwrap()
+                .key_positions(argc)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys("get", 2), [1]);
+        assert_eq!(keys("set", 3), [1]);
+        assert_eq!(keys("mget", 4), [1, 2, 3]);
+        assert_eq!(keys("del", 3), [1, 2]);
+        assert!(keys("scan", 6).is_empty());
+        assert!(keys("command", 1).is_empty());
+        assert!(keys("info", 2).is_empty());
+        assert_eq!(keys("hset", 6), [1]);
+        assert_eq!(keys("blpop", 4), [1, 2]);
+    }
+}
@@ -0,0 +1,59 @@
+use std::cmp::Ordering;
+
+/// A total order over user keys.
+///
+/// The memtable and table files order entries by the comparator the database
+/// was opened with, so the same comparator (identified by `name`) must be used
+/// every time a database is reopened.
+pub trait Comparator: Send + Sync {
+    /// Identifies the ordering. Changing how keys compare requires a new name.
+    fn name(&self) -> &'static str;
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Orders keys lexicographically by their bytes. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &'static str {
+        "wdis.BytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Orders keys in the reverse of `BytewiseComparator`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReverseBytewiseComparator;
+
+impl Comparator for ReverseBytewiseComparator {
+    fn name(&self) -> &'static str {
+        "wdis.ReverseBytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytewise() {
+        let c = BytewiseComparator;
+        assert_eq!(c.compare(b"a", b"b"), Ordering::Less);
+        assert_eq!(c.compare(b"ab", b"a"), Ordering::Greater);
+        assert_eq!(c.compare(b"", b""), Ordering::Equal);
+
+        let r = ReverseBytewiseComparator;
+        assert_eq!(r.compare(b"a", b"b"), Ordering::Greater);
+        assert_eq!(r.compare(b"ab", b"a"), Ordering::Less);
+        assert_ne!(c.name(), r.name());
+    }
+}
@@ -0,0 +1,1942 @@
+use std::cmp::Ordering as KeyOrdering;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+use crate::filename::{self, FileType};
+use crate::filter::FilterStats;
+use crate::iterator::{InternalIterator, MergingIterator};
+use crate::key::{InternalKeyComparator, ParsedInternalKey, ValueType};
+use crate::log::{err, LogReader, LogWriter, StatusCode};
+use crate::memtable::{IterMode, MemTableIterator};
+use crate::memtable::{LookupResult, MemTable, ENTRY_OVERHEAD};
+use crate::options::{Durability, Options, ReadOptions};
+use crate::table_cache::{TableCache, TableWriter};
+use crate::version::{Compaction, FileMetaData, Version, VersionEdit, VersionSet};
+use crate::write_batch::WriteBatch;
+use crate::write_queue::WriteQueue;
+
+pub use crate::db_iter::DbIterator;
+pub use crate::version::CompactionStats;
+pub use crate::write_queue::WriteStats;
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// Writes stall while this many full memtables are waiting to be flushed.
+const MAX_IMMUTABLE_MEMTABLES: usize = 2;
+
+/// A key-value store made of a write-ahead log, in-memory `MemTable`s and
+/// sorted table files arranged in levels.
+///
+/// Every write is a `WriteBatch` that is assigned the next run of sequence
+/// numbers, appended to the log as one record and then inserted into the
+/// memtable, so the log always holds at least what a reader can observe.
+///
+/// Concurrent writers go through a group commit queue, so many small batches
+/// share one log record and one flush. When the log is fsynced is set by
+/// `Options::durability`.
+///
+/// Once the memtable holds about `Options::write_buffer_size` bytes it becomes
+/// immutable and writes move on to a fresh memtable and log. A background
+/// thread writes immutable memtables out as level 0 table files and merges
+/// tables as set by `Options::compaction_strategy`. Which files make up the
+/// database is recorded in the MANIFEST; a log is deleted once the manifest
+/// says its writes are in a table.
+pub struct Db {
+    inner: Arc<DbInner>,
+    syncer: Option<Syncer>,
+    background: Option<JoinHandle<()>>,
+}
+
+/// State shared with the background threads.
+struct DbInner {
+    dir: PathBuf,
+    options: Options,
+    icmp: InternalKeyComparator,
+    tables: Arc<TableCache>,
+    last_sequence: AtomicU64,
+    log: Arc<Mutex<Wal>>,
+    writers: WriteQueue,
+    state: Mutex<State>,
+    /// Signalled when a memtable is queued for flushing, when background work
+    /// finishes and on shutdown.
+    state_cv: Condvar,
+    /// Whether `State::imm` is non-empty, checked by long compactions without
+    /// taking the lock.
+    has_imm: AtomicBool,
+}
+
+/// The memtables and table files holding the database contents.
+struct State {
+    mem: Arc<MemTable>,
+    /// Logs holding the writes in `mem`, oldest first. The last one is the log
+    /// being written.
+    mem_logs: Vec<u64>,
+    /// Full memtables waiting to be flushed, oldest first.
+    imm: VecDeque<Immutable>,
+    versions: VersionSet,
+    shutting_down: bool,
+    /// Set when background work, a log write or a memtable add fails. Writes
+    /// fail from then on, since the memtables can no longer be drained or the
+    /// log or the memtable may hold part of a batch.
+    bg_error: Option<String>,
+    compaction_stats: CompactionStats,
+    /// Tables compacted away but still read by an open iterator.
+    obsolete_tables: Vec<u64>,
+    /// Sequence numbers pinned by live snapshots, with how many pin each.
+    snapshots: BTreeMap<u64, usize>,
+}
+
+struct Immutable {
+    mem: Arc<MemTable>,
+    logs: Vec<u64>,
+}
+
+/// The log file being written and how much of it is known to be on disk.
+struct Wal {
+    number: u64,
+    writer: LogWriter<BufWriter<File>>,
+    /// Records were written since the last sync.
+    dirty: bool,
+    /// Length of the file at the last sync.
+    synced_len: u64,
+}
+
+impl Wal {
+    fn create(dir: &Path, number: u64) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(filename::log_file_name(dir, number))?;
+        Ok(Wal {
+            number,
+            writer: LogWriter::new(BufWriter::new(file)),
+            dirty: false,
+            synced_len: 0,
+        })
+    }
+
+    /// Adds a record, syncing it if `durability` asks for it.
+    fn append(&mut self, record: &[u8], durability: Durability) -> Result<()> {
+        self.writer.add_record(record)?;
+        self.dirty = true;
+        match durability {
+            Durability::Always => self.sync(),
+            Durability::EverySec | Durability::No => self.writer.flush(),
+        }
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        let file = self.writer.get_ref().get_ref();
+        file.sync_data()?;
+        self.synced_len = file.metadata()?.len();
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// A consistent view of the database as of one sequence number, taken with
+/// `Db::snapshot` and passed to reads through `ReadOptions::snapshot`.
+///
+/// Compaction keeps every version a snapshot can see until the last clone of
+/// the handle is dropped.
+#[derive(Clone)]
+pub struct Snapshot {
+    pinned: Arc<PinnedSequence>,
+}
+
+struct PinnedSequence {
+    sequence: u64,
+    db: Arc<DbInner>,
+}
+
+impl Snapshot {
+    /// Sequence number of the last write the snapshot sees.
+    pub fn sequence(&self) -> u64 {
+        self.pinned.sequence
+    }
+}
+
+impl std::fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("sequence", &self.pinned.sequence)
+            .finish()
+    }
+}
+
+impl Drop for PinnedSequence {
+    /// Unpins the sequence number, so the next compaction may drop what only
+    /// this snapshot could see.
+    fn drop(&mut self) {
+        let mut state = self.db.state.lock().unwrap();
+        if let Some(count) = state.snapshots.get_mut(&self.sequence) {
+            *count -= 1;
+            if *count == 0 {
+                state.snapshots.remove(&self.sequence);
+            }
+        }
+    }
+}
+
+/// Background thread that syncs the log every `Options::sync_interval`.
+struct Syncer {
+    stop: mpsc::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Syncer {
+    fn start(log: Arc<Mutex<Wal>>, interval: std::time::Duration) -> Syncer {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let mut wal = log.lock().unwrap();
+                if wal.dirty {
+                    if let Err(e) = wal.sync() {
+                        eprintln!("Failed to sync log: {}", e);
+                    }
+                }
+            }
+        });
+        Syncer { stop, handle }
+    }
+
+    fn stop(self) {
+        drop(self.stop);
+        let _ = self.handle.join();
+    }
+}
+
+impl Db {
+    /// Opens the database stored in `dir` with default options.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Db> {
+        Self::open_with(dir, Options::default())
+    }
+
+    /// Opens the database stored in `dir`, creating the directory if needed.
+    ///
+    /// The table files are loaded from the manifest, then every log that is
+    /// not yet in a table is replayed, oldest first, into the memtable, and
+    /// the sequence counter resumes after the highest sequence found. A fresh
+    /// log file and manifest are then started for this session.
+    pub fn open_with(dir: impl AsRef<Path>, options: Options) -> Result<Db> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let icmp = InternalKeyComparator::new(options.comparator.clone());
+        let tables = Arc::new(TableCache::new(
+            &dir,
+            icmp.clone(),
+            options.filter_policy.clone(),
+        ));
+
+        let mut logs = Vec::new();
+        let mut has_tables = false;
+        let mut numbers_in_use = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            match name.to_str().and_then(filename::parse_file_name) {
+                Some((number, FileType::Log)) => logs.push(number),
+                Some((_, FileType::Table)) => has_tables = true,
+                _ => {}
+            }
+            if let Some((number, _)) = name.to_str().and_then(filename::parse_file_name) {
+                numbers_in_use.push(number);
+            }
+        }
+        if has_tables && !filename::current_file_name(&dir).exists() {
+            return err(
+                StatusCode::Corruption,
+                "table files found without a manifest",
+            );
+        }
+
+        let mut versions = VersionSet::recover(&dir, icmp.clone(), tables.clone(), &options)?;
+        for number in numbers_in_use {
+            versions.mark_file_number_used(number);
+        }
+        logs.retain(|&number| number >= versions.log_number());
+        logs.sort_unstable();
+
+        let mut last_sequence = versions.last_sequence();
+        let new_memtable =
+            |need| MemTable::with_capacity(icmp.clone(), memtable_capacity(&options, need));
+        let mut edit = VersionEdit::default();
+        let mut compaction_stats = CompactionStats::new(options.compaction_strategy);
+        let mut write_level0 = |versions: &mut VersionSet, mem: &MemTable| -> Result<()> {
+            let meta = write_memtable(&tables, versions.new_file_number(), mem)?;
+            compaction_stats.bytes_flushed += meta.file_size;
+            edit.new_files.push((0, meta));
+            Ok(())
+        };
+
+        // Replay the logs. Should they hold more than fits in one memtable, the
+        // full ones are flushed as they go, and everything replayed ends up in
+        // tables so the old logs can be dropped.
+        let mut mem = new_memtable(0)?;
+        let mut flushed = false;
+        for &number in &logs {
+            let seq = recover_log(&filename::log_file_name(&dir, number), |batch| {
+                let need = memtable_need(&batch);
+                if !has_room(&mem, need, &options) {
+                    if !mem.is_empty() {
+                        write_level0(&mut versions, &mem)?;
+                        flushed = true;
+                    }
+                    mem = new_memtable(need)?;
+                }
+                batch.insert_into(&mem)
+            })?;
+            last_sequence = last_sequence.max(seq);
+        }
+        let mut mem_logs = logs;
+        if flushed && !mem.is_empty() {
+            write_level0(&mut versions, &mem)?;
+            mem = new_memtable(0)?;
+        }
+        if flushed {
+            mem_logs.clear();
+        }
+
+        let log_number = versions.new_file_number();
+        let log = Arc::new(Mutex::new(Wal::create(&dir, log_number)?));
+        mem_logs.push(log_number);
+        edit.log_number = Some(mem_logs[0]);
+        versions.log_and_apply(&mut edit, last_sequence)?;
+        delete_obsolete_files(&dir, &mut versions)?;
+
+        let syncer = match options.durability {
+            Durability::EverySec => Some(Syncer::start(log.clone(), options.sync_interval)),
+            Durability::Always | Durability::No => None,
+        };
+
+        let inner = Arc::new(DbInner {
+            dir,
+            icmp,
+            tables,
+            last_sequence: AtomicU64::new(last_sequence),
+            log,
+            writers: WriteQueue::new(options.max_write_group_size),
+            state: Mutex::new(State {
+                mem: Arc::new(mem),
+                mem_logs,
+                imm: VecDeque::new(),
+                versions,
+                shutting_down: false,
+                bg_error: None,
+                compaction_stats,
+                obsolete_tables: Vec::new(),
+                snapshots: BTreeMap::new(),
+            }),
+            state_cv: Condvar::new(),
+            has_imm: AtomicBool::new(false),
+            options,
+        });
+        let background = {
+            let inner = inner.clone();
+            thread::spawn(move || inner.run_background())
+        };
+
+        Ok(Db {
+            inner,
+            syncer,
+            background: Some(background),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.inner.dir
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.inner.options
+    }
+
+    /// Number of the log file currently being written.
+    pub fn log_number(&self) -> u64 {
+        self.inner.log.lock().unwrap().number
+    }
+
+    /// Sequence number of the most recent write.
+    pub fn last_sequence(&self) -> u64 {
+        self.inner.last_sequence.load(Ordering::Acquire)
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write(batch)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(batch)
+    }
+
+    /// Returns the current value of `key`, or `None` if it is missing or deleted.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_with(&ReadOptions::default(), key)
+    }
+
+    /// Returns the value of `key` as of `options.snapshot`, or its current
+    /// value without one.
+    pub fn get_with(&self, options: &ReadOptions, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (seq, memtables, version) = self.inner.read_view(options);
+
+        // Newest first: the first memtable or table that knows the key decides.
+        let found = match memtables.iter().find_map(|mem| mem.get(key, seq)) {
+            Some(found) => Some(found),
+            None => version.get(&self.inner.icmp, key, seq)?,
+        };
+        Ok(match found {
+            Some(LookupResult::Value(value)) => Some(value),
+            Some(LookupResult::Deleted) | None => None,
+        })
+    }
+
+    /// Pins the current state of the database. Reads given the snapshot see
+    /// it as it is now, whatever is written later.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut state = self.inner.state.lock().unwrap();
+        let sequence = self.inner.last_sequence.load(Ordering::Acquire);
+        *state.snapshots.entry(sequence).or_default() += 1;
+        Snapshot {
+            pinned: Arc::new(PinnedSequence {
+                sequence,
+                db: self.inner.clone(),
+            }),
+        }
+    }
+
+    /// Returns an iterator over the keys visible at `options.snapshot`, or at
+    /// the latest write, within the bounds set in `options`. It is not
+    /// positioned until one of its seek methods is called.
+    pub fn iter(&self, options: ReadOptions) -> DbIterator {
+        let (sequence, memtables, version) = self.inner.read_view(&options);
+
+        let mut children: Vec<Box<dyn InternalIterator>> = memtables
+            .into_iter()
+            .map(|mem| {
+                Box::new(MemTableIterator::new(mem, IterMode::AllVersions))
+                    as Box<dyn InternalIterator>
+            })
+            .collect();
+        children.extend(version.iterators(&self.inner.icmp));
+        let input = MergingIterator::new(children, self.inner.icmp.clone());
+
+        // Tables compacted away while the iterator was open can go once it is
+        // dropped.
+        let inner = self.inner.clone();
+        let release = Box::new(move || {
+            let mut state = inner.state.lock().unwrap();
+            inner.delete_obsolete_tables(&mut state);
+        });
+        DbIterator::new(
+            input,
+            self.inner.icmp.user_comparator().clone(),
+            sequence,
+            options,
+            version,
+            release,
+        )
+    }
+
+    /// Applies every entry of `batch` atomically: either all of them survive a
+    /// crash or none do.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.inner
+            .writers
+            .write(batch, |group| self.inner.commit(group))
+    }
+
+    /// Forces the log to stable storage, whatever the durability mode.
+    pub fn sync(&self) -> Result<()> {
+        self.inner.log.lock().unwrap().sync()
+    }
+
+    /// Turns the memtable into a table file, even if it is not full, and waits
+    /// until every pending flush has finished.
+    pub fn flush(&self) -> Result<()> {
+        {
+            let mut log = self.inner.log.lock().unwrap();
+            self.inner.make_room_for_write(&mut log, 0, true)?;
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        while !state.imm.is_empty() && state.bg_error.is_none() {
+            state = self.inner.state_cv.wait(state).unwrap();
+        }
+        DbInner::check_bg_error(&state)
+    }
+
+    /// How group commit has merged writes so far.
+    pub fn write_stats(&self) -> WriteStats {
+        self.inner.writers.stats()
+    }
+
+    /// How often table filters have spared a data block read so far.
+    pub fn filter_stats(&self) -> FilterStats {
+        self.inner.tables.filter_counters().stats()
+    }
+
+    /// How much table data flushes and compactions have written so far.
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.inner.state.lock().unwrap().compaction_stats.clone()
+    }
+
+    /// Arena bytes allocated by the memtable being written and the ones
+    /// waiting to be flushed: the memory the database holds for its data.
+    pub fn memtable_usage(&self) -> usize {
+        let state = self.inner.state.lock().unwrap();
+        state.mem.allocated()
+            + state
+                .imm
+                .iter()
+                .map(|imm| imm.mem.allocated())
+                .sum::<usize>()
+    }
+
+    /// Number of table files in each level.
+    pub fn level_file_counts(&self) -> Vec<usize> {
+        let version = self.inner.state.lock().unwrap().versions.current();
+        version.files.iter().map(Vec::len).collect()
+    }
+
+    /// Stops the background threads.
+    fn shutdown(&mut self) {
+        if let Some(syncer) = self.syncer.take() {
+            syncer.stop();
+        }
+        self.inner.state.lock().unwrap().shutting_down = true;
+        self.inner.state_cv.notify_all();
+        if let Some(background) = self.background.take() {
+            let _ = background.join();
+        }
+    }
+}
+
+impl Drop for Db {
+    /// Stops the background threads and syncs the log, so a clean shutdown
+    /// loses nothing in any durability mode. Memtables still waiting to be
+    /// flushed are recovered from their logs on the next open.
+    fn drop(&mut self) {
+        self.shutdown();
+        if let Err(e) = self.sync() {
+            eprintln!("Failed to sync log on close: {}", e);
+        }
+    }
+}
+
+impl DbInner {
+    /// Logs and applies a merged group of batches. Only the group commit leader
+    /// calls this, so sequence numbers are handed out in log order.
+    fn commit(&self, mut batch: WriteBatch) -> Result<()> {
+        let mut log = self.log.lock().unwrap();
+        let mem = self.make_room_for_write(&mut log, memtable_need(&batch), false)?;
+        let last_sequence = self.last_sequence.load(Ordering::Relaxed);
+        batch.set_sequence(last_sequence + 1);
+
+        if let Err(e) = log.append(batch.contents(), self.options.durability) {
+            // The log may hold some of the batch now, and the next batch would
+            // reuse its sequence numbers, so no write may follow.
+            return Err(self.stop_writes("log write failed", e));
+        }
+        if let Err(e) = batch.insert_into(&mem) {
+            // Likewise the memtable may hold some of the batch, though
+            // `make_room_for_write` reserved enough for all of it.
+            return Err(self.stop_writes("memtable add failed", e));
+        }
+        self.last_sequence
+            .store(last_sequence + batch.count() as u64, Ordering::Release);
+        Ok(())
+    }
+
+    /// Records `e` as the error every later write fails with, for a failed
+    /// write that leaves the log or the memtable unfit to build on.
+    fn stop_writes(&self, what: &str, e: std::io::Error) -> std::io::Error {
+        let mut state = self.state.lock().unwrap();
+        state.bg_error = Some(format!("{}: {}", what, e));
+        self.state_cv.notify_all();
+        e
+    }
+
+    /// Returns a memtable with room for `need` more bytes, first switching to a
+    /// new memtable and log if the current one is full (or non-empty, when
+    /// `force` is set). Stalls while too many memtables are waiting to be
+    /// flushed or level 0 has too many files. The caller holds the log lock.
+    fn make_room_for_write(
+        &self,
+        log: &mut Wal,
+        need: usize,
+        force: bool,
+    ) -> Result<Arc<MemTable>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            Self::check_bg_error(&state)?;
+            if state.mem.is_empty() {
+                if state.mem.remaining() < need {
+                    // Too big for the default arena; nothing to flush, so just grow it.
+                    let capacity = memtable_capacity(&self.options, need);
+                    state.mem = Arc::new(MemTable::with_capacity(self.icmp.clone(), capacity)?);
+                }
+                return Ok(state.mem.clone());
+            }
+            if !force && has_room(&state.mem, need, &self.options) {
+                return Ok(state.mem.clone());
+            }
+            let level0_files = state.versions.current().files[0].len();
+            if state.imm.len() >= MAX_IMMUTABLE_MEMTABLES
+                || level0_files >= self.options.level0_stop_writes_trigger
+            {
+                state = self.state_cv.wait(state).unwrap();
+                continue;
+            }
+
+            // The old log is only deleted once its memtable is in a table, but
+            // the syncer moves on to the new log, so sync the old one now.
+            let number = state.versions.new_file_number();
+            let new_log = Wal::create(&self.dir, number)?;
+            match self.options.durability {
+                Durability::Always | Durability::EverySec => log.sync()?,
+                Durability::No => log.writer.flush()?,
+            }
+            *log = new_log;
+
+            let capacity = memtable_capacity(&self.options, need);
+            let mem = std::mem::replace(
+                &mut state.mem,
+                Arc::new(MemTable::with_capacity(self.icmp.clone(), capacity)?),
+            );
+            let logs = std::mem::replace(&mut state.mem_logs, vec![number]);
+            state.imm.push_back(Immutable { mem, logs });
+            self.has_imm.store(true, Ordering::Release);
+            self.state_cv.notify_all();
+            // A forced rotation is done; a regular write goes around again in
+            // case the fresh memtable is still too small.
+            if force {
+                return Ok(state.mem.clone());
+            }
+        }
+    }
+
+    /// The sequence number a read with `options` sees, and what it reads: the
+    /// memtables, newest first, and the current version.
+    ///
+    /// The latest sequence number is taken under the state lock, together with
+    /// the version: a compaction installed before then only dropped versions
+    /// that were already shadowed at that sequence number.
+    fn read_view(&self, options: &ReadOptions) -> (u64, Vec<Arc<MemTable>>, Arc<Version>) {
+        let state = self.state.lock().unwrap();
+        let sequence = match &options.snapshot {
+            Some(snapshot) => snapshot.sequence(),
+            None => self.last_sequence.load(Ordering::Acquire),
+        };
+        let memtables = std::iter::once(&state.mem)
+            .chain(state.imm.iter().rev().map(|imm| &imm.mem))
+            .cloned()
+            .collect();
+        (sequence, memtables, state.versions.current())
+    }
+
+    fn check_bg_error(state: &MutexGuard<State>) -> Result<()> {
+        match &state.bg_error {
+            Some(e) => Err(std::io::Error::other(format!(
+                "writes stopped after a failure: {}",
+                e
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Body of the background thread: flushes immutable memtables, oldest
+    /// first, and runs compactions while no flush is pending, until shut down.
+    fn run_background(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.shutting_down {
+                return;
+            }
+            if state.bg_error.is_some() {
+                state = self.state_cv.wait(state).unwrap();
+                continue;
+            }
+
+            let result = if !state.imm.is_empty() {
+                drop(state);
+                self.flush_oldest_imm()
+            } else if let Some(compaction) = state.versions.pick_compaction() {
+                drop(state);
+                self.compact(compaction)
+            } else {
+                state = self.state_cv.wait(state).unwrap();
+                continue;
+            };
+
+            state = self.state.lock().unwrap();
+            if let Err(e) = result {
+                eprintln!("Background work failed: {}", e);
+                state.bg_error = Some(e.to_string());
+            }
+            self.state_cv.notify_all();
+        }
+    }
+
+    /// Writes the oldest immutable memtable to a level 0 table and deletes the
+    /// logs it no longer needs.
+    fn flush_oldest_imm(&self) -> Result<()> {
+        let (mem, number) = {
+            let mut state = self.state.lock().unwrap();
+            let Some(imm) = state.imm.front() else {
+                return Ok(());
+            };
+            (imm.mem.clone(), state.versions.new_file_number())
+        };
+        let meta = write_memtable(&self.tables, number, &mem)?;
+
+        let mut state = self.state.lock().unwrap();
+        // Every log before the next memtable's first one is now redundant.
+        let next_log = match state.imm.get(1) {
+            Some(next) => next.logs[0],
+            None => state.mem_logs[0],
+        };
+        let file_size = meta.file_size;
+        let mut edit = VersionEdit {
+            log_number: Some(next_log),
+            new_files: vec![(0, meta)],
+            ..VersionEdit::default()
+        };
+        state
+            .versions
+            .log_and_apply(&mut edit, self.last_sequence.load(Ordering::Acquire))?;
+        state.compaction_stats.bytes_flushed += file_size;
+
+        let imm = state.imm.pop_front().unwrap();
+        for number in imm.logs {
+            if let Err(e) = fs::remove_file(filename::log_file_name(&self.dir, number)) {
+                eprintln!("Failed to delete log {}: {}", number, e);
+            }
+        }
+        self.has_imm.store(!state.imm.is_empty(), Ordering::Release);
+        self.state_cv.notify_all();
+        Ok(())
+    }
+
+    /// Merges the inputs of `c` into new files in the output level and
+    /// installs them in place of the inputs.
+    ///
+    /// Of the versions of a key, only the newest is kept: no reader can see an
+    /// older one. A deletion is dropped too once no deeper level can hold the
+    /// key it hides.
+    fn compact(&self, c: Compaction) -> Result<()> {
+        let mut edit = VersionEdit::default();
+        c.add_input_deletions(&mut edit);
+
+        if c.is_trivial_move() {
+            let f = &c.inputs[0][0];
+            edit.new_files.push((c.output_level, f.meta.clone()));
+            let mut state = self.state.lock().unwrap();
+            return state
+                .versions
+                .log_and_apply(&mut edit, self.last_sequence.load(Ordering::Acquire));
+        }
+
+        let mut outputs = Vec::new();
+        if let Err(e) = self.write_compaction_outputs(&c, &mut outputs) {
+            for meta in &outputs {
+                let _ = fs::remove_file(filename::table_file_name(&self.dir, meta.number));
+            }
+            return Err(e);
+        }
+
+        let bytes_written = outputs.iter().map(|meta| meta.file_size).sum::<u64>();
+        edit.new_files
+            .extend(outputs.into_iter().map(|meta| (c.output_level, meta)));
+        {
+            let mut state = self.state.lock().unwrap();
+            state
+                .versions
+                .log_and_apply(&mut edit, self.last_sequence.load(Ordering::Acquire))?;
+            let stats = &mut state.compaction_stats;
+            stats.bytes_read += c
+                .inputs
+                .iter()
+                .flatten()
+                .map(|f| f.meta.file_size)
+                .sum::<u64>();
+            stats.bytes_written += bytes_written;
+            stats.compactions += 1;
+        }
+        // The inputs go once no open iterator still reads them. The compaction
+        // itself holds the version it started from, so let go of it first.
+        let inputs: Vec<u64> = c.inputs.iter().flatten().map(|f| f.meta.number).collect();
+        drop(c);
+        let mut state = self.state.lock().unwrap();
+        state.obsolete_tables.extend(inputs);
+        self.delete_obsolete_tables(&mut state);
+        Ok(())
+    }
+
+    /// Deletes the tables compacted away that no version in use still holds.
+    fn delete_obsolete_tables(&self, state: &mut State) {
+        if state.obsolete_tables.is_empty() {
+            return;
+        }
+        let live = state.versions.live_files();
+        state.obsolete_tables.retain(|&number| {
+            if live.contains(&number) {
+                return true;
+            }
+            self.tables.evict(number);
+            if let Err(e) = fs::remove_file(filename::table_file_name(&self.dir, number)) {
+                eprintln!("Failed to delete table {}: {}", number, e);
+            }
+            false
+        });
+    }
+
+    fn write_compaction_outputs(
+        &self,
+        c: &Compaction,
+        outputs: &mut Vec<FileMetaData>,
+    ) -> Result<()> {
+        let ucmp = self.icmp.user_comparator();
+        let children = c
+            .inputs
+            .iter()
+            .flatten()
+            .map(|f| Box::new(f.table.iter()) as Box<dyn InternalIterator>)
+            .collect();
+        let mut input = MergingIterator::new(children, self.icmp.clone());
+        input.seek_to_first();
+
+        // A version can only be dropped if no reader can see it: not one
+        // starting from now, nor one holding a snapshot.
+        let smallest_snapshot = {
+            let state = self.state.lock().unwrap();
+            let last_sequence = self.last_sequence.load(Ordering::Acquire);
+            match state.snapshots.keys().next() {
+                Some(&oldest) => oldest.min(last_sequence),
+                None => last_sequence,
+            }
+        };
+        let mut writer: Option<TableWriter> = None;
+        let mut current_user_key: Option<Vec<u8>> = None;
+        let mut last_sequence_for_key = u64::MAX;
+
+        let result = (|| {
+            while input.valid() {
+                // Keep writes flowing: a pending flush goes before the rest of
+                // a long compaction.
+                if self.has_imm.load(Ordering::Acquire) {
+                    self.flush_oldest_imm()?;
+                }
+
+                let Some(key) = ParsedInternalKey::decode(input.key()) else {
+                    return err(StatusCode::Corruption, "bad internal key in table");
+                };
+                let first_occurrence = current_user_key
+                    .as_deref()
+                    .is_none_or(|k| ucmp.compare(k, key.user_key) != KeyOrdering::Equal);
+                if first_occurrence {
+                    // Outputs are only cut between user keys, so a key never
+                    // spans two files of one level.
+                    if let Some(w) = writer.take_if(|w| w.file_size() >= c.max_output_file_size) {
+                        outputs.push(w.finish()?);
+                    }
+                    current_user_key = Some(key.user_key.to_vec());
+                    last_sequence_for_key = u64::MAX;
+                }
+
+                let drop = if last_sequence_for_key <= smallest_snapshot {
+                    // A newer version of this key is visible to every reader.
+                    true
+                } else {
+                    key.value_type == ValueType::TypeDeletion
+                        && key.sequence <= smallest_snapshot
+                        && c.is_base_level_for_key(&self.icmp, key.user_key)
+                };
+                last_sequence_for_key = key.sequence;
+
+                if !drop {
+                    if writer.is_none() {
+                        let number = self.state.lock().unwrap().versions.new_file_number();
+                        writer = Some(self.tables.create(number)?);
+                    }
+                    writer.as_mut().unwrap().add(input.key(), input.value())?;
+                }
+                input.next();
+            }
+            input.status()?;
+            if let Some(w) = writer.take() {
+                outputs.push(w.finish()?);
+            }
+            Ok(())
+        })();
+
+        if let Some(w) = writer {
+            w.abandon();
+        }
+        result
+    }
+}
+
+/// Arena bytes a batch may take once inserted into a memtable.
+fn memtable_need(batch: &WriteBatch) -> usize {
+    batch.approximate_size() + batch.count() as usize * ENTRY_OVERHEAD
+}
+
+/// Whether `mem` should take `need` more bytes rather than be rotated out.
+fn has_room(mem: &MemTable, need: usize, options: &Options) -> bool {
+    mem.remaining() >= need && mem.allocated() + need <= options.write_buffer_size
+}
+
+/// Arena size for a new memtable, leaving headroom past the rotation threshold
+/// for the write that crosses it.
+fn memtable_capacity(options: &Options, need: usize) -> usize {
+    options.write_buffer_size.max(need) * 2
+}
+
+/// Writes the contents of `mem` to table file `number`.
+fn write_memtable(tables: &TableCache, number: u64, mem: &MemTable) -> Result<FileMetaData> {
+    let mut writer = tables.create(number)?;
+    for (key, value) in mem.entries() {
+        if let Err(e) = writer.add(key, value) {
+            writer.abandon();
+            return Err(e);
+        }
+    }
+    writer.finish()
+}
+
+/// Removes logs that are already in tables, tables that are not part of the
+/// current version (left behind by a compaction that never finished), old
+/// manifests and temp files.
+fn delete_obsolete_files(dir: &Path, versions: &mut VersionSet) -> Result<()> {
+    let live = versions.live_files();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let Some((number, file_type)) = name.to_str().and_then(filename::parse_file_name) else {
+            continue;
+        };
+        let obsolete = match file_type {
+            FileType::Log => number < versions.log_number(),
+            FileType::Table => !live.contains(&number),
+            FileType::Manifest => number != versions.manifest_number(),
+            FileType::Temp => true,
+            FileType::Current => false,
+        };
+        if obsolete {
+            fs::remove_file(dir.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Replays one log file, passing each batch to `apply`, and returns the highest
+/// sequence number seen.
+///
+/// Each record is one `WriteBatch` and is applied whole or not at all. A record
+/// cut short at the end of the log (a crash in the middle of a write) is treated
+/// as the end of the log. A record that fails its checksum or cannot be decoded
+/// is skipped.
+fn recover_log(path: &Path, mut apply: impl FnMut(WriteBatch) -> Result<()>) -> Result<u64> {
+    let mut reader = LogReader::new(BufReader::new(File::open(path)?), true);
+    let mut buf = Vec::new();
+    let mut last_sequence = 0;
+
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::Other => {
+                eprintln!("{}: skipping record: {}", path.display(), e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+
+        match WriteBatch::from_contents(std::mem::take(&mut buf)) {
+            // An empty batch takes no sequence number.
+            Ok(batch) if batch.is_empty() => {}
+            Ok(batch) => {
+                last_sequence = last_sequence.max(batch.sequence() + batch.count() as u64 - 1);
+                apply(batch)?;
+            }
+            Err(e) => eprintln!("{}: skipping record: {}", path.display(), e),
+        }
+    }
+    Ok(last_sequence)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::options::{CompactionStrategy, SizeTieredOptions};
+    use std::collections::BTreeMap;
+
+    /// Returns an empty scratch directory unique to `name`.
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("wdis-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    impl Db {
+        /// Simulates a machine crash: everything written to the current log
+        /// since its last sync is lost.
+        pub(crate) fn simulate_crash(mut self) {
+            self.shutdown();
+            let wal = self.inner.log.lock().unwrap();
+            wal.writer
+                .get_ref()
+                .get_ref()
+                .set_len(wal.synced_len)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_db_put_get_delete() {
+        let dir = test_dir("db_put_get_delete");
+        let db = Db::open(&dir).unwrap();
+
+        assert_eq!(db.get(b"k").unwrap(), None);
+        db.put(b"k", b"v1").unwrap();
+        db.put(b"k", b"v2").unwrap();
+        assert_eq!(db.get(b"k").unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(db.last_sequence(), 2);
+
+        db.delete(b"k").unwrap();
+        assert_eq!(db.get(b"k").unwrap(), None);
+        assert_eq!(db.last_sequence(), 3);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_writes_log() {
+        let dir = test_dir("db_writes_log");
+        let db = Db::open(&dir).unwrap();
+        db.put(b"key", b"value").unwrap();
+
+        let first_log = db.log_number();
+        let log = filename::log_file_name(&dir, first_log);
+        let mut reader = crate::log::LogReader::new(File::open(log).unwrap(), true);
+        let mut record = Vec::new();
+        assert!(reader.read(&mut record).unwrap() > 0);
+        let mut expected = WriteBatch::new();
+        expected.put(b"key", b"value");
+        expected.set_sequence(1);
+        assert_eq!(record, expected.contents());
+        assert_eq!(reader.read(&mut record).unwrap(), 0);
+
+        // Reopening starts a new log next to the old one.
+        drop(db);
+        let db = Db::open(&dir).unwrap();
+        assert!(db.log_number() > first_log);
+        assert_eq!(files(&dir, FileType::Log), vec![first_log, db.log_number()]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_recover_log_skips_empty_batch() {
+        let dir = test_dir("recover_log_empty_batch");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.log");
+        let mut writer = LogWriter::new(File::create(&path).unwrap());
+        let mut empty = WriteBatch::new();
+        empty.set_sequence(7);
+        writer.add_record(empty.contents()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"k", b"v");
+        batch.set_sequence(3);
+        writer.add_record(batch.contents()).unwrap();
+        writer.flush().unwrap();
+
+        let mut applied = 0;
+        let last = recover_log(&path, |_| {
+            applied += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(last, 3);
+        assert_eq!(applied, 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_recover() {
+        let dir = test_dir("db_recover");
+        {
+            let db = Db::open(&dir).unwrap();
+            db.put(b"a", b"1").unwrap();
+            db.put(b"b", b"2").unwrap();
+            db.delete(b"a").unwrap();
+        }
+        {
+            let db = Db::open(&dir).unwrap();
+            assert_eq!(db.get(b"a").unwrap(), None);
+            assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+            assert_eq!(db.last_sequence(), 3);
+            db.put(b"a", b"3").unwrap();
+        }
+
+        // Logs from both earlier sessions are replayed in order.
+        let db = Db::open(&dir).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.last_sequence(), 4);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_write_batch() {
+        let dir = test_dir("db_write_batch");
+        {
+            let db = Db::open(&dir).unwrap();
+            db.put(b"a", b"0").unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch.put(b"a", b"1");
+            batch.put(b"b", b"2");
+            batch.delete(b"a");
+            batch.put(b"c", b"3");
+            db.write(batch).unwrap();
+            db.write(WriteBatch::new()).unwrap();
+
+            assert_eq!(db.last_sequence(), 5);
+            assert_eq!(db.get(b"a").unwrap(), None);
+            assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+        }
+
+        let db = Db::open(&dir).unwrap();
+        assert_eq!(db.last_sequence(), 5);
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap(), Some(b"3".to_vec()));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_group_commit() {
+        let dir = test_dir("db_group_commit");
+        let options = Options {
+            max_write_group_size: 4,
+            ..Options::default()
+        };
+        {
+            let db = std::sync::Arc::new(Db::open_with(&dir, options.clone()).unwrap());
+            let handles: Vec<_> = (0..8)
+                .map(|i| {
+                    let db = db.clone();
+                    std::thread::spawn(move || {
+                        for j in 0..50 {
+                            db.put(format!("{}-{}", i, j).as_bytes(), b"v").unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for h in handles {
+                h.join().unwrap();
+            }
+
+            let stats = db.write_stats();
+            assert_eq!(stats.batches, 400);
+            assert!(stats.largest_group <= 4);
+            assert_eq!(db.last_sequence(), 400);
+        }
+
+        // Grouped records recover like any other batch.
+        let db = Db::open_with(&dir, options).unwrap();
+        assert_eq!(db.last_sequence(), 400);
+        for i in 0..8 {
+            for j in 0..50 {
+                assert!(db.get(format!("{}-{}", i, j).as_bytes()).unwrap().is_some());
+            }
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn durability_options(durability: Durability, sync_interval: std::time::Duration) -> Options {
+        Options {
+            durability,
+            sync_interval,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn test_db_durability_always() {
+        let dir = test_dir("db_durability_always");
+        let options = durability_options(Durability::Always, std::time::Duration::from_secs(3600));
+        let db = Db::open_with(&dir, options.clone()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"2").unwrap();
+        db.simulate_crash();
+
+        let db = Db::open_with(&dir, options).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_log_write_failure() {
+        let dir = test_dir("db_log_write_failure");
+        let options = durability_options(Durability::Always, std::time::Duration::from_secs(3600));
+        let db = Db::open_with(&dir, options.clone()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        let last_sequence = db.inner.last_sequence.load(Ordering::Acquire);
+
+        // Every write to /dev/full fails for lack of space.
+        let full = OpenOptions::new().write(true).open("/dev/full").unwrap();
+        let wal = Wal {
+            number: 0,
+            writer: LogWriter::new(BufWriter::new(full)),
+            dirty: false,
+            synced_len: 0,
+        };
+        let log = std::mem::replace(&mut *db.inner.log.lock().unwrap(), wal);
+        assert!(db.put(b"b", b"2").is_err());
+        assert_eq!(
+            db.inner.last_sequence.load(Ordering::Acquire),
+            last_sequence
+        );
+        assert_eq!(db.get(b"b").unwrap(), None);
+
+        // Later writes fail even once the log works again.
+        *db.inner.log.lock().unwrap() = log;
+        let e = db.put(b"c", b"3").unwrap_err();
+        assert!(e.to_string().contains("log write failed"), "{}", e);
+        assert_eq!(db.get(b"c").unwrap(), None);
+        drop(db);
+
+        let db = Db::open_with(&dir, options).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+        db.put(b"c", b"3").unwrap();
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_durability_no() {
+        let dir = test_dir("db_durability_no");
+        let options = durability_options(Durability::No, std::time::Duration::from_millis(10));
+        let db = Db::open_with(&dir, options.clone()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        db.put(b"b", b"2").unwrap();
+        db.simulate_crash();
+
+        // Nothing was ever synced, so the whole session is lost.
+        let db = Db::open_with(&dir, options.clone()).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), None);
+        assert_eq!(db.get(b"b").unwrap(), None);
+
+        // A clean close syncs.
+        db.put(b"c", b"3").unwrap();
+        drop(db);
+        let db = Db::open_with(&dir, options).unwrap();
+        assert_eq!(db.get(b"c").unwrap(), Some(b"3".to_vec()));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_durability_everysec() {
+        let dir = test_dir("db_durability_everysec");
+
+        // Writes older than one interval have been synced by the background thread.
+        let options =
+            durability_options(Durability::EverySec, std::time::Duration::from_millis(20));
+        let db = Db::open_with(&dir, options.clone()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        db.simulate_crash();
+        let db = Db::open_with(&dir, options).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        drop(db);
+
+        // Writes made since the last sync are lost.
+        let options =
+            durability_options(Durability::EverySec, std::time::Duration::from_secs(3600));
+        let db = Db::open_with(&dir, options.clone()).unwrap();
+        db.put(b"b", b"2").unwrap();
+        db.simulate_crash();
+        let db = Db::open_with(&dir, options).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_recover_partial_batch() {
+        let dir = test_dir("db_recover_partial_batch");
+        let (number, complete) = {
+            let db = Db::open(&dir).unwrap();
+            db.put(b"before", b"1").unwrap();
+            let complete = fs::metadata(filename::log_file_name(&dir, db.log_number()))
+                .unwrap()
+                .len();
+
+            let mut batch = WriteBatch::new();
+            for i in 0..1000 {
+                batch.put(format!("key{}", i).as_bytes(), &[b'x'; 100]);
+            }
+            db.write(batch).unwrap();
+            (db.log_number(), complete)
+        };
+
+        // The batch spans several log blocks; losing its tail must lose all of it.
+        let len = fs::metadata(filename::log_file_name(&dir, number))
+            .unwrap()
+            .len();
+        assert!(len - complete > 2 * 32 * 1024);
+        truncate_log(&dir, number, len - 10);
+
+        let db = Db::open(&dir).unwrap();
+        assert_eq!(db.get(b"before").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"key0").unwrap(), None);
+        assert_eq!(db.get(b"key999").unwrap(), None);
+        assert_eq!(db.last_sequence(), 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn truncate_log(dir: &Path, number: u64, len: u64) {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(filename::log_file_name(dir, number))
+            .unwrap();
+        file.set_len(len).unwrap();
+    }
+
+    #[test]
+    fn test_db_recover_truncated_record() {
+        let dir = test_dir("db_recover_truncated_record");
+        let log_len = |db: &Db| {
+            fs::metadata(filename::log_file_name(&dir, db.log_number()))
+                .unwrap()
+                .len()
+        };
+
+        let (number, complete, full) = {
+            let db = Db::open(&dir).unwrap();
+            db.put(b"k1", b"v1").unwrap();
+            db.put(b"k2", b"v2").unwrap();
+            let complete = log_len(&db);
+            db.put(b"k3", b"v3").unwrap();
+            (db.log_number(), complete, log_len(&db))
+        };
+
+        // Cut the last record anywhere inside its header or payload.
+        for len in complete..full {
+            let copy = test_dir(&format!("db_recover_truncated_record_{}", len));
+            fs::create_dir_all(&copy).unwrap();
+            fs::copy(
+                filename::log_file_name(&dir, number),
+                filename::log_file_name(&copy, number),
+            )
+            .unwrap();
+            truncate_log(&copy, number, len);
+
+            let db = Db::open(&copy).unwrap();
+            assert_eq!(db.get(b"k1").unwrap(), Some(b"v1".to_vec()));
+            assert_eq!(db.get(b"k2").unwrap(), Some(b"v2".to_vec()));
+            assert_eq!(db.get(b"k3").unwrap(), None);
+            assert_eq!(db.last_sequence(), 2);
+
+            // The lost sequence number is handed out again.
+            db.put(b"k3", b"v3'").unwrap();
+            assert_eq!(db.last_sequence(), 3);
+            drop(db);
+            let db = Db::open(&copy).unwrap();
+            assert_eq!(db.get(b"k3").unwrap(), Some(b"v3'".to_vec()));
+
+            let _ = fs::remove_dir_all(&copy);
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn files(dir: &Path, file_type: FileType) -> Vec<u64> {
+        let mut numbers: Vec<u64> = fs::read_dir(dir)
+            .unwrap()
+            .filter_map(|e| filename::parse_file_name(e.unwrap().file_name().to_str()?))
+            .filter(|&(_, t)| t == file_type)
+            .map(|(n, _)| n)
+            .collect();
+        numbers.sort_unstable();
+        numbers
+    }
+
+    fn small_buffer_options() -> Options {
+        Options {
+            write_buffer_size: 64 * 1024,
+            // Keeps every flushed table in level 0.
+            level0_compaction_trigger: 100,
+            level0_stop_writes_trigger: 100,
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn test_db_rotate_and_flush() {
+        let dir = test_dir("db_rotate_and_flush");
+        let db = Db::open_with(&dir, small_buffer_options()).unwrap();
+        for i in 0..2000 {
+            db.put(format!("key{:05}", i).as_bytes(), &[b'v'; 100])
+                .unwrap();
+        }
+        assert_eq!(db.get(b"key01999").unwrap(), Some(vec![b'v'; 100]));
+        db.flush().unwrap();
+
+        // Every memtable is now in a table, and only the current log is left.
+        let state = db.inner.state.lock().unwrap();
+        assert!(state.mem.is_empty());
+        assert!(state.imm.is_empty());
+        let level0 = &state.versions.current().files[0];
+        assert!(level0.len() > 1);
+        assert!(level0
+            .windows(2)
+            .all(|w| w[0].meta.number > w[1].meta.number));
+        assert_eq!(level0[0].table.largest_sequence(), 2000);
+        assert_eq!(
+            files(&dir, FileType::Table),
+            level0
+                .iter()
+                .rev()
+                .map(|f| f.meta.number)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(files(&dir, FileType::Log), vec![db.log_number()]);
+        drop(state);
+
+        // Flushing an empty memtable is a no-op.
+        let tables = files(&dir, FileType::Table);
+        db.flush().unwrap();
+        assert_eq!(files(&dir, FileType::Table), tables);
+        drop(db);
+
+        // Sequence numbers resume after the tables even with no log to replay.
+        let db = Db::open_with(&dir, small_buffer_options()).unwrap();
+        assert_eq!(db.last_sequence(), 2000);
+        assert!(db.log_number() > *tables.last().unwrap());
+        for i in (0..2000).step_by(7) {
+            let key = format!("key{:05}", i);
+            assert_eq!(db.get(key.as_bytes()).unwrap(), Some(vec![b'v'; 100]));
+        }
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_recover_flushes_large_log() {
+        let dir = test_dir("db_recover_flushes_large_log");
+        {
+            let db = Db::open(&dir).unwrap();
+            for i in 0..2000 {
+                db.put(format!("key{:05}", i).as_bytes(), &[b'v'; 100])
+                    .unwrap();
+            }
+        }
+        assert!(files(&dir, FileType::Table).is_empty());
+        fs::write(filename::temp_file_name(&dir, 100), b"partial").unwrap();
+
+        // The log no longer fits in one memtable, so recovery writes tables and
+        // drops the replayed log.
+        let db = Db::open_with(&dir, small_buffer_options()).unwrap();
+        assert_eq!(db.last_sequence(), 2000);
+        assert!(files(&dir, FileType::Table).len() > 1);
+        assert_eq!(files(&dir, FileType::Log), vec![db.log_number()]);
+        assert!(files(&dir, FileType::Temp).is_empty());
+        assert!(db.log_number() > 100);
+        assert_eq!(db.get(b"key00000").unwrap(), Some(vec![b'v'; 100]));
+        assert_eq!(db.get(b"key01999").unwrap(), Some(vec![b'v'; 100]));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_get_newest_first() {
+        let dir = test_dir("db_get_newest_first");
+        let db = Db::open(&dir).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.put(b"b", b"1").unwrap();
+        db.put(b"c", b"1").unwrap();
+        db.flush().unwrap();
+        db.put(b"a", b"2").unwrap();
+        db.delete(b"b").unwrap();
+        db.flush().unwrap();
+        db.put(b"a", b"3").unwrap();
+
+        // Memtable, then the newer table, then the older one.
+        assert_eq!(db.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"d").unwrap(), None);
+        drop(db);
+
+        let db = Db::open(&dir).unwrap();
+        assert_eq!(db.level_file_counts()[0], 2);
+        assert_eq!(db.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap(), Some(b"1".to_vec()));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_filter_stats() {
+        let dir = test_dir("db_filter_stats");
+        let db = Db::open(&dir).unwrap();
+        for i in 0..100 {
+            db.put(format!("key{}", i).as_bytes(), b"v").unwrap();
+        }
+        db.flush().unwrap();
+
+        for i in 0..99 {
+            // Inside the table's key range, so only the filter can rule it out.
+            assert_eq!(db.get(format!("key{}!", i).as_bytes()).unwrap(), None);
+        }
+        assert_eq!(db.get(b"key7").unwrap(), Some(b"v".to_vec()));
+        let stats = db.filter_stats();
+        assert!(stats.hits > 90);
+        assert_eq!(stats.hits + stats.misses, 100);
+        assert_eq!(stats.misses - stats.false_positives, 1);
+        drop(db);
+
+        // Without a filter policy nothing is counted.
+        let options = Options {
+            filter_policy: None,
+            ..Options::default()
+        };
+        let db = Db::open_with(&dir, options).unwrap();
+        assert_eq!(db.get(b"key5!").unwrap(), None);
+        assert_eq!(db.filter_stats(), FilterStats::default());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn compaction_options() -> Options {
+        Options {
+            write_buffer_size: 16 * 1024,
+            level0_compaction_trigger: 2,
+            max_bytes_for_level_base: 64 * 1024,
+            max_file_size: 32 * 1024,
+            ..Options::default()
+        }
+    }
+
+    /// Waits until the background thread has nothing left to do.
+    fn wait_for_compactions(db: &Db) {
+        let mut state = db.inner.state.lock().unwrap();
+        while (!state.imm.is_empty() || state.versions.needs_compaction())
+            && state.bg_error.is_none()
+        {
+            state = db.inner.state_cv.wait(state).unwrap();
+        }
+        assert_eq!(state.bg_error, None);
+    }
+
+    /// Number of entries in all table files, old versions and deletions included.
+    fn table_entries(db: &Db) -> usize {
+        let version = db.inner.state.lock().unwrap().versions.current();
+        let mut count = 0;
+        for f in version.files.iter().flatten() {
+            let mut it = f.table.iter();
+            it.seek_to_first();
+            while it.valid() {
+                count += 1;
+                it.next();
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn test_db_compaction() {
+        let dir = test_dir("db_compaction");
+        let db = Db::open_with(&dir, compaction_options()).unwrap();
+        for round in 0..3 {
+            for i in 0..1000 {
+                let value = format!("{}-{}", round, i).repeat(10);
+                db.put(format!("key{:04}", i).as_bytes(), value.as_bytes())
+                    .unwrap();
+            }
+        }
+        for i in (0..1000).step_by(3) {
+            db.delete(format!("key{:04}", i).as_bytes()).unwrap();
+        }
+        db.flush().unwrap();
+        wait_for_compactions(&db);
+
+        let counts = db.level_file_counts();
+        assert!(counts[0] < 2, "{:?}", counts);
+        assert!(counts[1..].iter().sum::<usize>() > 1, "{:?}", counts);
+        // Overwritten versions and most deletions are gone.
+        assert!(table_entries(&db) < 1500, "{}", table_entries(&db));
+
+        let check = |db: &Db| {
+            for i in 0..1000 {
+                let expected = (i % 3 != 0).then(|| format!("2-{}", i).repeat(10).into_bytes());
+                assert_eq!(db.get(format!("key{:04}", i).as_bytes()).unwrap(), expected);
+            }
+        };
+        check(&db);
+
+        // Only live tables and the latest manifest are left on disk.
+        let live = db
+            .inner
+            .state
+            .lock()
+            .unwrap()
+            .versions
+            .current()
+            .live_files();
+        let mut tables = files(&dir, FileType::Table);
+        let mut expected: Vec<u64> = live.into_iter().collect();
+        tables.sort_unstable();
+        expected.sort_unstable();
+        assert_eq!(tables, expected);
+        assert_eq!(files(&dir, FileType::Manifest).len(), 1);
+        drop(db);
+
+        let db = Db::open_with(&dir, compaction_options()).unwrap();
+        assert_eq!(db.last_sequence(), 3000 + 334);
+        check(&db);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_manifest_recover() {
+        let dir = test_dir("db_manifest_recover");
+        let db = Db::open(&dir).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.flush().unwrap();
+        let table = files(&dir, FileType::Table);
+        assert_eq!(table.len(), 1);
+        drop(db);
+
+        // A table the manifest does not know about is left over from a
+        // compaction that never finished, and is removed.
+        fs::copy(
+            filename::table_file_name(&dir, table[0]),
+            filename::table_file_name(&dir, 1000),
+        )
+        .unwrap();
+        let db = Db::open(&dir).unwrap();
+        assert_eq!(files(&dir, FileType::Table), table);
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert!(db.log_number() > 1000);
+        drop(db);
+
+        // Tables cannot be trusted without the manifest naming them.
+        fs::remove_file(filename::current_file_name(&dir)).unwrap();
+        let e = Db::open(&dir).err().unwrap();
+        assert!(e.to_string().contains("Corruption"), "{}", e);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_size_tiered_compaction() {
+        const TRIGGER: usize = 8;
+        // The same writes under each strategy.
+        let run = |name: &str, strategy: CompactionStrategy| {
+            let dir = test_dir(name);
+            let options = Options {
+                compaction_strategy: strategy,
+                level0_compaction_trigger: TRIGGER,
+                max_bytes_for_level_base: 32 * 1024,
+                max_file_size: 8 * 1024,
+                ..compaction_options()
+            };
+            let db = Db::open_with(&dir, options.clone()).unwrap();
+            // An ingest: every key written once, in no particular order.
+            let key = |i: usize| format!("key{:05}", (i * 7919) % 10000);
+            for i in 0..10000 {
+                db.put(key(i).as_bytes(), i.to_string().repeat(8).as_bytes())
+                    .unwrap();
+            }
+            db.flush().unwrap();
+            wait_for_compactions(&db);
+            let check = |db: &Db| {
+                for i in (0..10000).step_by(7) {
+                    let expected = i.to_string().repeat(8).into_bytes();
+                    assert_eq!(db.get(key(i).as_bytes()).unwrap(), Some(expected));
+                }
+            };
+            check(&db);
+            let stats = db.compaction_stats();
+            let counts = db.level_file_counts();
+            drop(db);
+
+            let db = Db::open_with(&dir, options).unwrap();
+            check(&db);
+            drop(db);
+            let _ = fs::remove_dir_all(&dir);
+            (stats, counts)
+        };
+
+        let (leveled, _) = run("db_compaction_leveled", CompactionStrategy::Leveled);
+        let tiered = SizeTieredOptions {
+            size_ratio: 1,
+            min_merge_width: 4,
+            max_merge_width: 8,
+        };
+        let (size_tiered, counts) = run(
+            "db_compaction_size_tiered",
+            CompactionStrategy::SizeTiered(tiered),
+        );
+
+        assert_eq!(leveled.strategy, CompactionStrategy::Leveled);
+        assert_eq!(size_tiered.strategy, CompactionStrategy::SizeTiered(tiered));
+        assert!(size_tiered.compactions > 0);
+        // Every run stays in level 0, below the trigger.
+        assert!(counts[0] < TRIGGER, "{:?}", counts);
+        assert!(counts[1..].iter().all(|&n| n == 0), "{:?}", counts);
+        assert!(
+            size_tiered.write_amplification() < leveled.write_amplification(),
+            "{:?} {:?}",
+            size_tiered,
+            leveled
+        );
+    }
+
+    fn scan(it: &mut DbIterator) -> Vec<(String, String)> {
+        let mut out = Vec::new();
+        while it.valid() {
+            out.push((
+                String::from_utf8(it.key().to_vec()).unwrap(),
+                String::from_utf8(it.value().to_vec()).unwrap(),
+            ));
+            it.next();
+        }
+        it.status().unwrap();
+        out
+    }
+
+    fn model_range(
+        model: &BTreeMap<String, String>,
+        range: impl std::ops::RangeBounds<String>,
+    ) -> Vec<(String, String)> {
+        model
+            .range(range)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_db_iterator() {
+        let dir = test_dir("db_iterator");
+        let db = Db::open_with(&dir, compaction_options()).unwrap();
+        let mut model = BTreeMap::new();
+        let mut snapshot = None;
+        for round in 0..5u64 {
+            for i in 0..400u64 {
+                let key = format!(
+                    "{}{:03}",
+                    ["a", "b", "c"][(i % 3) as usize],
+                    (i * 37 + round) % 300
+                );
+                if (i + round) % 5 == 0 {
+                    db.delete(key.as_bytes()).unwrap();
+                    model.remove(&key);
+                } else {
+                    let value = format!("{}-{}", round, i);
+                    db.put(key.as_bytes(), value.as_bytes()).unwrap();
+                    model.insert(key, value);
+                }
+            }
+            if round == 1 {
+                snapshot = Some((db.snapshot(), model.clone()));
+            }
+            if round < 4 {
+                db.flush().unwrap();
+            }
+        }
+        // Entries are spread over the memtable, level 0 and deeper levels.
+        wait_for_compactions(&db);
+        assert!(!db.inner.state.lock().unwrap().mem.is_empty());
+        assert!(db.level_file_counts()[1..].iter().sum::<usize>() > 0);
+
+        let mut it = db.iter(ReadOptions::default());
+        assert!(!it.valid());
+        it.seek_to_first();
+        assert_eq!(scan(&mut it), model_range(&model, ..));
+        it.seek(b"b1");
+        assert_eq!(scan(&mut it), model_range(&model, "b1".to_string()..));
+        it.seek(b"d");
+        assert!(!it.valid());
+
+        let (snapshot, old) = snapshot.unwrap();
+        let mut it = db.iter(ReadOptions {
+            snapshot: Some(snapshot),
+            ..ReadOptions::default()
+        });
+        it.seek_to_first();
+        assert_eq!(scan(&mut it), model_range(&old, ..));
+
+        let mut it = db.iter(ReadOptions {
+            lower_bound: Some(b"a100".to_vec()),
+            upper_bound: Some(b"b050".to_vec()),
+            ..ReadOptions::default()
+        });
+        it.seek_to_first();
+        let bounded = model_range(&model, "a100".to_string().."b050".to_string());
+        assert_eq!(scan(&mut it), bounded);
+        // Seeking before the lower bound starts at the bound.
+        it.seek(b"a");
+        assert_eq!(scan(&mut it), bounded);
+
+        let mut it = db.iter(ReadOptions {
+            prefix: Some(b"b".to_vec()),
+            ..ReadOptions::default()
+        });
+        it.seek_to_first();
+        assert_eq!(
+            scan(&mut it),
+            model_range(&model, "b".to_string().."c".to_string())
+        );
+        it.seek(b"b2");
+        assert_eq!(
+            scan(&mut it),
+            model_range(&model, "b2".to_string().."c".to_string())
+        );
+        it.seek(b"c");
+        assert!(!it.valid());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_iterator_keeps_files() {
+        let dir = test_dir("db_iterator_keeps_files");
+        let db = Db::open_with(&dir, compaction_options()).unwrap();
+        for i in 0..300 {
+            db.put(format!("key{:03}", i).as_bytes(), b"old").unwrap();
+        }
+        db.flush().unwrap();
+        wait_for_compactions(&db);
+        let old_tables = files(&dir, FileType::Table);
+
+        let mut it = db.iter(ReadOptions::default());
+        for round in 0..4 {
+            for i in 0..300 {
+                db.put(
+                    format!("key{:03}", i).as_bytes(),
+                    format!("new{}", round).as_bytes(),
+                )
+                .unwrap();
+            }
+            db.flush().unwrap();
+        }
+        wait_for_compactions(&db);
+
+        // The tables the iterator reads were compacted away but are still there.
+        let live = db
+            .inner
+            .state
+            .lock()
+            .unwrap()
+            .versions
+            .current()
+            .live_files();
+        assert!(old_tables.iter().all(|n| !live.contains(n)));
+        let on_disk = files(&dir, FileType::Table);
+        assert!(old_tables.iter().all(|n| on_disk.contains(n)));
+        it.seek_to_first();
+        let entries = scan(&mut it);
+        assert_eq!(entries.len(), 300);
+        assert!(entries.iter().all(|(_, v)| v == "old"));
+
+        drop(it);
+        let on_disk = files(&dir, FileType::Table);
+        assert!(old_tables.iter().all(|n| !on_disk.contains(n)));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_db_snapshot() {
+        let dir = test_dir("db_snapshot");
+        let db = Db::open_with(&dir, compaction_options()).unwrap();
+        db.put(b"k", b"v1").unwrap();
+        db.put(b"gone", b"x").unwrap();
+        db.flush().unwrap();
+        let snapshot = db.snapshot();
+        assert_eq!(snapshot.sequence(), 2);
+        let at = |snapshot: &Snapshot| ReadOptions {
+            snapshot: Some(snapshot.clone()),
+            ..ReadOptions::default()
+        };
+
+        db.put(b"k", b"v2").unwrap();
+        db.delete(b"gone").unwrap();
+        db.put(b"new", b"n").unwrap();
+        assert_eq!(db.get(b"k").unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(
+            db.get_with(&at(&snapshot), b"k").unwrap(),
+            Some(b"v1".to_vec())
+        );
+        assert_eq!(
+            db.get_with(&at(&snapshot), b"gone").unwrap(),
+            Some(b"x".to_vec())
+        );
+        assert_eq!(db.get_with(&at(&snapshot), b"new").unwrap(), None);
+
+        // Compaction keeps what the snapshot sees.
+        db.flush().unwrap();
+        wait_for_compactions(&db);
+        assert_eq!(db.level_file_counts()[0], 0);
+        assert_eq!(table_entries(&db), 5);
+        assert_eq!(
+            db.get_with(&at(&snapshot), b"k").unwrap(),
+            Some(b"v1".to_vec())
+        );
+        let mut it = db.iter(at(&snapshot));
+        it.seek_to_first();
+        assert_eq!(
+            scan(&mut it),
+            [
+                ("gone".to_string(), "x".to_string()),
+                ("k".to_string(), "v1".to_string())
+            ]
+        );
+        drop(it);
+
+        // Once the last handle is gone, the next compaction drops them.
+        let clone = snapshot.clone();
+        drop(snapshot);
+        db.put(b"k", b"v3").unwrap();
+        db.flush().unwrap();
+        wait_for_compactions(&db);
+        assert_eq!(table_entries(&db), 6);
+        drop(clone);
+        db.put(b"k", b"v4").unwrap();
+        db.flush().unwrap();
+        wait_for_compactions(&db);
+        assert_eq!(table_entries(&db), 2);
+        assert_eq!(db.get(b"k").unwrap(), Some(b"v4".to_vec()));
+        assert_eq!(db.get(b"gone").unwrap(), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,171 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use crate::comparator::Comparator;
+use crate::iterator::{InternalIterator, MergingIterator};
+use crate::key::{self, ParsedInternalKey, ValueType, MAX_SEQUENCE_NUMBER};
+use crate::log::{err, StatusCode};
+use crate::options::ReadOptions;
+use crate::version::Version;
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// An iterator over the user keys of a `Db`, in comparator order, as of one
+/// sequence number.
+///
+/// It merges every memtable and table file and yields the newest version of
+/// each key that is visible at its sequence number, skipping deleted keys. The
+/// memtables and table files it reads stay alive until it is dropped, even if
+/// compaction replaces them in the meantime.
+pub struct DbIterator {
+    input: MergingIterator,
+    ucmp: Arc<dyn Comparator>,
+    sequence: u64,
+    options: ReadOptions,
+    valid: bool,
+    /// Set if the input holds a key that cannot be parsed.
+    corrupted: bool,
+    version: Option<Arc<Version>>,
+    /// Runs once the iterator no longer holds `version`.
+    release: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl DbIterator {
+    pub(crate) fn new(
+        input: MergingIterator,
+        ucmp: Arc<dyn Comparator>,
+        sequence: u64,
+        options: ReadOptions,
+        version: Arc<Version>,
+        release: Box<dyn FnOnce() + Send>,
+    ) -> DbIterator {
+        DbIterator {
+            input,
+            ucmp,
+            sequence: sequence.min(MAX_SEQUENCE_NUMBER),
+            options,
+            valid: false,
+            corrupted: false,
+            version: Some(version),
+            release: Some(release),
+        }
+    }
+
+    pub fn valid(&self) -> bool {
+        self.valid
+    }
+
+    /// Positions at the first key, or at the lower bound or prefix if set.
+    pub fn seek_to_first(&mut self) {
+        if self.options.lower_bound.is_some() || self.options.prefix.is_some() {
+            self.seek(&[]);
+            return;
+        }
+        self.input.seek_to_first();
+        self.find_next_user_entry(false, &mut Vec::new());
+    }
+
+    /// Positions at the first key at or after `target` (and within the bounds).
+    pub fn seek(&mut self, target: &[u8]) {
+        let mut start = target;
+        for bound in [&self.options.lower_bound, &self.options.prefix]
+            .into_iter()
+            .flatten()
+        {
+            if self.ucmp.compare(bound, start) == Ordering::Greater {
+                start = bound;
+            }
+        }
+        let lookup = ParsedInternalKey::new(start, self.sequence, ValueType::TypeValue).encode();
+        self.input.seek(&lookup);
+        self.find_next_user_entry(false, &mut Vec::new());
+    }
+
+    /// Moves to the next key. Must only be called while valid.
+    pub fn next(&mut self) {
+        assert!(self.valid, "iterator is not valid");
+        // Every other version of the current key is older, so skip them all.
+        let mut skip = self.key().to_vec();
+        self.input.next();
+        self.find_next_user_entry(true, &mut skip);
+    }
+
+    /// The current user key. Must only be called while valid.
+    pub fn key(&self) -> &[u8] {
+        assert!(self.valid, "iterator is not valid");
+        key::extract_user_key(self.input.key())
+    }
+
+    /// The current value. Must only be called while valid.
+    pub fn value(&self) -> &[u8] {
+        assert!(self.valid, "iterator is not valid");
+        self.input.value()
+    }
+
+    /// The first error hit while reading, which also ends the iteration.
+    pub fn status(&self) -> Result<()> {
+        if self.corrupted {
+            return err(StatusCode::Corruption, "bad internal key while iterating");
+        }
+        self.input.status()
+    }
+
+    /// Advances the input to the newest visible version of the next key that
+    /// is not deleted, skipping entries for keys at or before `skip` while
+    /// `skipping` is set.
+    fn find_next_user_entry(&mut self, mut skipping: bool, skip: &mut Vec<u8>) {
+        self.valid = false;
+        while self.input.valid() {
+            let Some(ikey) = ParsedInternalKey::decode(self.input.key()) else {
+                self.corrupted = true;
+                return;
+            };
+            if self.past_end(ikey.user_key) {
+                return;
+            }
+            if ikey.sequence <= self.sequence {
+                match ikey.value_type {
+                    ValueType::TypeDeletion => {
+                        // Hides every older version of the key.
+                        skip.clear();
+                        skip.extend_from_slice(ikey.user_key);
+                        skipping = true;
+                    }
+                    ValueType::TypeValue => {
+                        let hidden =
+                            skipping && self.ucmp.compare(ikey.user_key, skip) != Ordering::Greater;
+                        if !hidden {
+                            self.valid = true;
+                            return;
+                        }
+                    }
+                }
+            }
+            self.input.next();
+        }
+    }
+
+    /// Whether `user_key` and everything after it is out of range.
+    fn past_end(&self, user_key: &[u8]) -> bool {
+        if let Some(upper) = &self.options.upper_bound {
+            if self.ucmp.compare(user_key, upper) != Ordering::Less {
+                return true;
+            }
+        }
+        if let Some(prefix) = &self.options.prefix {
+            if !user_key.starts_with(prefix) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl Drop for DbIterator {
+    fn drop(&mut self) {
+        self.version.take();
+        if let Some(release) = self.release.take() {
+            release();
+        }
+    }
+}
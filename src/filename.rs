@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    Log,
+    Table,
+    /// A table being written; only renamed to its final name once durable.
+    Temp,
+    /// Records every change to the set of table files.
+    Manifest,
+    /// Names the manifest in use.
+    Current,
+}
+
+pub fn log_file_name(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:06}.log", number))
+}
+
+pub fn table_file_name(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:06}.ldb", number))
+}
+
+pub fn temp_file_name(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:06}.dbtmp", number))
+}
+
+pub fn manifest_file_name(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("MANIFEST-{:06}", number))
+}
+
+pub fn current_file_name(dir: &Path) -> PathBuf {
+    dir.join("CURRENT")
+}
+
+/// Points CURRENT at manifest `number`. The new contents are written to a temp
+/// file and renamed into place, so CURRENT is never seen half written.
+pub fn set_current_file(dir: &Path, number: u64) -> std::io::Result<()> {
+    let manifest = manifest_file_name(dir, number);
+    let contents = format!("{}\n", manifest.file_name().unwrap().to_str().unwrap());
+    let temp = temp_file_name(dir, number);
+    let mut file = File::create(&temp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temp, current_file_name(dir))?;
+    File::open(dir)?.sync_all()
+}
+
+/// Parses a file name produced by one of the `*_file_name` helpers back into
+/// its number and type. Unknown names yield `None`.
+pub fn parse_file_name(name: &str) -> Option<(u64, FileType)> {
+    if name == "CURRENT" {
+        return Some((0, FileType::Current));
+    }
+    if let Some(number) = name.strip_prefix("MANIFEST-") {
+        if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        return Some((number.parse().ok()?, FileType::Manifest));
+    }
+    let (stem, ext) = name.split_once('.')?;
+    if stem.is_empty() || !stem.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let number = stem.parse().ok()?;
+    match ext {
+        "log" => Some((number, FileType::Log)),
+        "ldb" => Some((number, FileType::Table)),
+        "dbtmp" => Some((number, FileType::Temp)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_name_roundtrip() {
+        let name = log_file_name(Path::new("db"), 7);
+        assert_eq!(name, Path::new("db/000007.log"));
+        let name = name.file_name().unwrap().to_str().unwrap();
+        assert_eq!(parse_file_name(name), Some((7, FileType::Log)));
+
+        assert_eq!(parse_file_name("000012.ldb"), Some((12, FileType::Table)));
+        assert_eq!(parse_file_name("000013.dbtmp"), Some((13, FileType::Temp)));
+        assert_eq!(
+            parse_file_name("MANIFEST-000003"),
+            Some((3, FileType::Manifest))
+        );
+        assert_eq!(parse_file_name("CURRENT"), Some((0, FileType::Current)));
+        assert_eq!(parse_file_name("MANIFEST-"), None);
+        assert_eq!(parse_file_name("LOCK"), None);
+        assert_eq!(parse_file_name("x1.log"), None);
+        assert_eq!(parse_file_name("000001.tmp"), None);
+    }
+}
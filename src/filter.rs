@@ -0,0 +1,216 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Builds a compact summary of a set of user keys that can rule keys out of
+/// the set without reading it.
+///
+/// Table files store one filter over all of their user keys, so the policy
+/// (identified by `name`) must not change how filters are encoded without
+/// also changing its name. Tables whose filter was built by another policy are
+/// read without a filter.
+pub trait FilterPolicy: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Builds a filter matching every key in `keys`.
+    fn create_filter(&self, keys: &[&[u8]]) -> Vec<u8>;
+
+    /// Returns false only if `key` was not among the keys `filter` was built
+    /// from. May return true for keys that were not.
+    fn key_may_match(&self, key: &[u8], filter: &[u8]) -> bool;
+}
+
+/// A Bloom filter using `bits_per_key` bits per key. About 10 bits per key
+/// gives a 1% false positive rate.
+#[derive(Clone, Copy, Debug)]
+pub struct BloomFilterPolicy {
+    bits_per_key: usize,
+    /// Number of probes per key.
+    k: usize,
+}
+
+impl BloomFilterPolicy {
+    pub fn new(bits_per_key: usize) -> BloomFilterPolicy {
+        // ln(2) * bits_per_key probes minimize the false positive rate.
+        let k = (bits_per_key as f64 * 0.69) as usize;
+        BloomFilterPolicy {
+            bits_per_key,
+            k: k.clamp(1, 30),
+        }
+    }
+}
+
+impl FilterPolicy for BloomFilterPolicy {
+    fn name(&self) -> &'static str {
+        "wdis.BuiltinBloomFilter"
+    }
+
+    /// The filter is a bit array followed by one byte holding the probe count.
+    fn create_filter(&self, keys: &[&[u8]]) -> Vec<u8> {
+        // Tiny filters have a high false positive rate whatever the key count.
+        let bytes = (keys.len() * self.bits_per_key).max(64).div_ceil(8);
+        let bits = bytes * 8;
+
+        let mut filter = vec![0; bytes + 1];
+        filter[bytes] = self.k as u8;
+        for key in keys {
+            // Double hashing: probe h, h + delta, h + 2 * delta, ...
+            let mut h = bloom_hash(key);
+            let delta = h.rotate_right(17);
+            for _ in 0..self.k {
+                let bit = h as usize % bits;
+                filter[bit / 8] |= 1 << (bit % 8);
+                h = h.wrapping_add(delta);
+            }
+        }
+        filter
+    }
+
+    fn key_may_match(&self, key: &[u8], filter: &[u8]) -> bool {
+        if filter.len() < 2 {
+            return false;
+        }
+        let bits = (filter.len() - 1) * 8;
+        let k = filter[filter.len() - 1];
+        if k > 30 {
+            // Reserved for other encodings; be safe and match everything.
+            return true;
+        }
+
+        let mut h = bloom_hash(key);
+        let delta = h.rotate_right(17);
+        for _ in 0..k {
+            let bit = h as usize % bits;
+            if filter[bit / 8] & (1 << (bit % 8)) == 0 {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+}
+
+/// A Murmur-like 32-bit hash.
+fn bloom_hash(data: &[u8]) -> u32 {
+    const SEED: u32 = 0xbc9f_1d34;
+    const M: u32 = 0xc6a4_a793;
+
+    let mut h = SEED ^ (data.len() as u32).wrapping_mul(M);
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        h = h
+            .wrapping_add(LittleEndian::read_u32(chunk))
+            .wrapping_mul(M);
+        h ^= h >> 16;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, &b) in rest.iter().enumerate() {
+            h = h.wrapping_add((b as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> 24;
+    }
+    h
+}
+
+/// How often table filters spared a data block read.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilterStats {
+    /// Lookups the filter ruled out, so no data block was read.
+    pub hits: u64,
+    /// Lookups the filter could not rule out, so the data block was read.
+    pub misses: u64,
+    /// Misses where the table turned out to hold no visible version of the key.
+    pub false_positives: u64,
+}
+
+/// Filter counters shared by every table of a database.
+#[derive(Debug, Default)]
+pub struct FilterCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl FilterCounters {
+    pub(crate) fn record_hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_miss(&self, found: bool) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        if !found {
+            self.false_positives.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> FilterStats {
+        FilterStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            false_positives: self.false_positives.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: u32) -> Vec<u8> {
+        i.to_le_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_bloom_empty() {
+        let policy = BloomFilterPolicy::new(10);
+        let filter = policy.create_filter(&[]);
+        assert!(!policy.key_may_match(b"hello", &filter));
+        assert!(!policy.key_may_match(b"", &filter));
+        assert!(!policy.key_may_match(b"hello", &[]));
+    }
+
+    #[test]
+    fn test_bloom_small() {
+        let policy = BloomFilterPolicy::new(10);
+        let filter = policy.create_filter(&[b"hello", b"world"]);
+        assert!(policy.key_may_match(b"hello", &filter));
+        assert!(policy.key_may_match(b"world", &filter));
+        assert!(!policy.key_may_match(b"x", &filter));
+        assert!(!policy.key_may_match(b"foo", &filter));
+    }
+
+    #[test]
+    fn test_bloom_false_positive_rate() {
+        let policy = BloomFilterPolicy::new(10);
+        for n in [1, 10, 100, 1000, 10000] {
+            let keys: Vec<Vec<u8>> = (0..n).map(key).collect();
+            let refs: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+            let filter = policy.create_filter(&refs);
+            assert!(filter.len() <= (n as usize * 10 / 8) + 40);
+
+            // No false negatives.
+            assert!(keys.iter().all(|k| policy.key_may_match(k, &filter)));
+
+            let false_positives = (0..10000)
+                .filter(|i| policy.key_may_match(&key(i + 1_000_000_000), &filter))
+                .count();
+            assert!(
+                false_positives < 200,
+                "{} keys: {} false positives",
+                n,
+                false_positives
+            );
+        }
+    }
+
+    #[test]
+    fn test_bloom_hash() {
+        // Every tail length takes a different path.
+        let hashes: Vec<u32> = (0..8).map(|n| bloom_hash(&b"abcdefgh"[..n])).collect();
+        for (i, a) in hashes.iter().enumerate() {
+            assert!(hashes[i + 1..].iter().all(|b| a != b));
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::cmp::Ordering;
+
+use crate::key::InternalKeyComparator;
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// A forward cursor over entries ordered by internal key.
+pub trait InternalIterator: Send {
+    fn valid(&self) -> bool;
+
+    fn seek_to_first(&mut self);
+
+    /// Positions at the first entry with a key at or after `target`.
+    fn seek(&mut self, target: &[u8]);
+
+    /// Moves to the next entry. Must only be called while valid.
+    fn next(&mut self);
+
+    fn key(&self) -> &[u8];
+
+    fn value(&self) -> &[u8];
+
+    /// The first error hit while iterating. An iterator that fails becomes
+    /// invalid, so callers check this once iteration stops.
+    fn status(&self) -> Result<()>;
+}
+
+/// Merges several iterators into one ordered stream. Entries with equal keys
+/// come out in the order of the children that hold them.
+pub struct MergingIterator {
+    children: Vec<Box<dyn InternalIterator>>,
+    icmp: InternalKeyComparator,
+    current: Option<usize>,
+}
+
+impl MergingIterator {
+    pub fn new(children: Vec<Box<dyn InternalIterator>>, icmp: InternalKeyComparator) -> Self {
+        MergingIterator {
+            children,
+            icmp,
+            current: None,
+        }
+    }
+
+    fn find_smallest(&mut self) {
+        let mut smallest: Option<usize> = None;
+        for (i, child) in self.children.iter().enumerate() {
+            if !child.valid() {
+                continue;
+            }
+            smallest = match smallest {
+                Some(s)
+                    if self.icmp.compare(child.key(), self.children[s].key()) != Ordering::Less =>
+                {
+                    Some(s)
+                }
+                _ => Some(i),
+            };
+        }
+        self.current = smallest;
+    }
+}
+
+impl InternalIterator for MergingIterator {
+    fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn seek_to_first(&mut self) {
+        for child in &mut self.children {
+            child.seek_to_first();
+        }
+        self.find_smallest();
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        for child in &mut self.children {
+            child.seek(target);
+        }
+        self.find_smallest();
+    }
+
+    fn next(&mut self) {
+        if let Some(i) = self.current {
+            self.children[i].next();
+            self.find_smallest();
+        }
+    }
+
+    fn key(&self) -> &[u8] {
+        self.children[self.current.unwrap()].key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.children[self.current.unwrap()].value()
+    }
+
+    fn status(&self) -> Result<()> {
+        self.children.iter().try_for_each(|child| child.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::{ParsedInternalKey, ValueType};
+
+    /// Iterates over a sorted list of entries.
+    struct VecIter {
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        pos: usize,
+        icmp: InternalKeyComparator,
+    }
+
+    impl InternalIterator for VecIter {
+        fn valid(&self) -> bool {
+            self.pos < self.entries.len()
+        }
+        fn seek_to_first(&mut self) {
+            self.pos = 0;
+        }
+        fn seek(&mut self, target: &[u8]) {
+            self.pos = self
+                .entries
+                .partition_point(|(k, _)| self.icmp.compare(k, target) == Ordering::Less);
+        }
+        fn next(&mut self) {
+            self.pos += 1;
+        }
+        fn key(&self) -> &[u8] {
+            &self.entries[self.pos].0
+        }
+        fn value(&self) -> &[u8] {
+            &self.entries[self.pos].1
+        }
+        fn status(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn child(entries: &[(&str, u64)]) -> Box<dyn InternalIterator> {
+        Box::new(VecIter {
+            entries: entries
+                .iter()
+                .map(|&(k, seq)| {
+                    let key = ParsedInternalKey::new(k.as_bytes(), seq, ValueType::TypeValue);
+                    (key.encode(), format!("{}{}", k, seq).into_bytes())
+                })
+                .collect(),
+            pos: 0,
+            icmp: InternalKeyComparator::default(),
+        })
+    }
+
+    fn collect(it: &mut MergingIterator) -> Vec<String> {
+        let mut out = Vec::new();
+        while it.valid() {
+            out.push(String::from_utf8(it.value().to_vec()).unwrap());
+            it.next();
+        }
+        out
+    }
+
+    #[test]
+    fn test_merging_iterator() {
+        let mut it = MergingIterator::new(
+            vec![
+                child(&[("a", 1), ("c", 5), ("e", 1)]),
+                child(&[]),
+                child(&[("b", 2), ("c", 7), ("f", 3)]),
+            ],
+            InternalKeyComparator::default(),
+        );
+        assert!(!it.valid());
+        it.seek_to_first();
+        assert_eq!(collect(&mut it), ["a1", "b2", "c7", "c5", "e1", "f3"]);
+
+        it.seek(&ParsedInternalKey::new(b"c", 6, ValueType::TypeValue).encode());
+        assert_eq!(collect(&mut it), ["c5", "e1", "f3"]);
+        it.seek(b"zzzzzzzzz");
+        assert!(!it.valid());
+        assert!(it.status().is_ok());
+    }
+}
@@ -1,11 +1,142 @@
 
-use byteorder::{LittleEndian, WriteBytesExt};
+use std::cmp::Ordering;
+use std::sync::Arc;
 
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use integer_encoding::VarInt;
+
+use crate::comparator::{BytewiseComparator, Comparator};
+
+/// Sequence numbers share a `u64` with the value type, leaving them 56 bits.
+pub const MAX_SEQUENCE_NUMBER: u64 = (1 << 56) - 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ValueType {
     TypeDeletion = 0,
     TypeValue = 1,
 }
-trait VarintExt {
+
+impl TryFrom<u8> for ValueType {
+    type Error = u8;
+
+    fn try_from(t: u8) -> Result<Self, Self::Error> {
+        match t {
+            0 => Ok(ValueType::TypeDeletion),
+            1 => Ok(ValueType::TypeValue),
+            _ => Err(t),
+        }
+    }
+}
+
+/// Packs a sequence number and value type into the 8-byte tag that follows
+/// the user key in an internal key.
+pub fn pack_tag(seq: u64, t: ValueType) -> u64 {
+    debug_assert!(seq <= MAX_SEQUENCE_NUMBER);
+    (seq << 8) | t as u64
+}
+
+/// An internal key split into its parts. Encoded, it is the user key followed
+/// by the little-endian `seq << 8 | type` tag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParsedInternalKey<'a> {
+    pub user_key: &'a [u8],
+    pub sequence: u64,
+    pub value_type: ValueType,
+}
+
+impl<'a> ParsedInternalKey<'a> {
+    pub fn new(user_key: &'a [u8], sequence: u64, value_type: ValueType) -> Self {
+        ParsedInternalKey {
+            user_key,
+            sequence,
+            value_type,
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        self.user_key.len() + 8
+    }
+
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        dst.extend_from_slice(self.user_key);
+        dst.write_u64::<LittleEndian>(pack_tag(self.sequence, self.value_type))
+            .unwrap();
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    /// Parses an encoded internal key. Returns `None` if it is shorter than a
+    /// tag or carries an unknown value type.
+    pub fn decode(internal_key: &'a [u8]) -> Option<Self> {
+        if internal_key.len() < 8 {
+            return None;
+        }
+        let (user_key, tag) = internal_key.split_at(internal_key.len() - 8);
+        let tag = LittleEndian::read_u64(tag);
+        Some(ParsedInternalKey {
+            user_key,
+            sequence: tag >> 8,
+            value_type: ValueType::try_from(tag as u8).ok()?,
+        })
+    }
+}
+
+/// The user key part of an encoded internal key.
+pub fn extract_user_key(internal_key: &[u8]) -> &[u8] {
+    &internal_key[..internal_key.len().saturating_sub(8)]
+}
+
+/// Orders internal keys by user key ascending, using the wrapped user
+/// comparator, then by tag descending, so the newest version of a key comes
+/// first and a seek to `(user_key, seq)` lands on the newest version whose
+/// sequence number is at most `seq`.
+#[derive(Clone)]
+pub struct InternalKeyComparator {
+    user: Arc<dyn Comparator>,
+}
+
+impl Default for InternalKeyComparator {
+    fn default() -> Self {
+        Self::new(Arc::new(BytewiseComparator))
+    }
+}
+
+impl InternalKeyComparator {
+    pub fn new(user: Arc<dyn Comparator>) -> Self {
+        InternalKeyComparator { user }
+    }
+
+    pub fn user_comparator(&self) -> &Arc<dyn Comparator> {
+        &self.user
+    }
+
+    /// Compares two encoded internal keys.
+    pub fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        if a.len() < 8 || b.len() < 8 {
+            // Malformed keys never come out of `ParsedInternalKey`; keep the order total anyway.
+            return a.cmp(b);
+        }
+        let (ua, ta) = a.split_at(a.len() - 8);
+        let (ub, tb) = b.split_at(b.len() - 8);
+        self.user
+            .compare(ua, ub)
+            .then_with(|| LittleEndian::read_u64(tb).cmp(&LittleEndian::read_u64(ta)))
+    }
+
+    /// Compares two keys built by `build_mem_key`.
+    pub fn compare_mem_keys(&self, a: &[u8], b: &[u8]) -> Ordering {
+        match (strip_mem_key(a), strip_mem_key(b)) {
+            (Some(a), Some(b)) => self.compare(a, b),
+            _ => a.cmp(b),
+        }
+    }
+}
+
+pub(crate) trait VarintExt {
     fn extend_varint(&mut self, num: usize);
 }
 
@@ -32,8 +163,7 @@ pub fn build_mem_key(seq: u64, t: ValueType, key: &[u8]) -> Vec<u8> {
 
     // 写入 Key 部分
     buf.extend_varint(keysize);
-    buf.extend_from_slice(key);
-    buf.write_u64::<LittleEndian>((t as u64) | (seq << 8)).unwrap();
+    ParsedInternalKey::new(key, seq, t).encode_to(&mut buf);
     buf
 }
 
@@ -50,6 +180,19 @@ pub fn build_mem_value(value: &[u8]) -> Vec<u8> {
     buf
 }
 
+/// Strips the varint length prefix from a key built by `build_mem_key`,
+/// leaving the encoded internal key.
+pub fn strip_mem_key(memkey: &[u8]) -> Option<&[u8]> {
+    let (keysize, n) = usize::decode_var(memkey)?;
+    memkey.get(n..n.checked_add(keysize)?)
+}
+
+/// Strips the varint length prefix written by `build_mem_value`.
+pub fn decode_mem_value(buf: &[u8]) -> Option<&[u8]> {
+    let (len, n) = usize::decode_var(buf)?;
+    buf.get(n..n + len)
+}
+
 fn varint_len(mut num: usize) -> usize {
     let mut len = 0;
     loop {
@@ -66,9 +209,16 @@ fn varint_len(mut num: usize) -> usize {
 #[cfg(test)]
 mod test {
     use crate::key::build_mem_value;
+    use crate::key::decode_mem_value;
 
     use super::build_mem_key;
+    use super::strip_mem_key;
+    use super::InternalKeyComparator;
+    use super::ParsedInternalKey;
     use super::ValueType;
+    use crate::comparator::ReverseBytewiseComparator;
+    use std::cmp::Ordering;
+    use std::sync::Arc;
 
     
     #[test]
@@ -84,7 +234,70 @@ mod test {
         );
 
         println!("{:?}",build_mem_value("123".as_bytes()));
+        assert_eq!(
+            decode_mem_value(&build_mem_value("123".as_bytes())),
+            Some("123".as_bytes())
+        );
+    }
+
+    #[test]
+    fn test_parsed_internal_key_roundtrip() {
+        for (key, seq, t) in [
+            (&b"abc"[..], 231, ValueType::TypeValue),
+            (&b""[..], 0, ValueType::TypeDeletion),
+            (&b"\xff\x00"[..], super::MAX_SEQUENCE_NUMBER, ValueType::TypeValue),
+        ] {
+            let parsed = ParsedInternalKey::new(key, seq, t);
+            let encoded = parsed.encode();
+            assert_eq!(encoded.len(), parsed.encoded_len());
+            assert_eq!(ParsedInternalKey::decode(&encoded), Some(parsed));
+        }
+
+        assert_eq!(ParsedInternalKey::decode(b"short"), None);
+        let mut bad_type = ParsedInternalKey::new(b"k", 1, ValueType::TypeValue).encode();
+        bad_type[1] = 7;
+        assert_eq!(ParsedInternalKey::decode(&bad_type), None);
     }
-}
 
+    #[test]
+    fn test_strip_mem_key() {
+        let memkey = build_mem_key(231, ValueType::TypeDeletion, b"abc");
+        assert_eq!(
+            strip_mem_key(&memkey),
+            Some(&ParsedInternalKey::new(b"abc", 231, ValueType::TypeDeletion).encode()[..])
+        );
+        assert_eq!(strip_mem_key(&memkey[..5]), None);
+    }
+
+    #[test]
+    fn test_internal_key_comparator() {
+        let icmp = InternalKeyComparator::default();
+        let key = |seq, t, k: &str| build_mem_key(seq, t, k.as_bytes());
+
+        // User keys compare bytewise, regardless of their length prefix.
+        assert_eq!(
+            icmp.compare_mem_keys(&key(1, ValueType::TypeValue, "aa"), &key(1, ValueType::TypeValue, "b")),
+            Ordering::Less
+        );
+        // Newer versions sort first.
+        assert_eq!(
+            icmp.compare_mem_keys(&key(9, ValueType::TypeValue, "a"), &key(300, ValueType::TypeValue, "a")),
+            Ordering::Greater
+        );
+        // At the same sequence, a value sorts before a deletion.
+        assert_eq!(
+            icmp.compare_mem_keys(&key(5, ValueType::TypeValue, "a"), &key(5, ValueType::TypeDeletion, "a")),
+            Ordering::Less
+        );
+        assert_eq!(
+            icmp.compare_mem_keys(&key(5, ValueType::TypeValue, "a"), &key(5, ValueType::TypeValue, "a")),
+            Ordering::Equal
+        );
 
+        // A custom user comparator only changes the user key order.
+        let rcmp = InternalKeyComparator::new(Arc::new(ReverseBytewiseComparator));
+        let ikey = |seq, k: &str| ParsedInternalKey::new(k.as_bytes(), seq, ValueType::TypeValue).encode();
+        assert_eq!(rcmp.compare(&ikey(1, "a"), &ikey(1, "b")), Ordering::Greater);
+        assert_eq!(rcmp.compare(&ikey(9, "a"), &ikey(300, "a")), Ordering::Greater);
+    }
+}
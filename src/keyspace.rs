@@ -0,0 +1,1388 @@
+//! The typed keyspace the server keeps in a `Db`.
+//!
+//! Every stored value starts with a header byte: the low bits hold the kind of
+//! value and `FLAG_EXPIRES` says whether an 8-byte big-endian expiry time, in
+//! milliseconds since the Unix epoch, follows. The payload comes after. Since
+//! the expiry is part of the value, it goes through the log and survives
+//! recovery like the value itself.
+//!
+//! Expired keys are invisible to reads as soon as their time passes. They are
+//! deleted by the next write that touches them, or by `expire_cycle`, which
+//! the server runs in the background.
+//!
+//! A hash or a list keeps only a header under its own key, which is stored as
+//! `KEY_SPACE` followed by the key. Each hash field and each list element is a
+//! db key of its own, so one is read or written without touching the others:
+//! `FIELD_SPACE`, the length of the key as a u32, big-endian, the key, then
+//! the field. The length keeps the fields of one hash together and apart from
+//! those of a hash whose key extends this one. List elements are named by
+//! their position, which the header tracks at both ends.
+//!
+//! An in-memory index tracks every live key with an estimate of the memory it
+//! takes and how recently and often it is used, for `write_with_room` to
+//! evict keys once `Options::maxmemory` is reached.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::db::{Db, DbIterator};
+use crate::log::{err, StatusCode};
+use crate::options::{EvictionPolicy, Options, ReadOptions};
+use crate::write_batch::WriteBatch;
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+const KIND_STRING: u8 = 0;
+const KIND_HASH: u8 = 1;
+const KIND_LIST: u8 = 2;
+const KIND_MASK: u8 = 0x7f;
+const FLAG_EXPIRES: u8 = 0x80;
+
+/// First byte of the db key of every key in the keyspace.
+const KEY_SPACE: u8 = b'k';
+/// First byte of the db key of every hash field and list element.
+const FIELD_SPACE: u8 = b'f';
+
+fn db_key(key: &[u8]) -> Vec<u8> {
+    let mut db_key = Vec::with_capacity(1 + key.len());
+    db_key.push(KEY_SPACE);
+    db_key.extend_from_slice(key);
+    db_key
+}
+
+/// The start of the db keys of the fields of the hash at `key`. Keys reach
+/// the server with a u32 length, so the length always fits.
+fn field_prefix(key: &[u8]) -> Vec<u8> {
+    let len = u32::try_from(key.len()).expect("key longer than u32::MAX");
+    let mut prefix = Vec::with_capacity(5 + key.len());
+    prefix.push(FIELD_SPACE);
+    prefix.extend_from_slice(&len.to_be_bytes());
+    prefix.extend_from_slice(key);
+    prefix
+}
+
+fn field_key(key: &[u8], field: &[u8]) -> Vec<u8> {
+    let mut db_key = field_prefix(key);
+    db_key.extend_from_slice(field);
+    db_key
+}
+
+/// What a key holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    String,
+    Hash,
+    List,
+}
+
+/// An end of a list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// A value as stored in the keyspace. The value of a hash or a list is its
+/// header, see `Writer::hset` and `Writer::push`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub kind: Kind,
+    pub value: Vec<u8>,
+    /// When the key expires, in milliseconds since the Unix epoch.
+    pub expires_at: Option<u64>,
+}
+
+impl Entry {
+    /// A string entry.
+    pub fn new(value: impl Into<Vec<u8>>, expires_at: Option<u64>) -> Entry {
+        Entry {
+            kind: Kind::String,
+            value: value.into(),
+            expires_at,
+        }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        1 + if self.expires_at.is_some() { 8 } else { 0 } + self.value.len()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let kind = match self.kind {
+            Kind::String => KIND_STRING,
+            Kind::Hash => KIND_HASH,
+            Kind::List => KIND_LIST,
+        };
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        match self.expires_at {
+            Some(at) => {
+                buf.push(kind | FLAG_EXPIRES);
+                buf.extend_from_slice(&at.to_be_bytes());
+            }
+            None => buf.push(kind),
+        }
+        buf.extend_from_slice(&self.value);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Entry> {
+        let Some((&header, rest)) = buf.split_first() else {
+            return err(StatusCode::Corruption, "empty keyspace value");
+        };
+        let kind = match header & KIND_MASK {
+            KIND_STRING => Kind::String,
+            KIND_HASH => Kind::Hash,
+            KIND_LIST => Kind::List,
+            _ => return err(StatusCode::Corruption, "unknown keyspace value kind"),
+        };
+        let (value, expires_at) = if header & FLAG_EXPIRES == 0 {
+            (rest, None)
+        } else {
+            match rest.split_first_chunk::<8>() {
+                Some((at, value)) => (value, Some(u64::from_be_bytes(*at))),
+                None => return err(StatusCode::Corruption, "truncated expiry time"),
+            }
+        };
+        Ok(Entry {
+            kind,
+            value: value.to_vec(),
+            expires_at,
+        })
+    }
+
+    /// How many fields a hash, or elements a list, has. A string counts as
+    /// one item.
+    pub fn item_count(&self) -> Result<u64> {
+        match self.kind {
+            Kind::String => Ok(1),
+            Kind::Hash => Ok(HashHeader::decode(&self.value)?.len),
+            Kind::List => Ok(ListHeader::decode(&self.value)?.len),
+        }
+    }
+
+    /// Estimated bytes taken by what the entry refers to besides itself: the
+    /// fields of a hash or the elements of a list.
+    fn external_size(&self) -> usize {
+        let size = match self.kind {
+            Kind::String => return 0,
+            Kind::Hash => HashHeader::decode(&self.value).map(|h| h.size),
+            Kind::List => ListHeader::decode(&self.value).map(|h| h.size),
+        };
+        size.map_or(0, |size| size as usize)
+    }
+}
+
+/// The value of a hash: how many fields it has and the total length of their
+/// db keys and values, each a u64, big-endian.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct HashHeader {
+    len: u64,
+    size: u64,
+}
+
+impl HashHeader {
+    fn encode(&self) -> Vec<u8> {
+        [self.len.to_be_bytes(), self.size.to_be_bytes()].concat()
+    }
+
+    fn decode(buf: &[u8]) -> Result<HashHeader> {
+        if buf.len() != 16 {
+            return err(StatusCode::Corruption, "bad hash header");
+        }
+        Ok(HashHeader {
+            len: u64::from_be_bytes(buf[..8].try_into().unwrap()),
+            size: u64::from_be_bytes(buf[8..].try_into().unwrap()),
+        })
+    }
+}
+
+/// The value of a list: the position of its first element, how many elements
+/// it has and the total length of their db keys and values, each a u64,
+/// big-endian. Elements are named by their position, big-endian, so they sort
+/// in list order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ListHeader {
+    head: u64,
+    len: u64,
+    size: u64,
+}
+
+impl Default for ListHeader {
+    /// An empty list starts in the middle of the positions, leaving room to
+    /// grow at both ends.
+    fn default() -> ListHeader {
+        ListHeader {
+            head: 1 << 63,
+            len: 0,
+            size: 0,
+        }
+    }
+}
+
+impl ListHeader {
+    fn encode(&self) -> Vec<u8> {
+        [
+            self.head.to_be_bytes(),
+            self.len.to_be_bytes(),
+            self.size.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    fn decode(buf: &[u8]) -> Result<ListHeader> {
+        if buf.len() != 24 {
+            return err(StatusCode::Corruption, "bad list header");
+        }
+        Ok(ListHeader {
+            head: u64::from_be_bytes(buf[..8].try_into().unwrap()),
+            len: u64::from_be_bytes(buf[8..16].try_into().unwrap()),
+            size: u64::from_be_bytes(buf[16..].try_into().unwrap()),
+        })
+    }
+
+    /// The position of the element at `end`, if any.
+    fn end_position(&self, end: End) -> Option<u64> {
+        match (self.len, end) {
+            (0, _) => None,
+            (_, End::Left) => Some(self.head),
+            (len, End::Right) => Some(self.head + len - 1),
+        }
+    }
+
+    /// The positions of the first and last element from index `start` to
+    /// `stop` inclusive, where negative indexes count from the end, -1 being
+    /// the last element. `None` if the range holds no element.
+    fn positions(&self, start: i64, stop: i64) -> Option<(u64, u64)> {
+        let len = self.len as i64;
+        let resolve = |index: i64| if index < 0 { len + index } else { index };
+        let start = resolve(start).max(0);
+        let stop = resolve(stop).min(len - 1);
+        if start > stop {
+            return None;
+        }
+        Some((self.head + start as u64, self.head + stop as u64))
+    }
+}
+
+/// Estimated bytes a key takes beyond its key and encoded value: its entries
+/// in the in-memory index.
+pub const ENTRY_OVERHEAD: usize = 64;
+
+/// Keys sampled to pick one to evict under the LRU and LFU policies.
+const EVICTION_SAMPLES: usize = 5;
+
+/// LFU counters start here so that new keys are not evicted right away.
+const LFU_INIT: u8 = 5;
+/// The higher, the more accesses it takes to raise an LFU counter.
+const LFU_LOG_FACTOR: u64 = 10;
+/// An LFU counter drops by one for each period of this length without access.
+const LFU_DECAY_MS: u64 = 60_000;
+
+/// What the index knows about a live key.
+struct KeyInfo {
+    /// Estimated memory the key takes, see `ENTRY_OVERHEAD`.
+    size: usize,
+    expires_at: Option<u64>,
+    last_access: u64,
+    /// Logarithmic access counter, as in Redis: each access raises it with a
+    /// probability that shrinks as it grows.
+    frequency: u8,
+}
+
+impl KeyInfo {
+    /// The LFU counter after decay for the time since the last access.
+    fn decayed_frequency(&self, now: u64) -> u8 {
+        let periods = now.saturating_sub(self.last_access) / LFU_DECAY_MS;
+        self.frequency
+            .saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+}
+
+/// A set of keys that can also be sampled at random.
+#[derive(Default)]
+struct KeySet {
+    keys: Vec<Vec<u8>>,
+    positions: HashMap<Vec<u8>, usize>,
+}
+
+impl KeySet {
+    fn insert(&mut self, key: &[u8]) {
+        if !self.positions.contains_key(key) {
+            self.positions.insert(key.to_vec(), self.keys.len());
+            self.keys.push(key.to_vec());
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(pos) = self.positions.remove(key) {
+            self.keys.swap_remove(pos);
+            if let Some(moved) = self.keys.get(pos) {
+                self.positions.insert(moved.clone(), pos);
+            }
+        }
+    }
+
+    /// Up to `n` keys picked at random, or all keys if there are no more
+    /// than `n`.
+    fn sample(&self, n: usize, rng: &mut Rng) -> Vec<&[u8]> {
+        if self.keys.len() <= n {
+            return self.keys.iter().map(Vec::as_slice).collect();
+        }
+        (0..n)
+            .map(|_| self.keys[rng.below(self.keys.len())].as_slice())
+            .collect()
+    }
+}
+
+/// A xorshift generator; eviction only needs cheap, roughly uniform picks.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// The live keys, with what eviction and expiry need to know about them.
+struct Index {
+    keys: HashMap<Vec<u8>, KeyInfo>,
+    all: KeySet,
+    /// Keys with an expiry time.
+    volatile: KeySet,
+    by_deadline: BTreeSet<(u64, Vec<u8>)>,
+    /// Sum of the sizes of all keys.
+    used: usize,
+    rng: Rng,
+}
+
+impl Index {
+    fn new() -> Index {
+        Index {
+            keys: HashMap::new(),
+            all: KeySet::default(),
+            volatile: KeySet::default(),
+            by_deadline: BTreeSet::new(),
+            used: 0,
+            rng: Rng(now_ms() | 1),
+        }
+    }
+
+    /// Records a write of `entry`, which counts as an access.
+    fn insert(&mut self, key: &[u8], entry: &Entry, now: u64) {
+        let (last_access, frequency) = match self.remove(key) {
+            Some(old) => (old.last_access, old.frequency),
+            None => (now, LFU_INIT),
+        };
+        let size = ENTRY_OVERHEAD + key.len() + entry.encoded_len() + entry.external_size();
+        self.used += size;
+        self.all.insert(key);
+        if let Some(at) = entry.expires_at {
+            self.volatile.insert(key);
+            self.by_deadline.insert((at, key.to_vec()));
+        }
+        self.keys.insert(
+            key.to_vec(),
+            KeyInfo {
+                size,
+                expires_at: entry.expires_at,
+                last_access,
+                frequency,
+            },
+        );
+        self.touch(key, now);
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<KeyInfo> {
+        let info = self.keys.remove(key)?;
+        self.used -= info.size;
+        self.all.remove(key);
+        if let Some(at) = info.expires_at {
+            self.volatile.remove(key);
+            self.by_deadline.remove(&(at, key.to_vec()));
+        }
+        Some(info)
+    }
+
+    fn touch(&mut self, key: &[u8], now: u64) {
+        let Some(info) = self.keys.get_mut(key) else {
+            return;
+        };
+        let mut frequency = info.decayed_frequency(now);
+        if frequency < u8::MAX {
+            let odds = (frequency.saturating_sub(LFU_INIT) as u64) * LFU_LOG_FACTOR + 1;
+            if self.rng.next().is_multiple_of(odds) {
+                frequency += 1;
+            }
+        }
+        info.frequency = frequency;
+        info.last_access = now;
+    }
+
+    /// The key `policy` evicts next, if any.
+    fn eviction_candidate(&mut self, policy: EvictionPolicy, now: u64) -> Option<Vec<u8>> {
+        let keys = &self.keys;
+        let oldest = |sample: Vec<&[u8]>| {
+            sample
+                .into_iter()
+                .min_by_key(|key| keys[*key].last_access)
+                .map(<[u8]>::to_vec)
+        };
+        match policy {
+            EvictionPolicy::NoEviction => None,
+            EvictionPolicy::AllKeysLru => oldest(self.all.sample(EVICTION_SAMPLES, &mut self.rng)),
+            EvictionPolicy::VolatileLru => {
+                oldest(self.volatile.sample(EVICTION_SAMPLES, &mut self.rng))
+            }
+            EvictionPolicy::AllKeysLfu => self
+                .all
+                .sample(EVICTION_SAMPLES, &mut self.rng)
+                .into_iter()
+                .min_by_key(|key| {
+                    let info = &keys[*key];
+                    (info.decayed_frequency(now), info.last_access)
+                })
+                .map(<[u8]>::to_vec),
+            EvictionPolicy::VolatileTtl => self.by_deadline.first().map(|(_, key)| key.clone()),
+            EvictionPolicy::AllKeysRandom => self
+                .all
+                .sample(1, &mut self.rng)
+                .first()
+                .map(|key| key.to_vec()),
+        }
+    }
+}
+
+pub struct Keyspace {
+    db: Db,
+    /// Held by every write, which makes a `Writer` an atomic read-modify-write.
+    write_lock: Mutex<()>,
+    /// Only held briefly; taken after `write_lock` when both are needed.
+    index: Mutex<Index>,
+    maxmemory: usize,
+    eviction_policy: EvictionPolicy,
+}
+
+impl Keyspace {
+    /// Opens the keyspace in `dir`. The index of live keys is rebuilt from a
+    /// full scan.
+    pub fn open_with(dir: impl AsRef<Path>, options: Options) -> Result<Keyspace> {
+        let maxmemory = options.maxmemory;
+        let eviction_policy = options.eviction_policy;
+        let db = Db::open_with(dir, options)?;
+        let mut index = Index::new();
+        let now = now_ms();
+        let mut iter = db.iter(ReadOptions {
+            prefix: Some(vec![KEY_SPACE]),
+            ..ReadOptions::default()
+        });
+        iter.seek_to_first();
+        while iter.valid() {
+            index.insert(&iter.key()[1..], &Entry::decode(iter.value())?, now);
+            iter.next();
+        }
+        iter.status()?;
+        drop(iter);
+        Ok(Keyspace {
+            db,
+            write_lock: Mutex::new(()),
+            index: Mutex::new(index),
+            maxmemory,
+            eviction_policy,
+        })
+    }
+
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        self.get_with(&ReadOptions::default(), key)
+    }
+
+    /// Reads a key, treating it as absent once expired.
+    pub fn get_with(&self, options: &ReadOptions, key: &[u8]) -> Result<Option<Entry>> {
+        let now = now_ms();
+        let Some(raw) = self.db.get_with(options, &db_key(key))? else {
+            return Ok(None);
+        };
+        let entry = Entry::decode(&raw)?;
+        if entry.is_expired(now) {
+            return Ok(None);
+        }
+        self.index.lock().unwrap().touch(key, now);
+        Ok(Some(entry))
+    }
+
+    /// Starts a write. Other writes wait until the returned `Writer` is
+    /// dropped, so whatever it reads stays current until then.
+    pub fn write(&self) -> Writer<'_> {
+        Writer {
+            keyspace: self,
+            _lock: self.write_lock.lock().unwrap(),
+        }
+    }
+
+    /// Reads a field of the hash at `key`, which the caller has checked is a
+    /// live hash, reading with the same `options`.
+    pub fn get_field_with(
+        &self,
+        options: &ReadOptions,
+        key: &[u8],
+        field: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        self.db.get_with(options, &field_key(key, field))
+    }
+
+    /// Reads the elements of the list at `key` from index `start` to `stop`
+    /// inclusive. Negative indexes count from the end, -1 being the last
+    /// element. The caller checks with the same `options` that `key` is a
+    /// live list.
+    pub fn list_range(
+        &self,
+        options: ReadOptions,
+        key: &[u8],
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Vec<u8>>> {
+        let Some(entry) = self.get_with(&options, key)? else {
+            return Ok(Vec::new());
+        };
+        let header = ListHeader::decode(&entry.value)?;
+        let Some((first, last)) = header.positions(start, stop) else {
+            return Ok(Vec::new());
+        };
+        let count = (last - first + 1) as usize;
+        let mut values = Vec::with_capacity(count.min(1024));
+        let mut iter = self.fields(options, key);
+        iter.seek(&first.to_be_bytes());
+        while iter.valid() && values.len() < count {
+            values.push(iter.value().to_vec());
+            iter.next();
+        }
+        iter.status()?;
+        Ok(values)
+    }
+
+    /// Iterates over the live keys, skipping expired ones. Only the snapshot
+    /// of `options` applies.
+    pub fn iter(&self, options: ReadOptions) -> KeyspaceIterator {
+        let options = ReadOptions {
+            snapshot: options.snapshot,
+            prefix: Some(vec![KEY_SPACE]),
+            ..ReadOptions::default()
+        };
+        KeyspaceIterator {
+            inner: self.db.iter(options),
+            now: now_ms(),
+        }
+    }
+
+    /// Iterates over the fields of the hash at `key`, or the elements of the
+    /// list, named by position, which the caller has checked is live. Only the
+    /// snapshot of `options` applies.
+    pub fn fields(&self, options: ReadOptions, key: &[u8]) -> FieldIterator {
+        let prefix = field_prefix(key);
+        let options = ReadOptions {
+            snapshot: options.snapshot,
+            prefix: Some(prefix.clone()),
+            ..ReadOptions::default()
+        };
+        FieldIterator {
+            inner: self.db.iter(options),
+            prefix,
+        }
+    }
+
+    /// Deletes up to `limit` keys whose expiry time is at or before `now`,
+    /// earliest first, and returns how many it deleted.
+    pub fn expire_cycle(&self, now: u64, limit: usize) -> Result<usize> {
+        let mut writer = self.write();
+        let expired: Vec<Vec<u8>> = self
+            .index
+            .lock()
+            .unwrap()
+            .by_deadline
+            .iter()
+            .take_while(|(at, _)| *at <= now)
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect();
+        for key in &expired {
+            writer.delete(key)?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Number of keys that have an expiry time.
+    pub fn volatile_len(&self) -> usize {
+        self.index.lock().unwrap().volatile.keys.len()
+    }
+
+    /// Estimated memory in use: the estimated size of every live key, see
+    /// `ENTRY_OVERHEAD`, plus `Db::memtable_usage`.
+    pub fn used_memory(&self) -> usize {
+        self.index.lock().unwrap().used + self.db.memtable_usage()
+    }
+
+    /// Starts a write that may grow the keyspace. Keys are first evicted as
+    /// `Options::eviction_policy` allows until they fit in what the memtables
+    /// leave of `Options::maxmemory`. Returns `None` if they do not fit with
+    /// nothing left to evict, or if the memtables alone reach the limit, in
+    /// which case the write should be refused.
+    ///
+    /// The check happens under the returned `Writer`, so concurrent writes
+    /// cannot all pass it before any of them lands.
+    pub fn write_with_room(&self) -> Result<Option<Writer<'_>>> {
+        let mut writer = self.write();
+        Ok(writer.make_room()?.then_some(writer))
+    }
+}
+
+/// Exclusive access to the keyspace for one read-modify-write.
+pub struct Writer<'a> {
+    keyspace: &'a Keyspace,
+    _lock: MutexGuard<'a, ()>,
+}
+
+impl Writer<'_> {
+    /// Reads a key, deleting it if it has expired.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Entry>> {
+        let Some(entry) = self.stored(key)? else {
+            return Ok(None);
+        };
+        let now = now_ms();
+        if entry.is_expired(now) {
+            self.delete(key)?;
+            return Ok(None);
+        }
+        self.keyspace.index.lock().unwrap().touch(key, now);
+        Ok(Some(entry))
+    }
+
+    /// Writes the whole entry of a key. Putting an entry of another kind over
+    /// a hash or a list drops its fields or elements; putting one of the same
+    /// kind keeps them, so that its expiry time can be changed.
+    pub fn put(&mut self, key: &[u8], entry: &Entry) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        if self.stored(key)?.is_some_and(|old| old.kind != entry.kind) {
+            self.delete_fields(key, &mut batch)?;
+        }
+        batch.put(&db_key(key), &entry.encode());
+        self.commit(key, batch, Some(entry))
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        if self.stored(key)?.is_some() {
+            self.delete_fields(key, &mut batch)?;
+        }
+        batch.delete(&db_key(key));
+        self.commit(key, batch, None)
+    }
+
+    /// Reads a field of the hash at `key`, which the caller has checked with
+    /// `get` is a live hash.
+    pub fn get_field(&mut self, key: &[u8], field: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.keyspace.db.get(&field_key(key, field))
+    }
+
+    /// Sets fields of the hash at `key`, creating the hash if needed, and
+    /// returns how many fields are new. A key of another kind is replaced. When
+    /// `pairs` names a field twice, the last value wins.
+    ///
+    /// The header of a hash counts its fields, so that the hash can go once the
+    /// last one is deleted, and sums their sizes for the memory estimate.
+    pub fn hset(&mut self, key: &[u8], pairs: &[(&[u8], &[u8])]) -> Result<usize> {
+        let (mut header, expires_at) = match self.get(key)? {
+            Some(entry) if entry.kind == Kind::Hash => {
+                (HashHeader::decode(&entry.value)?, entry.expires_at)
+            }
+            Some(_) => {
+                self.delete(key)?;
+                (HashHeader::default(), None)
+            }
+            None => (HashHeader::default(), None),
+        };
+        let pairs: BTreeMap<&[u8], &[u8]> = pairs.iter().copied().collect();
+        let fields: Vec<&[u8]> = pairs.keys().copied().collect();
+        let olds = self.read_fields(key, &header, &fields)?;
+        let mut batch = WriteBatch::new();
+        let mut added = 0;
+        for ((field, value), old) in pairs.into_iter().zip(olds) {
+            let db_key = field_key(key, field);
+            match old {
+                Some(old) => {
+                    header.size = header
+                        .size
+                        .saturating_sub((db_key.len() + old.len()) as u64)
+                }
+                None => {
+                    header.len += 1;
+                    added += 1;
+                }
+            }
+            header.size += (db_key.len() + value.len()) as u64;
+            batch.put(&db_key, value);
+        }
+        let entry = Entry {
+            kind: Kind::Hash,
+            value: header.encode(),
+            expires_at,
+        };
+        batch.put(&db_key(key), &entry.encode());
+        self.commit(key, batch, Some(&entry))?;
+        Ok(added)
+    }
+
+    /// Deletes fields of the hash at `key`, and the hash once it has no fields
+    /// left. Returns how many of the fields existed.
+    pub fn hdel(&mut self, key: &[u8], fields: &[&[u8]]) -> Result<usize> {
+        let Some(entry) = self.get(key)?.filter(|e| e.kind == Kind::Hash) else {
+            return Ok(0);
+        };
+        let mut header = HashHeader::decode(&entry.value)?;
+        let fields: Vec<&[u8]> = fields
+            .iter()
+            .copied()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let olds = self.read_fields(key, &header, &fields)?;
+        let mut batch = WriteBatch::new();
+        let mut removed = 0;
+        for (field, old) in fields.into_iter().zip(olds) {
+            let db_key = field_key(key, field);
+            if let Some(old) = old {
+                header.len -= 1;
+                header.size = header
+                    .size
+                    .saturating_sub((db_key.len() + old.len()) as u64);
+                batch.delete(&db_key);
+                removed += 1;
+            }
+        }
+        if removed == 0 {
+            return Ok(0);
+        }
+        if header.len == 0 {
+            batch.delete(&db_key(key));
+            self.commit(key, batch, None)?;
+        } else {
+            let entry = Entry {
+                value: header.encode(),
+                ..entry
+            };
+            batch.put(&db_key(key), &entry.encode());
+            self.commit(key, batch, Some(&entry))?;
+        }
+        Ok(removed)
+    }
+
+    /// Reads `fields`, sorted and distinct, of the hash at `key` with
+    /// `header`. When they are a good share of the hash, one pass over its
+    /// fields reads them all. Otherwise each is looked up alone, which is
+    /// cheaper since table filters let a lookup skip most files where a pass
+    /// visits every one.
+    fn read_fields(
+        &self,
+        key: &[u8],
+        header: &HashHeader,
+        fields: &[&[u8]],
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        if header.len == 0 {
+            return Ok(vec![None; fields.len()]);
+        }
+        if header.len > 4 * fields.len() as u64 {
+            return fields
+                .iter()
+                .map(|field| self.keyspace.db.get(&field_key(key, field)))
+                .collect();
+        }
+        let mut iter = self.keyspace.fields(ReadOptions::default(), key);
+        iter.seek(fields[0]);
+        let mut values = Vec::with_capacity(fields.len());
+        for field in fields {
+            while iter.valid() && iter.field() < *field {
+                iter.next();
+            }
+            let found = iter.valid() && iter.field() == *field;
+            values.push(found.then(|| iter.value().to_vec()));
+        }
+        iter.status()?;
+        Ok(values)
+    }
+
+    /// Pushes `values` one at a time onto `end` of the list at `key`, creating
+    /// the list if needed, and returns its new length. A key of another kind
+    /// is replaced.
+    pub fn push(&mut self, key: &[u8], values: &[&[u8]], end: End) -> Result<u64> {
+        let (mut header, expires_at) = match self.get(key)? {
+            Some(entry) if entry.kind == Kind::List => {
+                (ListHeader::decode(&entry.value)?, entry.expires_at)
+            }
+            Some(_) => {
+                self.delete(key)?;
+                (ListHeader::default(), None)
+            }
+            None => (ListHeader::default(), None),
+        };
+        let mut batch = WriteBatch::new();
+        for value in values {
+            let position = match end {
+                End::Left => {
+                    header.head -= 1;
+                    header.head
+                }
+                End::Right => header.head + header.len,
+            };
+            header.len += 1;
+            let db_key = field_key(key, &position.to_be_bytes());
+            header.size += (db_key.len() + value.len()) as u64;
+            batch.put(&db_key, value);
+        }
+        self.put_list(key, header, expires_at, batch)?;
+        Ok(header.len)
+    }
+
+    /// Pops the element at `end` of the list at `key`, deleting the list with
+    /// its last element.
+    pub fn pop(&mut self, key: &[u8], end: End) -> Result<Option<Vec<u8>>> {
+        let Some(entry) = self.get(key)?.filter(|e| e.kind == Kind::List) else {
+            return Ok(None);
+        };
+        let mut header = ListHeader::decode(&entry.value)?;
+        let Some(position) = header.end_position(end) else {
+            return Ok(None);
+        };
+        let db_key = field_key(key, &position.to_be_bytes());
+        let Some(value) = self.keyspace.db.get(&db_key)? else {
+            return err(StatusCode::Corruption, "missing list element");
+        };
+        let mut batch = WriteBatch::new();
+        batch.delete(&db_key);
+        if end == End::Left {
+            header.head += 1;
+        }
+        header.len -= 1;
+        header.size = header
+            .size
+            .saturating_sub((db_key.len() + value.len()) as u64);
+        self.put_list(key, header, entry.expires_at, batch)?;
+        Ok(Some(value))
+    }
+
+    /// Keeps only the elements of the list at `key` from index `start` to
+    /// `stop` inclusive, indexes counting as in `Keyspace::list_range`, and
+    /// deletes the list if none are left.
+    pub fn trim(&mut self, key: &[u8], start: i64, stop: i64) -> Result<()> {
+        let Some(entry) = self.get(key)?.filter(|e| e.kind == Kind::List) else {
+            return Ok(());
+        };
+        let header = ListHeader::decode(&entry.value)?;
+        let Some((first, last)) = header.positions(start, stop) else {
+            return self.delete(key);
+        };
+        let mut kept = ListHeader {
+            head: first,
+            len: last - first + 1,
+            size: header.size,
+        };
+        let (first, last) = (first.to_be_bytes(), last.to_be_bytes());
+        let mut batch = WriteBatch::new();
+        let mut iter = self.keyspace.fields(ReadOptions::default(), key);
+        iter.seek_to_first();
+        while iter.valid() {
+            if iter.field() >= &first[..] && iter.field() <= &last[..] {
+                // Skip over the kept elements.
+                iter.seek(&(u64::from_be_bytes(last) + 1).to_be_bytes());
+                continue;
+            }
+            kept.size = kept
+                .size
+                .saturating_sub((iter.inner.key().len() + iter.value().len()) as u64);
+            batch.delete(iter.inner.key());
+            iter.next();
+        }
+        iter.status()?;
+        self.put_list(key, kept, entry.expires_at, batch)
+    }
+
+    /// Completes `batch` with the new header of the list at `key`, or its
+    /// delete if the list is empty, and writes it.
+    fn put_list(
+        &mut self,
+        key: &[u8],
+        header: ListHeader,
+        expires_at: Option<u64>,
+        mut batch: WriteBatch,
+    ) -> Result<()> {
+        if header.len == 0 {
+            batch.delete(&db_key(key));
+            return self.commit(key, batch, None);
+        }
+        let entry = Entry {
+            kind: Kind::List,
+            value: header.encode(),
+            expires_at,
+        };
+        batch.put(&db_key(key), &entry.encode());
+        self.commit(key, batch, Some(&entry))
+    }
+
+    /// Evicts keys until memory use is within the limit. Returns false if
+    /// evicting cannot bring it back.
+    fn make_room(&mut self) -> Result<bool> {
+        let keyspace = self.keyspace;
+        if keyspace.maxmemory == 0 {
+            return Ok(true);
+        }
+        // Evicting adds tombstones to the memtables, which only a flush
+        // reclaims, so the keys get what the memtables leave of the limit
+        // before any eviction.
+        let budget = match keyspace.maxmemory.checked_sub(keyspace.db.memtable_usage()) {
+            Some(budget) if budget > 0 => budget,
+            _ => return Ok(false),
+        };
+        while keyspace.index.lock().unwrap().used > budget {
+            let candidate = keyspace
+                .index
+                .lock()
+                .unwrap()
+                .eviction_candidate(keyspace.eviction_policy, now_ms());
+            match candidate {
+                Some(key) => self.delete(&key)?,
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Reads a key whether or not it has expired.
+    fn stored(&self, key: &[u8]) -> Result<Option<Entry>> {
+        match self.keyspace.db.get(&db_key(key))? {
+            Some(raw) => Entry::decode(&raw).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Adds deletes of the fields or elements of the hash or list at `key` to
+    /// `batch`.
+    fn delete_fields(&self, key: &[u8], batch: &mut WriteBatch) -> Result<()> {
+        let mut iter = self.keyspace.fields(ReadOptions::default(), key);
+        iter.seek_to_first();
+        while iter.valid() {
+            batch.delete(iter.inner.key());
+            iter.next();
+        }
+        iter.status()
+    }
+
+    /// Writes `batch` and records the new entry of `key` in the index, `None`
+    /// if the batch deletes it.
+    fn commit(&mut self, key: &[u8], batch: WriteBatch, entry: Option<&Entry>) -> Result<()> {
+        self.keyspace.db.write(batch)?;
+        let mut index = self.keyspace.index.lock().unwrap();
+        match entry {
+            Some(entry) => index.insert(key, entry, now_ms()),
+            None => {
+                index.remove(key);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A forward iterator over live keys. See `DbIterator`.
+pub struct KeyspaceIterator {
+    inner: DbIterator,
+    now: u64,
+}
+
+impl KeyspaceIterator {
+    pub fn valid(&self) -> bool {
+        self.inner.valid()
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.inner.seek_to_first();
+        self.skip_expired();
+    }
+
+    pub fn seek(&mut self, target: &[u8]) {
+        self.inner.seek(&db_key(target));
+        self.skip_expired();
+    }
+
+    pub fn next(&mut self) {
+        self.inner.next();
+        self.skip_expired();
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.inner.key()[1..]
+    }
+
+    pub fn entry(&self) -> Result<Entry> {
+        Entry::decode(self.inner.value())
+    }
+
+    pub fn status(&self) -> Result<()> {
+        self.inner.status()
+    }
+
+    /// Values that fail to decode are not skipped, so `entry` reports them.
+    fn skip_expired(&mut self) {
+        while self.inner.valid()
+            && Entry::decode(self.inner.value()).is_ok_and(|e| e.is_expired(self.now))
+        {
+            self.inner.next();
+        }
+    }
+}
+
+/// A forward iterator over the fields of a hash, in field order, or the
+/// elements of a list, in list order.
+pub struct FieldIterator {
+    inner: DbIterator,
+    prefix: Vec<u8>,
+}
+
+impl FieldIterator {
+    pub fn valid(&self) -> bool {
+        self.inner.valid()
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.inner.seek_to_first();
+    }
+
+    /// Positions at the first field at or past `field`.
+    pub fn seek(&mut self, field: &[u8]) {
+        self.inner.seek(&[&self.prefix[..], field].concat());
+    }
+
+    pub fn next(&mut self) {
+        self.inner.next();
+    }
+
+    pub fn field(&self) -> &[u8] {
+        &self.inner.key()[self.prefix.len()..]
+    }
+
+    pub fn value(&self) -> &[u8] {
+        self.inner.value()
+    }
+
+    pub fn status(&self) -> Result<()> {
+        self.inner.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::test_dir;
+
+    #[test]
+    fn test_entry_encoding() {
+        for entry in [
+            Entry::new("v", None),
+            Entry::new("", Some(0)),
+            Entry::new(&b"\x80\x00"[..], Some(1_700_000_000_000)),
+        ] {
+            assert_eq!(Entry::decode(&entry.encode()).unwrap(), entry);
+        }
+        assert!(Entry::decode(b"").is_err());
+        assert!(Entry::decode(&[FLAG_EXPIRES, 1, 2]).is_err());
+        assert!(Entry::decode(&[5]).is_err());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let dir = test_dir("keyspace_expiry");
+        let keyspace = Keyspace::open_with(&dir, Options::default()).unwrap();
+        let now = now_ms();
+        let mut writer = keyspace.write();
+        writer
+            .put(b"gone", &Entry::new("v", Some(now - 1)))
+            .unwrap();
+        writer
+            .put(b"later", &Entry::new("v", Some(now + 60_000)))
+            .unwrap();
+        writer.put(b"kept", &Entry::new("v", None)).unwrap();
+        drop(writer);
+
+        // Expired keys are invisible before anything deletes them.
+        assert_eq!(keyspace.get(b"gone").unwrap(), None);
+        assert!(keyspace.db().get(&db_key(b"gone")).unwrap().is_some());
+        assert!(keyspace.get(b"later").unwrap().is_some());
+        let mut iter = keyspace.iter(ReadOptions::default());
+        iter.seek_to_first();
+        let mut keys = Vec::new();
+        while iter.valid() {
+            keys.push(iter.key().to_vec());
+            iter.next();
+        }
+        assert_eq!(keys, [b"kept".to_vec(), b"later".to_vec()]);
+        drop(iter);
+
+        assert_eq!(keyspace.volatile_len(), 2);
+        assert_eq!(keyspace.expire_cycle(now, 10).unwrap(), 1);
+        assert_eq!(keyspace.db().get(&db_key(b"gone")).unwrap(), None);
+        assert_eq!(keyspace.volatile_len(), 1);
+
+        // Expiry times come back with the log.
+        drop(keyspace);
+        let keyspace = Keyspace::open_with(&dir, Options::default()).unwrap();
+        assert_eq!(keyspace.volatile_len(), 1);
+        assert_eq!(
+            keyspace.get(b"later").unwrap().unwrap().expires_at,
+            Some(now + 60_000)
+        );
+        assert_eq!(keyspace.expire_cycle(now + 60_000, 10).unwrap(), 1);
+        assert_eq!(keyspace.get(b"later").unwrap(), None);
+    }
+
+    fn fields(keyspace: &Keyspace, key: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut iter = keyspace.fields(ReadOptions::default(), key);
+        iter.seek_to_first();
+        let mut fields = Vec::new();
+        while iter.valid() {
+            fields.push((iter.field().to_vec(), iter.value().to_vec()));
+            iter.next();
+        }
+        iter.status().unwrap();
+        fields
+    }
+
+    #[test]
+    fn test_hash() {
+        let dir = test_dir("keyspace_hash");
+        let keyspace = Keyspace::open_with(&dir, Options::default()).unwrap();
+        let mut writer = keyspace.write();
+        let pairs: [(&[u8], &[u8]); 3] = [(b"b", b"1"), (b"a", b"2"), (b"b", b"3")];
+        assert_eq!(writer.hset(b"h", &pairs).unwrap(), 2);
+        assert_eq!(writer.hset(b"h", &[(b"a", b"4"), (b"c", b"5")]).unwrap(), 1);
+        // A hash whose key extends `h` keeps its fields apart.
+        assert_eq!(writer.hset(b"hh", &[(b"a", b"6")]).unwrap(), 1);
+        assert_eq!(writer.get(b"h").unwrap().unwrap().kind, Kind::Hash);
+        assert_eq!(writer.get_field(b"h", b"a").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(writer.hdel(b"h", &[b"c", b"c", b"x"]).unwrap(), 1);
+        drop(writer);
+        assert_eq!(
+            fields(&keyspace, b"h"),
+            [
+                (b"a".to_vec(), b"4".to_vec()),
+                (b"b".to_vec(), b"3".to_vec())
+            ]
+        );
+        let mut iter = keyspace.fields(ReadOptions::default(), b"h");
+        iter.seek(b"b");
+        assert_eq!(iter.field(), b"b");
+
+        // Keys and memory estimates come back with the log.
+        let used = keyspace.index.lock().unwrap().used;
+        drop(iter);
+        drop(keyspace);
+        let keyspace = Keyspace::open_with(&dir, Options::default()).unwrap();
+        assert_eq!(keyspace.index.lock().unwrap().used, used);
+        let mut iter = keyspace.iter(ReadOptions::default());
+        iter.seek_to_first();
+        assert_eq!(iter.key(), b"h");
+        iter.next();
+        assert_eq!(iter.key(), b"hh");
+        iter.next();
+        assert!(!iter.valid());
+        drop(iter);
+
+        // Deleting the last field deletes the hash, and a string replaces
+        // a hash with all its fields.
+        let mut writer = keyspace.write();
+        assert_eq!(writer.hdel(b"hh", &[b"a"]).unwrap(), 1);
+        assert_eq!(writer.get(b"hh").unwrap(), None);
+        writer.put(b"h", &Entry::new("v", None)).unwrap();
+        drop(writer);
+        assert!(fields(&keyspace, b"h").is_empty());
+        assert_eq!(
+            keyspace.index.lock().unwrap().used,
+            ENTRY_OVERHEAD + 1 + Entry::new("v", None).encoded_len()
+        );
+        assert_eq!(field_key(b"h", b"a"), b"f\0\0\0\x01ha");
+
+        // Few fields of a big hash are looked up one by one rather than read
+        // in a pass over the hash; both find the same fields.
+        let mut writer = keyspace.write();
+        let names: Vec<Vec<u8>> = (0..20).map(|i| format!("{:02}", i).into_bytes()).collect();
+        let pairs: Vec<(&[u8], &[u8])> = names.iter().map(|n| (&n[..], &b"v"[..])).collect();
+        assert_eq!(writer.hset(b"big", &pairs).unwrap(), 20);
+        assert_eq!(
+            writer.hset(b"big", &[(b"05", b"w"), (b"x", b"w")]).unwrap(),
+            1
+        );
+        assert_eq!(writer.hdel(b"big", &[b"05", b"y"]).unwrap(), 1);
+        assert_eq!(
+            writer
+                .hdel(b"big", &pairs[..10].iter().map(|p| p.0).collect::<Vec<_>>())
+                .unwrap(),
+            9
+        );
+        drop(writer);
+        assert_eq!(fields(&keyspace, b"big").len(), 11);
+    }
+
+    #[test]
+    fn test_list() {
+        let dir = test_dir("keyspace_list");
+        let keyspace = Keyspace::open_with(&dir, Options::default()).unwrap();
+        let range = |start, stop| {
+            keyspace
+                .list_range(ReadOptions::default(), b"l", start, stop)
+                .unwrap()
+        };
+        let mut writer = keyspace.write();
+        assert_eq!(writer.push(b"l", &[b"c", b"d"], End::Right).unwrap(), 2);
+        assert_eq!(writer.push(b"l", &[b"b", b"a"], End::Left).unwrap(), 4);
+        drop(writer);
+        assert_eq!(range(0, -1), [b"a", b"b", b"c", b"d"]);
+        assert_eq!(range(1, 2), [b"b", b"c"]);
+        assert_eq!(range(-2, 100), [b"c", b"d"]);
+        assert!(range(3, 1).is_empty());
+        assert!(range(5, 8).is_empty());
+
+        let mut writer = keyspace.write();
+        assert_eq!(writer.pop(b"l", End::Left).unwrap(), Some(b"a".to_vec()));
+        assert_eq!(writer.pop(b"l", End::Right).unwrap(), Some(b"d".to_vec()));
+        writer.push(b"l", &[b"e", b"f"], End::Right).unwrap();
+        writer.trim(b"l", 1, -2).unwrap();
+        let entry = writer.get(b"l").unwrap().unwrap();
+        assert_eq!(entry.item_count().unwrap(), 2);
+        drop(writer);
+        assert_eq!(range(0, -1), [b"c", b"e"]);
+        // Trimmed elements are gone, not just hidden.
+        assert_eq!(fields(&keyspace, b"l").len(), 2);
+        assert_eq!(
+            keyspace.index.lock().unwrap().used,
+            ENTRY_OVERHEAD + 1 + entry.encoded_len() + entry.external_size()
+        );
+
+        let mut writer = keyspace.write();
+        writer.trim(b"l", 5, 10).unwrap();
+        assert_eq!(writer.get(b"l").unwrap(), None);
+        assert_eq!(writer.pop(b"l", End::Left).unwrap(), None);
+        writer.push(b"l", &[b"x"], End::Left).unwrap();
+        assert_eq!(writer.pop(b"l", End::Right).unwrap(), Some(b"x".to_vec()));
+        assert_eq!(writer.get(b"l").unwrap(), None);
+        drop(writer);
+        assert!(fields(&keyspace, b"l").is_empty());
+        assert_eq!(keyspace.index.lock().unwrap().used, 0);
+    }
+
+    #[test]
+    fn test_eviction_candidates() {
+        let mut index = Index::new();
+        index.insert(b"old", &Entry::new("v", Some(5_000)), 1_000);
+        index.insert(b"new", &Entry::new("v", Some(4_000)), 2_000);
+        index.insert(b"plain", &Entry::new("v", None), 1_500);
+        for _ in 0..1000 {
+            index.touch(b"new", 2_000);
+        }
+
+        let mut candidate = |policy| index.eviction_candidate(policy, 2_000);
+        assert_eq!(candidate(EvictionPolicy::NoEviction), None);
+        assert_eq!(candidate(EvictionPolicy::AllKeysLru), Some(b"old".to_vec()));
+        assert_eq!(
+            candidate(EvictionPolicy::VolatileLru),
+            Some(b"old".to_vec())
+        );
+        assert_eq!(
+            candidate(EvictionPolicy::VolatileTtl),
+            Some(b"new".to_vec())
+        );
+        // `plain` has been used less often than `new` and less recently than
+        // `old`, which share its initial counter.
+        assert_eq!(candidate(EvictionPolicy::AllKeysLfu), Some(b"old".to_vec()));
+        assert!(candidate(EvictionPolicy::AllKeysRandom).is_some());
+
+        index.remove(b"old");
+        index.remove(b"new");
+        assert_eq!(
+            index.eviction_candidate(EvictionPolicy::VolatileLru, 2_000),
+            None
+        );
+        assert_eq!(
+            index.eviction_candidate(EvictionPolicy::AllKeysLfu, 2_000),
+            Some(b"plain".to_vec())
+        );
+        assert_eq!(
+            index.used,
+            ENTRY_OVERHEAD + b"plain".len() + Entry::new("v", None).encoded_len()
+        );
+    }
+
+    #[test]
+    fn test_make_room() {
+        let dir = test_dir("keyspace_make_room");
+        let options = Options {
+            maxmemory: 1,
+            ..Options::default()
+        };
+        let keyspace = Keyspace::open_with(&dir, options.clone()).unwrap();
+        keyspace.write().put(b"k", &Entry::new("v", None)).unwrap();
+        assert!(keyspace.used_memory() > 1);
+        // Nothing may be evicted.
+        assert!(keyspace.write_with_room().unwrap().is_none());
+        assert!(keyspace.get(b"k").unwrap().is_some());
+        drop(keyspace);
+
+        let options = Options {
+            eviction_policy: EvictionPolicy::AllKeysLru,
+            ..options
+        };
+        let mut keyspace = Keyspace::open_with(&dir, options).unwrap();
+        let keys = |keyspace: &Keyspace| keyspace.index.lock().unwrap().keys.len();
+        let size = ENTRY_OVERHEAD + 2 + Entry::new("v", None).encoded_len();
+        assert_eq!(
+            keyspace.used_memory(),
+            size - 1 + keyspace.db.memtable_usage()
+        );
+        // With the memtables alone at the limit, evicting cannot help: the
+        // write is refused and no key goes for it.
+        keyspace.maxmemory = keyspace.db.memtable_usage();
+        assert!(keyspace.write_with_room().unwrap().is_none());
+        assert_eq!(keys(&keyspace), 1);
+
+        // Room for four keys besides the memtables. The tombstones evictions
+        // add to the memtables do not count against the keys, so each write
+        // evicts just the one key it has to.
+        for i in 0..50 {
+            keyspace.maxmemory = keyspace.db.memtable_usage() + 4 * size;
+            let mut writer = keyspace.write_with_room().unwrap().unwrap();
+            writer
+                .put(format!("{:02}", i).as_bytes(), &Entry::new("v", None))
+                .unwrap();
+            drop(writer);
+            assert_eq!(keys(&keyspace), (i + 2).min(5));
+        }
+        assert_eq!(keyspace.get(b"49").unwrap().unwrap().value, b"v");
+        assert_eq!(keyspace.get(b"k").unwrap(), None);
+    }
+}
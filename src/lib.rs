@@ -1,6 +1,22 @@
 pub mod buffer;
 pub mod pipeline;
+pub mod resp;
+pub mod reply;
 pub mod cmd_type;
+pub mod comparator;
+pub mod filter;
+pub mod glob;
+pub mod iterator;
 pub mod key;
 pub mod memtable;
 pub mod log;
+pub mod filename;
+pub mod table;
+mod table_cache;
+mod version;
+pub mod write_batch;
+mod write_queue;
+pub mod options;
+mod db_iter;
+pub mod db;
+pub mod keyspace;
@@ -12,11 +12,8 @@ pub enum StatusCode {
     Corruption,
 }
 
-fn err<T>(code: StatusCode, msg: &str) -> Result<T> {
-    Err(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        format!("{:?}: {}", code, msg),
-    ))
+pub(crate) fn err<T>(code: StatusCode, msg: &str) -> Result<T> {
+    Err(std::io::Error::other(format!("{:?}: {}", code, msg)))
 }
 
 const BLOCK_SIZE: usize = 32 * 1024;
@@ -32,7 +29,7 @@ pub enum RecordType {
 
 const CRC: crc::Crc<u32, crc::Table<1>> = crc::Crc::<u32, crc::Table<1>>::new(&crc::CRC_32_ISCSI);
 
-pub(crate) fn crc32(data: impl AsRef<[u8]>) -> u32 {
+pub fn crc32(data: impl AsRef<[u8]>) -> u32 {
     let mut digest = CRC.digest();
     digest.update(data.as_ref());
     digest.finalize()
@@ -129,6 +126,11 @@ impl<W: Write> LogWriter<W> {
         self.dst.flush()?;
         Ok(())
     }
+
+    /// The underlying writer, e.g. to sync the file it writes to.
+    pub fn get_ref(&self) -> &W {
+        &self.dst
+    }
 }
 
 pub struct LogReader<R: Read> {
@@ -156,18 +158,22 @@ impl<R: Read> LogReader<R> {
         let mut length: u16;
         let mut typ: u8;
         let mut dst_offset: usize = 0;
+        // Whether a First fragment started the record being put together.
+        let mut in_record = false;
 
         dst.clear();
 
         loop {
             if self.blocksize - self.blk_off < HEADER_SIZE {
                 // skip to next block
-                self.src
-                    .read_exact(&mut self.head_scratch[0..self.blocksize - self.blk_off])?;
+                let trailer = self.blocksize - self.blk_off;
+                if read_full(&mut self.src, &mut self.head_scratch[0..trailer])? < trailer {
+                    return Ok(0);
+                }
                 self.blk_off = 0;
             }
 
-            let mut bytes_read = self.src.read(&mut self.head_scratch)?;
+            let mut bytes_read = read_full(&mut self.src, &mut self.head_scratch)?;
 
             // EOF
             if bytes_read == 0 {
@@ -176,31 +182,59 @@ impl<R: Read> LogReader<R> {
 
             self.blk_off += bytes_read;
 
+            // A header cut short by a crash ends the log.
+            if bytes_read < HEADER_SIZE {
+                return Ok(0);
+            }
+
             checksum = u32::decode_fixed(&self.head_scratch[0..4]).unwrap();
             length = u16::decode_fixed(&self.head_scratch[4..6]).unwrap();
             typ = self.head_scratch[6];
 
+            if length as usize > self.blocksize - self.blk_off {
+                // A fragment never crosses a block, so the length is corrupt and
+                // nothing more in this block can be trusted.
+                let rest = (self.blocksize - self.blk_off) as u64;
+                std::io::copy(&mut (&mut self.src).take(rest), &mut std::io::sink())?;
+                self.blk_off = 0;
+                dst.clear();
+                return err(StatusCode::Corruption, "fragment overruns its block");
+            }
+
             dst.resize(dst_offset + length as usize, 0);
-            bytes_read = self
-                .src
-                .read(&mut dst[dst_offset..dst_offset + length as usize])?;
+            bytes_read = read_full(
+                &mut self.src,
+                &mut dst[dst_offset..dst_offset + length as usize],
+            )?;
             self.blk_off += bytes_read;
 
+            // So does a fragment whose payload was only partially written.
+            if bytes_read < length as usize {
+                dst.clear();
+                return Ok(0);
+            }
+
             if self.checksums
                 && !self.check_integrity(typ, &dst[dst_offset..dst_offset + bytes_read], checksum)
             {
                 return err(StatusCode::Corruption, "Invalid Checksum");
             }
 
+            if typ == RecordType::Full as u8 || typ == RecordType::First as u8 {
+                // A record still in progress lost its end; drop it.
+                dst.drain(..dst_offset);
+                dst_offset = 0;
+                in_record = typ == RecordType::First as u8;
+            } else if !in_record {
+                // A middle or last fragment whose first was lost or corrupted;
+                // skip until the next record starts.
+                dst.truncate(dst_offset);
+                continue;
+            }
+
             dst_offset += length as usize;
 
-            if typ == RecordType::Full as u8 {
-                return Ok(dst_offset);
-            } else if typ == RecordType::First as u8 {
-                continue;
-            } else if typ == RecordType::Middle as u8 {
-                continue;
-            } else if typ == RecordType::Last as u8 {
+            if typ == RecordType::Full as u8 || typ == RecordType::Last as u8 {
                 return Ok(dst_offset);
             }
         }
@@ -214,6 +248,20 @@ impl<R: Read> LogReader<R> {
     }
 }
 
+/// Reads until `buf` is full or the source is exhausted, returning the number of bytes read.
+fn read_full<R: Read>(src: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut off = 0;
+    while off < buf.len() {
+        match src.read(&mut buf[off..]) {
+            Ok(0) => break,
+            Ok(n) => off += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(off)
+}
+
 const MASK_DELTA: u32 = 0xa282ead8;
 
 pub fn mask_crc(c: u32) -> u32 {
@@ -317,17 +365,14 @@ mod tests {
         let mut dst = Vec::with_capacity(128);
 
         // First record is corrupted.
-
-        lr.read(&mut dst).unwrap();
+        assert!(lr.read(&mut dst).is_err());
 
         let mut i = 1;
         loop {
-            let r = lr.read(&mut dst);
-
-            if r.is_err() {
-                panic!("{}", r.unwrap_err());
-            } else if r.unwrap() == 0 {
-                break;
+            match lr.read(&mut dst) {
+                Err(e) => panic!("{}", e),
+                Ok(0) => break,
+                Ok(_) => {}
             }
 
             assert_eq!(dst, data[i]);
@@ -335,4 +380,92 @@ mod tests {
         }
         assert_eq!(i, data.len());
     }
+
+    #[test]
+    fn test_reader_corrupt_first_fragment() {
+        let data = [
+            "0101010101010101010101".as_bytes().to_vec(), // spans three blocks of 17
+            "abcdefghi".as_bytes().to_vec(),
+        ];
+        let mut lw = LogWriter::new(Vec::new());
+        lw.block_size = super::HEADER_SIZE + 10;
+        for e in data.iter() {
+            assert!(lw.add_record(e).is_ok());
+        }
+        // Corrupt the payload of the First fragment of the first record.
+        lw.dst[super::HEADER_SIZE] ^= 1;
+
+        let mut lr = LogReader::new(lw.dst.as_slice(), true);
+        lr.blocksize = super::HEADER_SIZE + 10;
+        let mut dst = Vec::new();
+        assert!(lr.read(&mut dst).is_err());
+        // The Middle and Last fragments that follow are not a record.
+        assert_eq!(lr.read(&mut dst).unwrap(), data[1].len());
+        assert_eq!(dst, data[1]);
+        assert_eq!(lr.read(&mut dst).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reader_corrupt_length() {
+        let data = [
+            "abcdefghi".as_bytes().to_vec(),
+            "123456789012".as_bytes().to_vec(),
+            "0101010101010101010101".as_bytes().to_vec(),
+        ];
+        for length in [10, 11, u16::MAX] {
+            let mut lw = LogWriter::new(Vec::new());
+            lw.block_size = super::HEADER_SIZE + 10;
+            for e in data.iter() {
+                assert!(lw.add_record(e).is_ok());
+            }
+            // The first record fits a block of 17; make it claim more.
+            lw.dst[4..6].copy_from_slice(&length.encode_fixed_vec());
+
+            let mut lr = LogReader::new(lw.dst.as_slice(), true);
+            lr.blocksize = super::HEADER_SIZE + 10;
+            let mut dst = Vec::new();
+            if length == 10 {
+                // Still within the block, so only the checksum catches it.
+                assert!(lr.read(&mut dst).is_err());
+            } else {
+                let e = lr.read(&mut dst).unwrap_err();
+                assert!(e.to_string().contains("overruns"), "{}", e);
+            }
+            // Reading resumes at the next block.
+            for record in &data[1..] {
+                assert_eq!(lr.read(&mut dst).unwrap(), record.len());
+                assert_eq!(&dst, record);
+            }
+            assert_eq!(lr.read(&mut dst).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn test_reader_truncated() {
+        let data = [
+            "abcdefghi".as_bytes().to_vec(),
+            "123456789012".as_bytes().to_vec(),
+            "0101010101010101010101".as_bytes().to_vec(),
+        ];
+        let mut lw = LogWriter::new(Vec::new());
+        lw.block_size = super::HEADER_SIZE + 10;
+        for e in data.iter() {
+            assert!(lw.add_record(e).is_ok());
+        }
+        let full = lw.dst.clone();
+
+        // Cut the log at every possible offset: the reader must return a prefix of the
+        // records, never an error or a partial record.
+        for cut in 0..full.len() {
+            let mut lr = LogReader::new(&full[..cut], true);
+            lr.blocksize = super::HEADER_SIZE + 10;
+            let mut dst = Vec::new();
+            let mut i = 0;
+            while lr.read(&mut dst).unwrap() > 0 {
+                assert_eq!(dst, data[i]);
+                i += 1;
+            }
+            assert!(i < data.len());
+        }
+    }
 }
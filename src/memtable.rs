@@ -1,55 +1,355 @@
-use crate::key::{self, ValueType};
+use crate::iterator::InternalIterator;
+use crate::key::{
+    self, InternalKeyComparator, ParsedInternalKey, ValueType, VarintExt, MAX_SEQUENCE_NUMBER,
+};
 use skl::{
-    generic::{
-        unique::{sync::SkipMap, Map},
-        Builder,
+    dynamic::{
+        unique::{
+            sync::{Entry, SkipMap},
+            Map,
+        },
+        Builder, BytesComparator, BytesEquivalentor,
     },
-    Arena,
+    Active, Arena,
 };
+use std::cmp::Ordering;
+use std::ops::Bound;
+use std::sync::Arc;
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// Skiplist ordering for keys built by `key::build_mem_key`.
+#[derive(Clone, Default)]
+struct MemKeyComparator(InternalKeyComparator);
+
+impl BytesEquivalentor for MemKeyComparator {
+    fn equivalent(&self, a: &[u8], b: &[u8]) -> bool {
+        a == b
+    }
+}
+
+impl BytesComparator for MemKeyComparator {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.0.compare_mem_keys(a, b)
+    }
+}
+
+/// Result of a successful `MemTable::get`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum LookupResult {
+    Value(Vec<u8>),
+    /// The newest visible version of the key is a deletion.
+    Deleted,
+}
+
+/// Arena size of a memtable created without an explicit capacity.
+pub const DEFAULT_CAPACITY: usize = 4 << 20;
+
+/// Upper bound on the arena space an entry takes beyond its key and value:
+/// the skiplist node with its tower, the length prefixes and the tag.
+pub const ENTRY_OVERHEAD: usize = 256;
 
 pub struct MemTable {
-    map: SkipMap<Vec<u8>, Vec<u8>>,
+    map: SkipMap<MemKeyComparator>,
+    icmp: InternalKeyComparator,
 }
 
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl MemTable {
     pub fn new() -> MemTable {
-        let l = Builder::new()
-            .with_capacity(4 << 20)
-            .alloc::<SkipMap<Vec<u8>, Vec<u8>>>()
-            .unwrap();
-        MemTable { map: l }
+        Self::with_comparator(InternalKeyComparator::default())
+    }
+
+    /// Creates a memtable whose entries are ordered by `icmp`.
+    pub fn with_comparator(icmp: InternalKeyComparator) -> MemTable {
+        Self::with_capacity(icmp, DEFAULT_CAPACITY).expect("failed to allocate a memtable")
+    }
+
+    /// Creates a memtable backed by an arena of `capacity` bytes, which can be
+    /// at most `u32::MAX`. Adding entries past the capacity fails, see
+    /// `remaining`.
+    pub fn with_capacity(icmp: InternalKeyComparator, capacity: usize) -> Result<MemTable> {
+        let capacity = u32::try_from(capacity).map_err(|_| {
+            std::io::Error::other(format!("memtable capacity {} is over 4 GiB", capacity))
+        })?;
+        let l = Builder::with(MemKeyComparator(icmp.clone()))
+            .with_capacity(capacity)
+            .alloc::<SkipMap<MemKeyComparator>>()
+            .map_err(std::io::Error::other)?;
+        Ok(MemTable { map: l, icmp })
     }
 
     pub fn len(&self) -> usize {
         self.map.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
     pub fn allocated(&self) -> usize {
         self.map.allocated()
     }
 
-    pub fn add(&self, seq: u64, t: ValueType, user_key: &[u8], value: &[u8]) {
+    /// Arena bytes still available for new entries.
+    pub fn remaining(&self) -> usize {
+        self.map.remaining()
+    }
+
+    /// Iterates over every entry in order as (encoded internal key, value).
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&[u8], &[u8])> + '_ {
+        self.map.iter().filter_map(|ent| {
+            Some((
+                key::strip_mem_key(ent.key())?,
+                key::decode_mem_value(ent.value())?,
+            ))
+        })
+    }
+
+    /// Adds an entry, failing once the arena is full. `Db` makes room for a
+    /// whole batch before adding it, so there this only fails if that
+    /// reckoning is wrong.
+    pub fn add(&self, seq: u64, t: ValueType, user_key: &[u8], value: &[u8]) -> Result<()> {
         let memkey = key::build_mem_key(seq, t, user_key);
         let memval = key::build_mem_value(value);
-        self.map.insert(&memkey, &memval).unwrap();
+        match self.map.insert(&memkey, &memval) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(std::io::Error::other(format!("memtable add failed: {}", e))),
+        }
+    }
+
+    /// Looks up the newest version of `user_key` whose sequence number is at
+    /// most `seq`. Returns `None` if no such version exists.
+    pub fn get(&self, user_key: &[u8], seq: u64) -> Option<LookupResult> {
+        // Versions of a key are ordered newest first, and a value sorts before a
+        // deletion with the same sequence, so this seeks past everything newer than `seq`.
+        let lookup = key::build_mem_key(seq, ValueType::TypeValue, user_key);
+        let ent = self.map.lower_bound(Bound::Included(lookup.as_slice()))?;
+
+        let found = ParsedInternalKey::decode(key::strip_mem_key(ent.key())?)?;
+        if self.icmp.user_comparator().compare(found.user_key, user_key) != Ordering::Equal {
+            return None;
+        }
+        match found.value_type {
+            ValueType::TypeValue => Some(LookupResult::Value(
+                key::decode_mem_value(ent.value())?.to_vec(),
+            )),
+            ValueType::TypeDeletion => Some(LookupResult::Deleted),
+        }
+    }
+}
+
+/// Which versions of each key a `MemTableIterator` yields.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IterMode {
+    /// Every version of every key, newest first within a key.
+    AllVersions,
+    /// Only the newest version of each key with a sequence number at most the
+    /// given one. That version may be a deletion.
+    Snapshot(u64),
+}
+
+/// A skiplist entry detached from the borrow of its map, see `detach`.
+type Cursor = Entry<'static, Active, MemKeyComparator>;
+
+/// A cursor over the entries of a `MemTable`, in both directions.
+///
+/// The iterator keeps the memtable alive and holds the current skiplist entry,
+/// stepping from it; only the seek methods search the skiplist. Writes made
+/// while iterating may or may not be seen.
+pub struct MemTableIterator {
+    /// Borrows from `mem`, so it is declared first to be dropped first.
+    current: Option<Cursor>,
+    mem: Arc<MemTable>,
+    mode: IterMode,
+}
+
+// SAFETY: the cursor reads the map only as a shared reference to it would,
+// and the map is `Sync`.
+unsafe impl Send for MemTableIterator {}
+
+impl MemTableIterator {
+    /// Creates an iterator over `mem`. It is not positioned until one of the
+    /// seek methods is called.
+    pub fn new(mem: Arc<MemTable>, mode: IterMode) -> MemTableIterator {
+        MemTableIterator {
+            current: None,
+            mem,
+            mode,
+        }
+    }
+
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.current = detach(self.mem.map.first());
+        self.skip_forward();
+    }
+
+    pub fn seek_to_last(&mut self) {
+        self.current = detach(self.mem.map.last());
+        self.skip_backward();
     }
 
-    pub fn get(&self,user_key: &[u8],seq: u64) -> Option<Vec<u8>>{
-        let find_key = key::build_mem_key(seq, ValueType::TypeValue, user_key);
-        let find =  match self.map.get(&find_key) {
-            Some(ent) => ent,
-            None => return None
+    /// Positions at the first key at or after `user_key`.
+    pub fn seek(&mut self, user_key: &[u8]) {
+        let seq = match self.mode {
+            IterMode::AllVersions => MAX_SEQUENCE_NUMBER,
+            IterMode::Snapshot(seq) => seq,
         };
-        Some(find.value().to_vec())
+        let lookup = key::build_mem_key(seq, ValueType::TypeValue, user_key);
+        self.current = detach(self.mem.map.lower_bound(Bound::Included(lookup.as_slice())));
+        self.skip_forward();
+    }
+
+    /// Moves to the next entry. Must only be called while valid.
+    pub fn next(&mut self) {
+        let Some(ent) = self.current else {
+            return;
+        };
+        self.current = ent.next();
+        if let IterMode::Snapshot(_) = self.mode {
+            // Past the older versions of the key just left.
+            let user_key = parse(&ent).user_key;
+            self.skip_while(|ent| ent.next(), |key| key.user_key == user_key);
+            self.skip_forward();
+        }
+    }
+
+    /// Moves to the previous entry. Must only be called while valid.
+    pub fn prev(&mut self) {
+        let Some(ent) = self.current else {
+            return;
+        };
+        self.current = ent.prev();
+        if let IterMode::Snapshot(_) = self.mode {
+            // Before the newer versions of the key just left, which lands on
+            // the oldest version of the key before it.
+            let user_key = parse(&ent).user_key;
+            self.skip_while(|ent| ent.prev(), |key| key.user_key == user_key);
+            self.skip_backward();
+        }
+    }
+
+    /// The current entry's user key, sequence number and type. Must only be
+    /// called while valid.
+    pub fn key(&self) -> ParsedInternalKey<'_> {
+        parse(self.current.as_ref().expect("iterator is not valid"))
+    }
+
+    /// The current entry's value, empty for a deletion. Must only be called
+    /// while valid.
+    pub fn value(&self) -> &[u8] {
+        let ent = self.current.as_ref().expect("iterator is not valid");
+        key::decode_mem_value(ent.value()).unwrap_or_default()
+    }
+
+    /// Steps with `step` while the current entry's key satisfies `cond`.
+    fn skip_while(
+        &mut self,
+        step: impl Fn(&Cursor) -> Option<Cursor>,
+        cond: impl Fn(&ParsedInternalKey) -> bool,
+    ) {
+        while let Some(ent) = &self.current {
+            if !cond(&parse(ent)) {
+                return;
+            }
+            self.current = step(ent);
+        }
     }
 
+    /// In snapshot mode, moves forward to the first entry visible at the
+    /// snapshot, which is the newest visible version of its key.
+    fn skip_forward(&mut self) {
+        if let IterMode::Snapshot(seq) = self.mode {
+            self.skip_while(|ent| ent.next(), |key| key.sequence > seq);
+        }
+    }
+
+    /// In snapshot mode, moves from the oldest version of the current key to
+    /// the newest one visible at the snapshot, or to earlier keys if it has
+    /// none.
+    fn skip_backward(&mut self) {
+        let IterMode::Snapshot(seq) = self.mode else {
+            return;
+        };
+        // Versions are ordered newest first, so if the oldest is not visible
+        // none of them is.
+        self.skip_while(|ent| ent.prev(), |key| key.sequence > seq);
+        while let Some(newer) = self.current.and_then(|ent| ent.prev()) {
+            let newer_key = parse(&newer);
+            if newer_key.user_key != self.key().user_key || newer_key.sequence > seq {
+                return;
+            }
+            self.current = Some(newer);
+        }
+    }
+}
+
+/// Positions by internal key rather than user key, so a `MemTableIterator` can
+/// be merged with table iterators.
+impl InternalIterator for MemTableIterator {
+    fn valid(&self) -> bool {
+        MemTableIterator::valid(self)
+    }
+
+    fn seek_to_first(&mut self) {
+        MemTableIterator::seek_to_first(self)
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        let mut lookup = Vec::with_capacity(target.len() + 5);
+        lookup.extend_varint(target.len());
+        lookup.extend_from_slice(target);
+        self.current = detach(self.mem.map.lower_bound(Bound::Included(lookup.as_slice())));
+        self.skip_forward();
+    }
+
+    fn next(&mut self) {
+        MemTableIterator::next(self)
+    }
+
+    fn key(&self) -> &[u8] {
+        let ent = self.current.as_ref().expect("iterator is not valid");
+        key::strip_mem_key(ent.key()).expect("memtable holds a malformed key")
+    }
+
+    fn value(&self) -> &[u8] {
+        MemTableIterator::value(self)
+    }
+
+    fn status(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Lets a `MemTableIterator` keep an entry of the map of the memtable it holds.
+fn detach(ent: Option<Entry<'_, Active, MemKeyComparator>>) -> Option<Cursor> {
+    // SAFETY: the entry points into the map and its arena. The iterator keeps
+    // it together with the `Arc<MemTable>` owning the map, so the map neither
+    // moves nor is dropped while the entry is in use, and the arena never
+    // frees an entry.
+    ent.map(|ent| unsafe {
+        std::mem::transmute::<Entry<'_, Active, MemKeyComparator>, Cursor>(ent)
+    })
+}
+
+fn parse(ent: &Cursor) -> ParsedInternalKey<'_> {
+    key::strip_mem_key(ent.key())
+        .and_then(ParsedInternalKey::decode)
+        .expect("memtable holds a malformed key")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::sync::Arc;
     use std::thread;
     
     #[test]
@@ -57,14 +357,56 @@ mod tests {
         let memtable = MemTable::new();
         assert_eq!(memtable.len(), 0);
         // Test add and get
-        memtable.add(1, ValueType::TypeValue, b"key1", b"value1");
+        memtable.add(1, ValueType::TypeValue, b"key1", b"value1").unwrap();
         assert_eq!(memtable.len(), 1);
-        assert_eq!(memtable.get(b"key1", 1), Some(b"value1".to_vec()));
+        assert_eq!(
+            memtable.get(b"key1", 1),
+            Some(LookupResult::Value(b"value1".to_vec()))
+        );
         
         // Test non-existent key
         assert_eq!(memtable.get(b"key2", 1), None);
     }
 
+    #[test]
+    fn test_memtable_versions() {
+        let memtable = MemTable::new();
+        memtable.add(1, ValueType::TypeValue, b"key", b"v1").unwrap();
+        memtable.add(5, ValueType::TypeValue, b"key", b"v5").unwrap();
+        memtable.add(7, ValueType::TypeDeletion, b"key", b"").unwrap();
+        memtable.add(300, ValueType::TypeValue, b"key", b"v300").unwrap();
+        memtable.add(2, ValueType::TypeValue, b"key1", b"other").unwrap();
+        memtable.add(2, ValueType::TypeValue, b"ke", b"other").unwrap();
+
+        let value = |v: &str| Some(LookupResult::Value(v.as_bytes().to_vec()));
+        assert_eq!(memtable.get(b"key", 0), None);
+        assert_eq!(memtable.get(b"key", 1), value("v1"));
+        assert_eq!(memtable.get(b"key", 4), value("v1"));
+        assert_eq!(memtable.get(b"key", 5), value("v5"));
+        assert_eq!(memtable.get(b"key", 6), value("v5"));
+        assert_eq!(memtable.get(b"key", 7), Some(LookupResult::Deleted));
+        assert_eq!(memtable.get(b"key", 299), Some(LookupResult::Deleted));
+        assert_eq!(memtable.get(b"key", 300), value("v300"));
+        assert_eq!(memtable.get(b"key", u64::MAX >> 8), value("v300"));
+        assert_eq!(memtable.get(b"k", 300), None);
+        assert_eq!(memtable.get(b"key0", 300), None);
+    }
+
+    #[test]
+    fn test_memtable_custom_comparator() {
+        use crate::comparator::ReverseBytewiseComparator;
+
+        let icmp = InternalKeyComparator::new(std::sync::Arc::new(ReverseBytewiseComparator));
+        let memtable = MemTable::with_comparator(icmp);
+        memtable.add(1, ValueType::TypeValue, b"a", b"1").unwrap();
+        memtable.add(2, ValueType::TypeValue, b"b", b"2").unwrap();
+        memtable.add(3, ValueType::TypeValue, b"a", b"3").unwrap();
+        assert_eq!(memtable.get(b"a", 2), Some(LookupResult::Value(b"1".to_vec())));
+        assert_eq!(memtable.get(b"a", 3), Some(LookupResult::Value(b"3".to_vec())));
+        assert_eq!(memtable.get(b"b", 3), Some(LookupResult::Value(b"2".to_vec())));
+        assert_eq!(memtable.get(b"c", 3), None);
+    }
+
     #[test]
     fn test_memtable_concurrent() {
         let memtable = Arc::new(MemTable::new());
@@ -77,7 +419,7 @@ mod tests {
                 for j in 0..100 {
                     let key = format!("key_{}_{}", i, j);
                     let value = format!("value_{}_{}", i, j);
-                    memtable.add((i * 100 + j) as u64, ValueType::TypeValue, key.as_bytes(), value.as_bytes());
+                    memtable.add((i * 100 + j) as u64, ValueType::TypeValue, key.as_bytes(), value.as_bytes()).unwrap();
                 }
             }));
         }
@@ -100,7 +442,143 @@ mod tests {
         let memtable = MemTable::new();
         let initial = memtable.allocated();
         
-        memtable.add(1, ValueType::TypeValue, b"key1", b"value1");
+        memtable.add(1, ValueType::TypeValue, b"key1", b"value1").unwrap();
         assert!(memtable.allocated() > initial);
     }
+
+    #[test]
+    fn test_memtable_capacity() {
+        let icmp = InternalKeyComparator::default();
+        assert!(MemTable::with_capacity(icmp.clone(), u32::MAX as usize + 1).is_err());
+
+        // Adding past the end of the arena fails instead of panicking.
+        let memtable = MemTable::with_capacity(icmp, 4096).unwrap();
+        let value = [0u8; 512];
+        let add = |seq| memtable.add(seq, ValueType::TypeValue, b"k", &value);
+        assert!((1..100).any(|seq| add(seq).is_err()));
+        assert!(memtable.remaining() < value.len() + ENTRY_OVERHEAD);
+    }
+
+    /// (user key, sequence, value) of every entry from the current position.
+    fn walk(it: &mut MemTableIterator, forward: bool) -> Vec<(String, u64, String)> {
+        let mut out = Vec::new();
+        while it.valid() {
+            let key = it.key();
+            out.push((
+                String::from_utf8(key.user_key.to_vec()).unwrap(),
+                key.sequence,
+                String::from_utf8(it.value().to_vec()).unwrap(),
+            ));
+            if forward {
+                it.next();
+            } else {
+                it.prev();
+            }
+        }
+        out
+    }
+
+    fn versions_memtable() -> Arc<MemTable> {
+        let memtable = MemTable::new();
+        memtable.add(1, ValueType::TypeValue, b"a", b"a1").unwrap();
+        memtable.add(4, ValueType::TypeValue, b"a", b"a4").unwrap();
+        memtable.add(2, ValueType::TypeValue, b"b", b"b2").unwrap();
+        memtable.add(5, ValueType::TypeDeletion, b"b", b"").unwrap();
+        memtable.add(6, ValueType::TypeValue, b"c", b"c6").unwrap();
+        memtable.add(3, ValueType::TypeValue, b"d", b"d3").unwrap();
+        Arc::new(memtable)
+    }
+
+    fn entries(list: &[(&str, u64, &str)]) -> Vec<(String, u64, String)> {
+        list.iter()
+            .map(|&(k, seq, v)| (k.to_string(), seq, v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_memtable_iterator_all_versions() {
+        let mut it = MemTableIterator::new(versions_memtable(), IterMode::AllVersions);
+        assert!(!it.valid());
+        let all = entries(&[
+            ("a", 4, "a4"),
+            ("a", 1, "a1"),
+            ("b", 5, ""),
+            ("b", 2, "b2"),
+            ("c", 6, "c6"),
+            ("d", 3, "d3"),
+        ]);
+
+        it.seek_to_first();
+        assert_eq!(walk(&mut it, true), all);
+        it.seek_to_last();
+        assert_eq!(walk(&mut it, false), all.iter().rev().cloned().collect::<Vec<_>>());
+
+        it.seek(b"b");
+        assert_eq!(walk(&mut it, true), all[2..]);
+        it.seek(b"bb");
+        assert_eq!(walk(&mut it, true), all[4..]);
+        it.seek(b"e");
+        assert!(!it.valid());
+
+        let mut it = MemTableIterator::new(Arc::new(MemTable::new()), IterMode::AllVersions);
+        it.seek_to_first();
+        assert!(!it.valid());
+        it.seek_to_last();
+        assert!(!it.valid());
+    }
+
+    #[test]
+    fn test_memtable_iterator_snapshot() {
+        let mem = versions_memtable();
+        let mut it = MemTableIterator::new(mem.clone(), IterMode::Snapshot(u64::MAX >> 8));
+        it.seek_to_first();
+        assert_eq!(
+            walk(&mut it, true),
+            entries(&[("a", 4, "a4"), ("b", 5, ""), ("c", 6, "c6"), ("d", 3, "d3")])
+        );
+        it.seek(b"b");
+        assert_eq!(it.key().value_type, ValueType::TypeDeletion);
+
+        // At 3, c does not exist yet and b is not yet deleted.
+        let at3 = entries(&[("a", 1, "a1"), ("b", 2, "b2"), ("d", 3, "d3")]);
+        let mut it = MemTableIterator::new(mem.clone(), IterMode::Snapshot(3));
+        it.seek_to_first();
+        assert_eq!(walk(&mut it, true), at3);
+        it.seek_to_last();
+        assert_eq!(walk(&mut it, false), at3.iter().rev().cloned().collect::<Vec<_>>());
+        it.seek(b"c");
+        assert_eq!(walk(&mut it, true), at3[2..]);
+
+        // Changing direction in the middle.
+        it.seek(b"b");
+        it.next();
+        assert_eq!(it.key().user_key, b"d");
+        it.prev();
+        assert_eq!(it.key().user_key, b"b");
+        it.prev();
+        assert_eq!((it.key().user_key, it.key().sequence), (&b"a"[..], 1));
+        it.prev();
+        assert!(!it.valid());
+
+        // Nothing is visible before the first write.
+        let mut it = MemTableIterator::new(mem, IterMode::Snapshot(0));
+        it.seek_to_first();
+        assert!(!it.valid());
+        it.seek_to_last();
+        assert!(!it.valid());
+    }
+
+    #[test]
+    fn test_memtable_iterator_cursor() {
+        let mem = versions_memtable();
+        let mut it = MemTableIterator::new(mem.clone(), IterMode::AllVersions);
+        it.seek(b"b");
+        // Stepping from the current entry finds one added right after it, and
+        // the iterator alone keeps the memtable alive.
+        mem.add(7, ValueType::TypeValue, b"bb", b"bb7").unwrap();
+        drop(mem);
+        it.next();
+        it.next();
+        assert_eq!((it.key().user_key, it.value()), (&b"bb"[..], &b"bb7"[..]));
+    }
 }
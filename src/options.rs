@@ -0,0 +1,234 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::comparator::{BytewiseComparator, Comparator};
+use crate::db::Snapshot;
+use crate::filter::{BloomFilterPolicy, FilterPolicy};
+
+/// When the write-ahead log is forced to stable storage.
+///
+/// In every mode a write is handed to the operating system before it is
+/// acknowledged, so acknowledged writes survive a crash of the process itself.
+/// The modes differ in what survives a crash of the machine (power loss, kernel
+/// panic).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// The log is fsynced before a write is acknowledged: nothing acknowledged
+    /// is ever lost. Concurrent writers share one fsync per commit group.
+    Always,
+    /// A background thread fsyncs the log every `Options::sync_interval`. A
+    /// machine crash loses at most the writes acknowledged since the last sync,
+    /// about one interval's worth.
+    EverySec,
+    /// The log is never fsynced while the database is open; the operating
+    /// system writes it back when it sees fit. A machine crash can lose any
+    /// write the OS had not yet written back.
+    No,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    /// Parses the names used in configuration: `always`, `everysec` or `no`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(Durability::Always),
+            "everysec" => Ok(Durability::EverySec),
+            "no" => Ok(Durability::No),
+            _ => Err(format!("unknown durability mode: {}", s)),
+        }
+    }
+}
+
+/// How table files are merged as the database grows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionStrategy {
+    /// Tables are kept in levels, each ten times the size of the one above and
+    /// holding one sorted run. Space overhead stays low, but every byte is
+    /// rewritten about once per level on its way down.
+    Leveled,
+    /// Every table is a sorted run in level 0, and runs of similar size are
+    /// merged into one. Data is rewritten far less often than with `Leveled`,
+    /// at the cost of more space held by old versions and more runs to search.
+    SizeTiered(SizeTieredOptions),
+}
+
+/// Tuning for `CompactionStrategy::SizeTiered`.
+///
+/// A merge starts once there are `Options::level0_compaction_trigger` runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeTieredOptions {
+    /// Percentage by which a run may be bigger than all the newer runs picked
+    /// so far together and still join the merge.
+    pub size_ratio: u32,
+    /// Fewest runs merged at once by size ratio.
+    pub min_merge_width: usize,
+    /// Most runs merged at once.
+    pub max_merge_width: usize,
+}
+
+impl Default for SizeTieredOptions {
+    fn default() -> Self {
+        SizeTieredOptions {
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: usize::MAX,
+        }
+    }
+}
+
+impl FromStr for CompactionStrategy {
+    type Err = String;
+
+    /// Parses the names used in configuration: `leveled` or `size-tiered`, the
+    /// latter with default tuning.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "leveled" => Ok(CompactionStrategy::Leveled),
+            "size-tiered" => Ok(CompactionStrategy::SizeTiered(SizeTieredOptions::default())),
+            _ => Err(format!("unknown compaction strategy: {}", s)),
+        }
+    }
+}
+
+/// Which keys `Keyspace` deletes to get back under `Options::maxmemory`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Nothing is evicted; writes that could grow the keyspace fail instead.
+    NoEviction,
+    /// The least recently used of a sample of keys.
+    AllKeysLru,
+    /// The least frequently used of a sample of keys.
+    AllKeysLfu,
+    /// The least recently used of a sample of keys with an expiry time.
+    VolatileLru,
+    /// The key with the nearest expiry time.
+    VolatileTtl,
+    /// Any key.
+    AllKeysRandom,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    /// Parses the names Redis uses, such as `allkeys-lru`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "volatile-lru" => Ok(EvictionPolicy::VolatileLru),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            "allkeys-random" => Ok(EvictionPolicy::AllKeysRandom),
+            _ => Err(format!("unknown eviction policy: {}", s)),
+        }
+    }
+}
+
+/// Settings used when opening a `Db`.
+#[derive(Clone)]
+pub struct Options {
+    /// Orders user keys in the memtable and in table files. A database must
+    /// always be reopened with the comparator it was created with.
+    pub comparator: Arc<dyn Comparator>,
+    /// Builds a filter for each table file so lookups of absent keys can skip
+    /// it without reading a data block. `None` writes tables without filters.
+    pub filter_policy: Option<Arc<dyn FilterPolicy>>,
+    /// Bytes of writes a memtable collects before it is flushed to a table file.
+    pub write_buffer_size: usize,
+    /// How tables are merged. A database may switch strategies between opens;
+    /// size-tiered compaction leaves any deeper levels as they are.
+    pub compaction_strategy: CompactionStrategy,
+    /// Level 0 is compacted once it holds this many files.
+    pub level0_compaction_trigger: usize,
+    /// Writes stop while level 0 holds this many files, until compaction
+    /// catches up.
+    pub level0_stop_writes_trigger: usize,
+    /// Size limit of level 1. Each deeper level may hold ten times more.
+    pub max_bytes_for_level_base: u64,
+    /// Compaction starts a new output file once the current one is this big.
+    pub max_file_size: u64,
+    /// Most batches merged into one log record by group commit.
+    pub max_write_group_size: usize,
+    pub durability: Durability,
+    /// How often the log is synced under `Durability::EverySec`.
+    pub sync_interval: Duration,
+    /// Memory the keys of a `Keyspace` may take before it evicts some, as
+    /// estimated by `Keyspace::used_memory`. 0 means no limit. The memtables
+    /// count too, and while they alone reach the limit no eviction helps, so
+    /// writes are refused until they are flushed: the limit should be well
+    /// above `write_buffer_size`.
+    pub maxmemory: usize,
+    pub eviction_policy: EvictionPolicy,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            comparator: Arc::new(BytewiseComparator),
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            write_buffer_size: 4 << 20,
+            compaction_strategy: CompactionStrategy::Leveled,
+            level0_compaction_trigger: 4,
+            level0_stop_writes_trigger: 12,
+            max_bytes_for_level_base: 10 << 20,
+            max_file_size: 2 << 20,
+            max_write_group_size: 64,
+            durability: Durability::EverySec,
+            sync_interval: Duration::from_secs(1),
+            maxmemory: 0,
+            eviction_policy: EvictionPolicy::NoEviction,
+        }
+    }
+}
+
+/// Settings for one read.
+#[derive(Clone, Debug, Default)]
+pub struct ReadOptions {
+    /// Reads see the database as of this snapshot. `None` reads the latest
+    /// state.
+    pub snapshot: Option<Snapshot>,
+    /// Iterators start at this user key, inclusive.
+    pub lower_bound: Option<Vec<u8>>,
+    /// Iterators stop before this user key.
+    pub upper_bound: Option<Vec<u8>>,
+    /// Iterators only yield user keys starting with this prefix, and stop at
+    /// the first key past them. Keys sharing a prefix must sort together under
+    /// the comparator, as they do under the bytewise one.
+    pub prefix: Option<Vec<u8>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_durability() {
+        assert_eq!("always".parse(), Ok(Durability::Always));
+        assert_eq!("EVERYSEC".parse(), Ok(Durability::EverySec));
+        assert_eq!("no".parse(), Ok(Durability::No));
+        assert!("sometimes".parse::<Durability>().is_err());
+    }
+
+    #[test]
+    fn test_parse_compaction_strategy() {
+        assert_eq!("leveled".parse(), Ok(CompactionStrategy::Leveled));
+        assert_eq!(
+            "Size-Tiered".parse(),
+            Ok(CompactionStrategy::SizeTiered(SizeTieredOptions::default()))
+        );
+        assert!("tiered".parse::<CompactionStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_parse_eviction_policy() {
+        assert_eq!("noeviction".parse(), Ok(EvictionPolicy::NoEviction));
+        assert_eq!("allkeys-LRU".parse(), Ok(EvictionPolicy::AllKeysLru));
+        assert_eq!("allkeys-lfu".parse(), Ok(EvictionPolicy::AllKeysLfu));
+        assert_eq!("volatile-lru".parse(), Ok(EvictionPolicy::VolatileLru));
+        assert_eq!("volatile-ttl".parse(), Ok(EvictionPolicy::VolatileTtl));
+        assert_eq!("allkeys-random".parse(), Ok(EvictionPolicy::AllKeysRandom));
+        assert!("lru".parse::<EvictionPolicy>().is_err());
+    }
+}
@@ -1,6 +1,8 @@
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use tokio::io::{AsyncWriteExt, WriteHalf};
 use tokio::net::TcpStream;
+
+use crate::cmd_type;
 /// Pipeline for buffering and writing data to a TCP stream
 pub struct Pipeline {
     writer: WriteHalf<TcpStream>,
@@ -18,9 +20,16 @@ impl Pipeline {
 
     /// Add data to the pipeline buffer
     pub async fn assign(&mut self, data: &str) {
-        let request = make_request(data).unwrap();
+        let request: Vec<&str> = data.split(' ').collect();
+        self.assign_args(&request).await.unwrap();
+    }
 
+    /// Add a request given as its raw arguments, the command name first.
+    /// Arguments may hold any bytes, spaces included.
+    pub async fn assign_args<A: AsRef<[u8]>>(&mut self, args: &[A]) -> Result<(), &'static str> {
+        let request = make_request(args)?;
         self.buf.put_slice(&request);
+        Ok(())
     }
 
     /// Write buffered data to the stream
@@ -38,46 +47,45 @@ impl Pipeline {
     }
 }
 
-fn make_request(cmd_str: &str) -> Result<BytesMut, &'static str> {
-    use std::collections::HashSet;
-
-    static CMD_TYPES: once_cell::sync::Lazy<HashSet<&str>> = once_cell::sync::Lazy::new(|| {
-        ["get", "set", "del", "incr", "decr", "mget", "setnx"]
-            .iter()
-            .cloned()
-            .collect()
-    });
-
-    let request: Vec<&str> = cmd_str.split(' ').collect();
-    if request.is_empty() {
-        return Err("Empty command");
-    }
-
-    let cmd = request[0].to_ascii_lowercase();
-    if !CMD_TYPES.contains(&cmd.as_str()) {
-        return Err("Invalid command");
+/// Encodes a request in the framed protocol, after checking the command and
+/// its arity against the command table.
+pub fn make_request<A: AsRef<[u8]>>(args: &[A]) -> Result<Bytes, &'static str> {
+    let name = args.first().ok_or("Empty command")?;
+    let spec = cmd_type::lookup(name).ok_or("Invalid command")?;
+    if !spec.arity.accepts(args.len()) {
+        return Err("Invalid number of arguments");
     }
+    Ok(make_buf(args))
+}
 
-    match cmd.as_str() {
-        "get" if request.len() == 2 => Ok(make_buf(request)),
-        "set" if request.len() == 3 => Ok(make_buf(request)),
-        _ => Err("Invalid number of arguments"),
+fn make_buf<A: AsRef<[u8]>>(args: &[A]) -> Bytes {
+    let total_len: usize = args.iter().map(|arg| 4 + arg.as_ref().len()).sum();
+    let mut buf = BytesMut::with_capacity(total_len + 4);
+    buf.put_u32(args.len() as u32);
+    for arg in args {
+        buf.put_u32(arg.as_ref().len() as u32);
+        buf.put_slice(arg.as_ref());
     }
+    buf.freeze()
 }
 
-fn make_buf(request: Vec<&str>) -> BytesMut {
-    let request_len = request.len() as u32;
-    let total_len: usize = request.iter().map(|s| 4 + s.len()).sum();
-    let mut buf = BytesMut::with_capacity(total_len + 4);
-    buf.put_u32(request_len);
-    for (i, data) in request.iter().enumerate() {
-        let processed_data = if i == 0 {
-            data.to_ascii_lowercase()
-        } else {
-            data.to_string()
-        };
-        buf.put_u32(processed_data.len() as u32);
-        buf.put_slice(processed_data.as_bytes());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_request() {
+        let value = &b"\x00 \xff"[..];
+        let request = make_request(&[&b"SET"[..], b"k", value]).unwrap();
+        let mut expected = vec![0, 0, 0, 3, 0, 0, 0, 3];
+        expected.extend_from_slice(b"SET");
+        expected.extend_from_slice(&[0, 0, 0, 1, b'k', 0, 0, 0, 3]);
+        expected.extend_from_slice(value);
+        assert_eq!(&request[..], &expected[..]);
+
+        assert_eq!(make_request::<&str>(&[]), Err("Empty command"));
+        assert_eq!(make_request(&["frob"]), Err("Invalid command"));
+        assert_eq!(make_request(&["get"]), Err("Invalid number of arguments"));
+        assert!(make_request(&["mget", "a", "b", "c"]).is_ok());
     }
-    buf
 }
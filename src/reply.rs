@@ -0,0 +1,204 @@
+//! Typed replies of the framed protocol.
+//!
+//! A framed reply is a u32 big-endian length followed by the encoded reply:
+//! a type byte, then
+//!
+//! - `Ok`, `Nil`: nothing;
+//! - `Integer`: the value as an i64, big-endian;
+//! - `Bulk`: the data, up to the end of the reply;
+//! - `Error`: the code, length-prefixed, then the message up to the end;
+//! - `Array`: a u32 element count, then each element encoded and
+//!   length-prefixed like a whole reply.
+
+use std::io::{Error, ErrorKind};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::resp::Value;
+
+type Result<T> = std::result::Result<T, Error>;
+
+const TYPE_OK: u8 = 0;
+const TYPE_NIL: u8 = 1;
+const TYPE_INTEGER: u8 = 2;
+const TYPE_BULK: u8 = 3;
+const TYPE_ERROR: u8 = 4;
+const TYPE_ARRAY: u8 = 5;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    Ok,
+    Nil,
+    Integer(i64),
+    Bulk(Vec<u8>),
+    /// `code` is a machine-readable word such as `ERR` or `WRONGTYPE`.
+    Error {
+        code: String,
+        message: String,
+    },
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn bulk(data: impl Into<Vec<u8>>) -> Reply {
+        Reply::Bulk(data.into())
+    }
+
+    pub fn error(code: &str, message: impl Into<String>) -> Reply {
+        Reply::Error {
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// Appends the reply as a whole frame, length prefix included.
+    pub fn encode_frame(&self, dst: &mut Vec<u8>) {
+        let start = dst.len();
+        dst.extend_from_slice(&[0; 4]);
+        self.encode(dst);
+        let len = (dst.len() - start - 4) as u32;
+        BigEndian::write_u32(&mut dst[start..start + 4], len);
+    }
+
+    /// Appends the reply without its length prefix.
+    pub fn encode(&self, dst: &mut Vec<u8>) {
+        match self {
+            Reply::Ok => dst.push(TYPE_OK),
+            Reply::Nil => dst.push(TYPE_NIL),
+            Reply::Integer(n) => {
+                dst.push(TYPE_INTEGER);
+                dst.extend_from_slice(&n.to_be_bytes());
+            }
+            Reply::Bulk(data) => {
+                dst.push(TYPE_BULK);
+                dst.extend_from_slice(data);
+            }
+            Reply::Error { code, message } => {
+                dst.push(TYPE_ERROR);
+                dst.extend_from_slice(&(code.len() as u32).to_be_bytes());
+                dst.extend_from_slice(code.as_bytes());
+                dst.extend_from_slice(message.as_bytes());
+            }
+            Reply::Array(items) => {
+                dst.push(TYPE_ARRAY);
+                dst.extend_from_slice(&(items.len() as u32).to_be_bytes());
+                for item in items {
+                    item.encode_frame(dst);
+                }
+            }
+        }
+    }
+
+    /// Decodes a reply from the bytes of one frame, without its length prefix.
+    pub fn decode(buf: &[u8]) -> Result<Reply> {
+        let (&kind, body) = buf.split_first().ok_or_else(|| invalid("empty reply"))?;
+        let reply = match kind {
+            TYPE_OK if body.is_empty() => Reply::Ok,
+            TYPE_NIL if body.is_empty() => Reply::Nil,
+            TYPE_INTEGER if body.len() == 8 => Reply::Integer(BigEndian::read_i64(body)),
+            TYPE_BULK => Reply::Bulk(body.to_vec()),
+            TYPE_ERROR => {
+                let (code, message) = split_prefixed(body)?;
+                Reply::Error {
+                    code: utf8(code)?,
+                    message: utf8(message)?,
+                }
+            }
+            TYPE_ARRAY => {
+                let count = body.get(..4).ok_or_else(|| invalid("truncated array"))?;
+                let mut rest = &body[4..];
+                let mut items = Vec::new();
+                for _ in 0..BigEndian::read_u32(count) {
+                    let (item, next) = split_prefixed(rest)?;
+                    items.push(Reply::decode(item)?);
+                    rest = next;
+                }
+                if !rest.is_empty() {
+                    return Err(invalid("trailing bytes after array"));
+                }
+                Reply::Array(items)
+            }
+            _ => return Err(invalid("bad reply")),
+        };
+        Ok(reply)
+    }
+}
+
+impl From<Reply> for Value {
+    fn from(reply: Reply) -> Value {
+        match reply {
+            Reply::Ok => Value::Simple("OK".to_string()),
+            Reply::Nil => Value::Null,
+            Reply::Integer(n) => Value::Integer(n),
+            Reply::Bulk(data) => Value::Bulk(data),
+            Reply::Error { code, message } => Value::Error(format!("{} {}", code, message)),
+            Reply::Array(items) => Value::Array(items.into_iter().map(Value::from).collect()),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+fn utf8(buf: &[u8]) -> Result<String> {
+    String::from_utf8(buf.to_vec()).map_err(|_| invalid("reply text is not UTF-8"))
+}
+
+/// Splits a u32 length-prefixed field off the front of `buf`.
+fn split_prefixed(buf: &[u8]) -> Result<(&[u8], &[u8])> {
+    let len = buf.get(..4).ok_or_else(|| invalid("truncated reply"))?;
+    let len = BigEndian::read_u32(len) as usize;
+    let field = buf
+        .get(4..4 + len)
+        .ok_or_else(|| invalid("truncated reply"))?;
+    Ok((field, &buf[4 + len..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let replies = [
+            Reply::Ok,
+            Reply::Nil,
+            Reply::Integer(-7),
+            Reply::bulk("error"),
+            Reply::bulk(""),
+            Reply::error("WRONGTYPE", "wrong kind of value"),
+            Reply::Array(vec![
+                Reply::bulk("a"),
+                Reply::Nil,
+                Reply::Array(vec![Reply::Integer(1)]),
+            ]),
+            Reply::Array(vec![]),
+        ];
+        for reply in replies {
+            let mut buf = Vec::new();
+            reply.encode_frame(&mut buf);
+            assert_eq!(BigEndian::read_u32(&buf) as usize, buf.len() - 4);
+            assert_eq!(Reply::decode(&buf[4..]).unwrap(), reply);
+        }
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(Reply::decode(b"").is_err());
+        assert!(Reply::decode(&[TYPE_OK, 0]).is_err());
+        assert!(Reply::decode(&[TYPE_INTEGER, 0, 0]).is_err());
+        assert!(Reply::decode(&[TYPE_ARRAY, 0, 0, 0, 1]).is_err());
+        assert!(Reply::decode(&[9]).is_err());
+    }
+
+    #[test]
+    fn test_to_resp() {
+        assert_eq!(Value::from(Reply::Ok), Value::Simple("OK".to_string()));
+        assert_eq!(Value::from(Reply::Nil), Value::Null);
+        assert_eq!(
+            Value::from(Reply::error("ERR", "syntax error")),
+            Value::Error("ERR syntax error".to_string())
+        );
+    }
+}
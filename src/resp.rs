@@ -0,0 +1,341 @@
+//! The Redis serialization protocol, RESP2 and RESP3.
+//!
+//! Parsing is incremental: the parsers take whatever bytes have arrived so far
+//! and return `Ok(None)` until a whole frame is there.
+
+use std::io::{Error, ErrorKind};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Deepest nesting of aggregates a frame may have. Requests need one level and
+/// replies a few; the cap keeps a peer from exhausting the stack.
+const MAX_DEPTH: usize = 16;
+
+/// The protocol version a connection speaks. Connections start with RESP2
+/// and switch with `HELLO 3`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    Resp2,
+    Resp3,
+}
+
+impl Version {
+    pub fn number(&self) -> i64 {
+        match self {
+            Version::Resp2 => 2,
+            Version::Resp3 => 3,
+        }
+    }
+}
+
+/// One RESP frame.
+///
+/// The RESP3-only types are downgraded when encoded for RESP2: a map becomes a
+/// flat array of keys and values, a set an array, a double a bulk string and a
+/// boolean the integer 0 or 1.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+    Set(Vec<Value>),
+    Double(f64),
+    Boolean(bool),
+}
+
+impl Value {
+    pub fn bulk(data: impl Into<Vec<u8>>) -> Value {
+        Value::Bulk(data.into())
+    }
+
+    pub fn encode(&self, version: Version, dst: &mut Vec<u8>) {
+        match self {
+            Value::Simple(s) => write_line(dst, b'+', s.as_bytes()),
+            Value::Error(s) => write_line(dst, b'-', s.as_bytes()),
+            Value::Integer(n) => write_line(dst, b':', n.to_string().as_bytes()),
+            Value::Bulk(data) => {
+                write_line(dst, b'$', data.len().to_string().as_bytes());
+                dst.extend_from_slice(data);
+                dst.extend_from_slice(b"\r\n");
+            }
+            Value::Null => match version {
+                Version::Resp2 => dst.extend_from_slice(b"$-1\r\n"),
+                Version::Resp3 => dst.extend_from_slice(b"_\r\n"),
+            },
+            Value::Array(items) => write_aggregate(dst, b'*', items, version),
+            Value::Map(pairs) => {
+                let (prefix, len) = match version {
+                    Version::Resp2 => (b'*', pairs.len() * 2),
+                    Version::Resp3 => (b'%', pairs.len()),
+                };
+                write_line(dst, prefix, len.to_string().as_bytes());
+                for (key, value) in pairs {
+                    key.encode(version, dst);
+                    value.encode(version, dst);
+                }
+            }
+            Value::Set(items) => match version {
+                Version::Resp2 => write_aggregate(dst, b'*', items, version),
+                Version::Resp3 => write_aggregate(dst, b'~', items, version),
+            },
+            Value::Double(d) => match version {
+                Version::Resp2 => Value::bulk(format_double(*d)).encode(version, dst),
+                Version::Resp3 => write_line(dst, b',', format_double(*d).as_bytes()),
+            },
+            Value::Boolean(b) => match version {
+                Version::Resp2 => Value::Integer(*b as i64).encode(version, dst),
+                Version::Resp3 => write_line(dst, b'#', if *b { b"t" } else { b"f" }),
+            },
+        }
+    }
+
+    /// Parses one frame from the front of `buf`, returning it and the number
+    /// of bytes it took.
+    pub fn parse(buf: &[u8]) -> Result<Option<(Value, usize)>> {
+        parse_value(buf, 0, 0)
+    }
+}
+
+/// Parses one request from the front of `buf`: either an array of bulk
+/// strings, as clients send, or an inline command, a line of
+/// whitespace-separated words as typed into telnet. Returns the arguments and
+/// the number of bytes the request took. An empty inline line yields no
+/// arguments.
+pub fn parse_command(buf: &[u8]) -> Result<Option<(Vec<Vec<u8>>, usize)>> {
+    if buf.first() != Some(&b'*') {
+        let Some(end) = buf.iter().position(|&b| b == b'\n') else {
+            return Ok(None);
+        };
+        let args = buf[..end]
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_vec())
+            .collect();
+        return Ok(Some((args, end + 1)));
+    }
+
+    let Some((value, n)) = Value::parse(buf)? else {
+        return Ok(None);
+    };
+    let Value::Array(items) = value else {
+        return Err(protocol_error("expected an array of bulk strings"));
+    };
+    let args = items
+        .into_iter()
+        .map(|item| match item {
+            Value::Bulk(data) => Ok(data),
+            _ => Err(protocol_error("expected an array of bulk strings")),
+        })
+        .collect::<Result<_>>()?;
+    Ok(Some((args, n)))
+}
+
+fn protocol_error(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Protocol error: {}", msg))
+}
+
+fn write_line(dst: &mut Vec<u8>, prefix: u8, line: &[u8]) {
+    dst.push(prefix);
+    dst.extend_from_slice(line);
+    dst.extend_from_slice(b"\r\n");
+}
+
+fn write_aggregate(dst: &mut Vec<u8>, prefix: u8, items: &[Value], version: Version) {
+    write_line(dst, prefix, items.len().to_string().as_bytes());
+    for item in items {
+        item.encode(version, dst);
+    }
+}
+
+fn format_double(d: f64) -> String {
+    match d {
+        d if d.is_nan() => "nan".to_string(),
+        d if d == f64::INFINITY => "inf".to_string(),
+        d if d == f64::NEG_INFINITY => "-inf".to_string(),
+        d => d.to_string(),
+    }
+}
+
+/// Returns the line starting at `pos`, without its CRLF, and the position
+/// after it.
+fn read_line(buf: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let len = buf.get(pos..)?.windows(2).position(|w| w == b"\r\n")?;
+    Some((&buf[pos..pos + len], pos + len + 2))
+}
+
+fn parse_number<T: std::str::FromStr>(line: &[u8]) -> Result<T> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| protocol_error("invalid number"))
+}
+
+/// Parses a length that may be -1 for a null.
+fn parse_len(line: &[u8]) -> Result<Option<usize>> {
+    match parse_number::<i64>(line)? {
+        -1 => Ok(None),
+        n => usize::try_from(n)
+            .map(Some)
+            .map_err(|_| protocol_error("invalid length")),
+    }
+}
+
+/// Parses the frame at `pos`, nested in `depth` aggregates.
+fn parse_value(buf: &[u8], pos: usize, depth: usize) -> Result<Option<(Value, usize)>> {
+    let Some(&prefix) = buf.get(pos) else {
+        return Ok(None);
+    };
+    let Some((line, mut end)) = read_line(buf, pos + 1) else {
+        return Ok(None);
+    };
+    let text = || String::from_utf8_lossy(line).to_string();
+    let value = match prefix {
+        b'+' => Value::Simple(text()),
+        b'-' => Value::Error(text()),
+        b':' => Value::Integer(parse_number(line)?),
+        b'_' => Value::Null,
+        b',' => Value::Double(match line {
+            b"inf" => f64::INFINITY,
+            b"-inf" => f64::NEG_INFINITY,
+            _ => parse_number(line)?,
+        }),
+        b'#' => match line {
+            b"t" => Value::Boolean(true),
+            b"f" => Value::Boolean(false),
+            _ => return Err(protocol_error("invalid boolean")),
+        },
+        b'$' => match parse_len(line)? {
+            None => Value::Null,
+            Some(len) => {
+                let Some(data) = buf.get(end..end + len + 2) else {
+                    return Ok(None);
+                };
+                if &data[len..] != b"\r\n" {
+                    return Err(protocol_error("bulk string not terminated by CRLF"));
+                }
+                end += len + 2;
+                Value::Bulk(data[..len].to_vec())
+            }
+        },
+        b'*' | b'~' | b'%' => {
+            let Some(len) = parse_len(line)? else {
+                return Ok(Some((Value::Null, end)));
+            };
+            if depth == MAX_DEPTH {
+                return Err(protocol_error("aggregates nested too deep"));
+            }
+            let count = if prefix == b'%' { len * 2 } else { len };
+            // The length comes from the peer; only trust it as far as the
+            // items actually arrive.
+            let mut items = Vec::with_capacity(count.min(1024));
+            for _ in 0..count {
+                let Some((item, next)) = parse_value(buf, end, depth + 1)? else {
+                    return Ok(None);
+                };
+                items.push(item);
+                end = next;
+            }
+            match prefix {
+                b'*' => Value::Array(items),
+                b'~' => Value::Set(items),
+                _ => {
+                    let mut items = items.into_iter();
+                    Value::Map(
+                        std::iter::from_fn(|| Some((items.next()?, items.next()?))).collect(),
+                    )
+                }
+            }
+        }
+        _ => return Err(protocol_error("unknown type byte")),
+    };
+    Ok(Some((value, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: &Value, version: Version) -> Vec<u8> {
+        let mut buf = Vec::new();
+        value.encode(version, &mut buf);
+        buf
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let values = [
+            Value::Simple("OK".to_string()),
+            Value::Error("ERR bad".to_string()),
+            Value::Integer(-42),
+            Value::bulk(&b"a\r\nb"[..]),
+            Value::bulk(""),
+            Value::Null,
+            Value::Array(vec![
+                Value::Integer(1),
+                Value::Array(vec![Value::bulk("x")]),
+            ]),
+            Value::Map(vec![(Value::bulk("proto"), Value::Integer(3))]),
+            Value::Set(vec![Value::bulk("m")]),
+            Value::Double(1.5),
+            Value::Double(f64::INFINITY),
+            Value::Boolean(true),
+        ];
+        for value in values {
+            let buf = encode(&value, Version::Resp3);
+            assert_eq!(Value::parse(&buf).unwrap(), Some((value, buf.len())));
+        }
+    }
+
+    #[test]
+    fn test_resp2_downgrade() {
+        let map = Value::Map(vec![(Value::bulk("a"), Value::Integer(1))]);
+        assert_eq!(encode(&map, Version::Resp2), b"*2\r\n$1\r\na\r\n:1\r\n");
+        assert_eq!(encode(&Value::Null, Version::Resp2), b"$-1\r\n");
+        assert_eq!(
+            encode(&Value::Double(2.5), Version::Resp2),
+            b"$3\r\n2.5\r\n"
+        );
+        assert_eq!(encode(&Value::Boolean(false), Version::Resp2), b":0\r\n");
+        assert_eq!(encode(&Value::Set(vec![]), Version::Resp2), b"*0\r\n");
+    }
+
+    #[test]
+    fn test_parse_incomplete() {
+        let buf = encode(
+            &Value::Array(vec![Value::bulk("get"), Value::bulk("key")]),
+            Version::Resp2,
+        );
+        for end in 0..buf.len() {
+            assert_eq!(Value::parse(&buf[..end]).unwrap(), None);
+            assert_eq!(parse_command(&buf[..end]).unwrap(), None);
+        }
+        assert!(Value::parse(b"?x\r\n").is_err());
+        assert!(Value::parse(b"$3\r\nabcd\r\n").is_err());
+        assert!(Value::parse(b":12x\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_command() {
+        let buf = b"*2\r\n$3\r\nget\r\n$1\r\nk\r\nPING  extra\r\n\r\n";
+        let (args, n) = parse_command(buf).unwrap().unwrap();
+        assert_eq!(args, [b"get".to_vec(), b"k".to_vec()]);
+        let (args, m) = parse_command(&buf[n..]).unwrap().unwrap();
+        assert_eq!(args, [b"PING".to_vec(), b"extra".to_vec()]);
+        let (args, _) = parse_command(&buf[n + m..]).unwrap().unwrap();
+        assert!(args.is_empty());
+        assert!(parse_command(b"*1\r\n:1\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_depth() {
+        let nested = |depth| [&b"*1\r\n"[..].repeat(depth), &b":1\r\n"[..]].concat();
+        assert!(Value::parse(&nested(MAX_DEPTH)).unwrap().is_some());
+        assert!(Value::parse(&nested(MAX_DEPTH + 1)).is_err());
+        // Fails fast rather than recursing through the whole input.
+        assert!(parse_command(&nested(100_000)).is_err());
+    }
+}
@@ -0,0 +1,29 @@
+//! Sorted table files.
+//!
+//! A table holds internal keys in comparator order:
+//!
+//! ```text
+//! data block*
+//! filter block      optional, one filter over every user key in the table
+//! metaindex block   name -> value, e.g. "wdis.largest_sequence" -> u64 LE
+//! index block       last key of each data block -> BlockHandle
+//! footer            metaindex handle | index handle | padding | magic
+//! ```
+//!
+//! Every block is followed by a 5-byte trailer: a compression type byte (always
+//! 0, uncompressed) and the masked CRC32C of the block contents and that byte.
+
+use crate::filter::FilterPolicy;
+
+pub mod block;
+pub mod builder;
+pub mod format;
+pub mod reader;
+
+/// Metaindex entry holding the highest sequence number stored in the table.
+pub const LARGEST_SEQUENCE_KEY: &[u8] = b"wdis.largest_sequence";
+
+/// Metaindex entry pointing at the filter block written by `policy`.
+pub fn filter_block_key(policy: &dyn FilterPolicy) -> Vec<u8> {
+    format!("filter.{}", policy.name()).into_bytes()
+}
@@ -0,0 +1,321 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+use integer_encoding::VarInt;
+
+use crate::key::VarintExt;
+use crate::log::{err, StatusCode};
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// Keys between restart points are prefix-compressed against their predecessor.
+pub const DEFAULT_RESTART_INTERVAL: usize = 16;
+
+/// Builds a block of sorted key/value entries:
+///
+/// ```text
+/// entry*      varint shared | varint unshared | varint value len | key delta | value
+/// restart*    u32 LE offset of each entry stored with shared = 0
+/// u32 LE      number of restarts
+/// ```
+pub struct BlockBuilder {
+    buffer: Vec<u8>,
+    restarts: Vec<u32>,
+    counter: usize,
+    restart_interval: usize,
+    last_key: Vec<u8>,
+}
+
+impl Default for BlockBuilder {
+    fn default() -> Self {
+        Self::new(DEFAULT_RESTART_INTERVAL)
+    }
+}
+
+impl BlockBuilder {
+    pub fn new(restart_interval: usize) -> BlockBuilder {
+        BlockBuilder {
+            buffer: Vec::new(),
+            restarts: vec![0],
+            counter: 0,
+            restart_interval: restart_interval.max(1),
+            last_key: Vec::new(),
+        }
+    }
+
+    /// Appends an entry. Keys must be added in increasing order.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        let shared = if self.counter < self.restart_interval {
+            self.last_key
+                .iter()
+                .zip(key)
+                .take_while(|(a, b)| a == b)
+                .count()
+        } else {
+            self.restarts.push(self.buffer.len() as u32);
+            self.counter = 0;
+            0
+        };
+
+        self.buffer.extend_varint(shared);
+        self.buffer.extend_varint(key.len() - shared);
+        self.buffer.extend_varint(value.len());
+        self.buffer.extend_from_slice(&key[shared..]);
+        self.buffer.extend_from_slice(value);
+
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.counter += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Size of the block if it were finished now.
+    pub fn size_estimate(&self) -> usize {
+        self.buffer.len() + 4 * self.restarts.len() + 4
+    }
+
+    /// Returns the finished block and resets the builder.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut block = std::mem::take(&mut self.buffer);
+        for r in &self.restarts {
+            block.extend_from_slice(&r.to_le_bytes());
+        }
+        block.extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+
+        self.restarts = vec![0];
+        self.counter = 0;
+        self.last_key.clear();
+        block
+    }
+}
+
+/// A parsed block. Cloning is cheap.
+#[derive(Clone)]
+pub struct Block {
+    data: Arc<[u8]>,
+    restarts_offset: usize,
+    num_restarts: usize,
+}
+
+impl Block {
+    pub fn new(contents: Vec<u8>) -> Result<Block> {
+        if contents.len() < 4 {
+            return err(StatusCode::Corruption, "block too short");
+        }
+        let num_restarts = LittleEndian::read_u32(&contents[contents.len() - 4..]) as usize;
+        let restarts_len = num_restarts
+            .checked_mul(4)
+            .and_then(|n| n.checked_add(4))
+            .filter(|&n| n <= contents.len());
+        match restarts_len {
+            Some(n) if num_restarts > 0 => Ok(Block {
+                restarts_offset: contents.len() - n,
+                num_restarts,
+                data: contents.into(),
+            }),
+            _ => err(StatusCode::Corruption, "bad restart array in block"),
+        }
+    }
+
+    pub fn iter(&self) -> BlockIter {
+        BlockIter {
+            block: self.clone(),
+            current: self.restarts_offset,
+            next: self.restarts_offset,
+            key: Vec::new(),
+            value: (0, 0),
+        }
+    }
+
+    fn restart_point(&self, i: usize) -> usize {
+        let off = self.restarts_offset + 4 * i;
+        LittleEndian::read_u32(&self.data[off..off + 4]) as usize
+    }
+}
+
+/// A cursor over the entries of a `Block`.
+pub struct BlockIter {
+    block: Block,
+    /// Offset of the current entry; `restarts_offset` when not valid.
+    current: usize,
+    /// Offset of the entry after the current one.
+    next: usize,
+    key: Vec<u8>,
+    /// Range of the current value within the block.
+    value: (usize, usize),
+}
+
+impl BlockIter {
+    pub fn valid(&self) -> bool {
+        self.current < self.block.restarts_offset
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn value(&self) -> &[u8] {
+        &self.block.data[self.value.0..self.value.1]
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.seek_to_restart(0);
+        self.advance();
+    }
+
+    /// Positions at the first entry with a key at or after `target` according to
+    /// `cmp`, or makes the iterator invalid if there is none.
+    pub fn seek(&mut self, target: &[u8], cmp: impl Fn(&[u8], &[u8]) -> Ordering) {
+        // Binary search for the last restart point whose key is before `target`.
+        let (mut left, mut right) = (0, self.block.num_restarts - 1);
+        while left < right {
+            let mid = (left + right).div_ceil(2);
+            self.seek_to_restart(mid);
+            if !self.advance() {
+                self.invalidate();
+                return;
+            }
+            if cmp(&self.key, target) == Ordering::Less {
+                left = mid;
+            } else {
+                right = mid - 1;
+            }
+        }
+
+        self.seek_to_restart(left);
+        while self.advance() {
+            if cmp(&self.key, target) != Ordering::Less {
+                return;
+            }
+        }
+    }
+
+    /// Moves to the next entry, or makes the iterator invalid at the end.
+    pub fn next(&mut self) {
+        self.advance();
+    }
+
+    fn seek_to_restart(&mut self, i: usize) {
+        self.key.clear();
+        self.next = self.block.restart_point(i);
+    }
+
+    fn invalidate(&mut self) {
+        self.current = self.block.restarts_offset;
+        self.next = self.block.restarts_offset;
+        self.key.clear();
+    }
+
+    /// Decodes the entry at `next`, making it the current one.
+    fn advance(&mut self) -> bool {
+        let data = &self.block.data[..self.block.restarts_offset];
+        let decoded = (|| {
+            let mut off = self.next;
+            let varint = |off: &mut usize| {
+                let (v, n) = usize::decode_var(data.get(*off..)?)?;
+                *off += n;
+                Some(v)
+            };
+            let shared = varint(&mut off)?;
+            let unshared = varint(&mut off)?;
+            let value_len = varint(&mut off)?;
+            let delta = data.get(off..off.checked_add(unshared)?)?;
+            let value_end = (off + unshared).checked_add(value_len)?;
+            if shared > self.key.len() || value_end > data.len() {
+                return None;
+            }
+            Some((shared, delta, off + unshared, value_end))
+        })();
+
+        match decoded {
+            Some((shared, delta, value_start, value_end)) => {
+                self.current = self.next;
+                self.key.truncate(shared);
+                self.key.extend_from_slice(delta);
+                self.value = (value_start, value_end);
+                self.next = value_end;
+                true
+            }
+            // The end of the entries, or a corrupt entry: either way there is nothing more.
+            None => {
+                self.invalidate();
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(entries: &[(String, String)], restart_interval: usize) -> Block {
+        let mut b = BlockBuilder::new(restart_interval);
+        for (k, v) in entries {
+            b.add(k.as_bytes(), v.as_bytes());
+        }
+        Block::new(b.finish()).unwrap()
+    }
+
+    fn entries(n: usize) -> Vec<(String, String)> {
+        (0..n)
+            .map(|i| (format!("key{:04}", i * 2), format!("value{}", i)))
+            .collect()
+    }
+
+    #[test]
+    fn test_block_iterate() {
+        for interval in [1, 3, 16] {
+            let entries = entries(100);
+            let block = build(&entries, interval);
+            let mut it = block.iter();
+            assert!(!it.valid());
+            it.seek_to_first();
+            for (k, v) in &entries {
+                assert!(it.valid());
+                assert_eq!(it.key(), k.as_bytes());
+                assert_eq!(it.value(), v.as_bytes());
+                it.next();
+            }
+            assert!(!it.valid());
+        }
+    }
+
+    #[test]
+    fn test_block_seek() {
+        let entries = entries(100);
+        let block = build(&entries, 4);
+        let mut it = block.iter();
+        let cmp = |a: &[u8], b: &[u8]| a.cmp(b);
+
+        it.seek(b"key0050", cmp);
+        assert_eq!(it.key(), b"key0050");
+        it.seek(b"key0051", cmp);
+        assert_eq!(it.key(), b"key0052");
+        assert_eq!(it.value(), b"value26");
+        it.seek(b"a", cmp);
+        assert_eq!(it.key(), b"key0000");
+        it.seek(b"key0198", cmp);
+        assert_eq!(it.key(), b"key0198");
+        it.seek(b"key0199", cmp);
+        assert!(!it.valid());
+    }
+
+    #[test]
+    fn test_block_empty_and_corrupt() {
+        let block = Block::new(BlockBuilder::default().finish()).unwrap();
+        let mut it = block.iter();
+        it.seek_to_first();
+        assert!(!it.valid());
+        it.seek(b"x", |a: &[u8], b: &[u8]| a.cmp(b));
+        assert!(!it.valid());
+
+        assert!(Block::new(vec![1, 2]).is_err());
+        assert!(Block::new(vec![0, 0, 0, 0]).is_err());
+        assert!(Block::new(vec![9, 0, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use std::cmp::Ordering;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::filter::FilterPolicy;
+use crate::key::{InternalKeyComparator, ParsedInternalKey};
+use crate::table::block::BlockBuilder;
+use crate::table::format::{block_trailer, BlockHandle, Footer};
+use crate::table::{filter_block_key, LARGEST_SEQUENCE_KEY};
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// Data blocks are cut once they reach roughly this many bytes.
+pub const DEFAULT_BLOCK_SIZE: usize = 4 * 1024;
+
+/// Writes a table file from internal keys added in increasing order.
+pub struct TableBuilder<W: Write> {
+    dst: W,
+    offset: u64,
+    icmp: InternalKeyComparator,
+    block_size: usize,
+    data_block: BlockBuilder,
+    index_block: BlockBuilder,
+    last_key: Vec<u8>,
+    /// Handle of the last finished data block, indexed once the next key is known.
+    pending_index: Option<BlockHandle>,
+    num_entries: u64,
+    largest_sequence: u64,
+    filter_policy: Option<Arc<dyn FilterPolicy>>,
+    /// Distinct user keys, kept for the filter.
+    filter_keys: Vec<Vec<u8>>,
+}
+
+impl<W: Write> TableBuilder<W> {
+    pub fn new(dst: W, icmp: InternalKeyComparator) -> TableBuilder<W> {
+        TableBuilder {
+            dst,
+            offset: 0,
+            icmp,
+            block_size: DEFAULT_BLOCK_SIZE,
+            data_block: BlockBuilder::default(),
+            // Index lookups binary search restart points, so restart at every entry.
+            index_block: BlockBuilder::new(1),
+            last_key: Vec::new(),
+            pending_index: None,
+            num_entries: 0,
+            largest_sequence: 0,
+            filter_policy: None,
+            filter_keys: Vec::new(),
+        }
+    }
+
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Writes a filter block built by `policy` over the user keys of the table.
+    pub fn with_filter_policy(mut self, policy: Arc<dyn FilterPolicy>) -> Self {
+        self.filter_policy = Some(policy);
+        self
+    }
+
+    /// Adds an entry. `key` is an encoded internal key and must sort after
+    /// every key added before it.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        debug_assert!(
+            self.num_entries == 0 || self.icmp.compare(&self.last_key, key) == Ordering::Less
+        );
+
+        if let Some(handle) = self.pending_index.take() {
+            self.index_block.add(&self.last_key, &handle.encode());
+        }
+        if let Some(parsed) = ParsedInternalKey::decode(key) {
+            self.largest_sequence = self.largest_sequence.max(parsed.sequence);
+            if self.filter_policy.is_some()
+                && self.filter_keys.last().map(Vec::as_slice) != Some(parsed.user_key)
+            {
+                self.filter_keys.push(parsed.user_key.to_vec());
+            }
+        }
+
+        self.data_block.add(key, value);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.num_entries += 1;
+
+        if self.data_block.size_estimate() >= self.block_size {
+            self.flush_data_block()?;
+        }
+        Ok(())
+    }
+
+    pub fn num_entries(&self) -> u64 {
+        self.num_entries
+    }
+
+    /// Bytes written so far.
+    pub fn file_size(&self) -> u64 {
+        self.offset
+    }
+
+    /// Highest sequence number among the keys added so far.
+    pub fn largest_sequence(&self) -> u64 {
+        self.largest_sequence
+    }
+
+    /// Writes the remaining blocks and the footer, returning the destination and
+    /// the final file size.
+    pub fn finish(mut self) -> Result<(W, u64)> {
+        self.flush_data_block()?;
+        if let Some(handle) = self.pending_index.take() {
+            self.index_block.add(&self.last_key, &handle.encode());
+        }
+
+        // Metaindex keys must be added in order: "filter.*" sorts first.
+        let mut meta = BlockBuilder::default();
+        if let Some(policy) = self.filter_policy.take() {
+            let keys: Vec<&[u8]> = self.filter_keys.iter().map(Vec::as_slice).collect();
+            let filter = self.write_block(&policy.create_filter(&keys))?;
+            meta.add(&filter_block_key(policy.as_ref()), &filter.encode());
+        }
+        meta.add(LARGEST_SEQUENCE_KEY, &self.largest_sequence.to_le_bytes());
+        let metaindex = self.write_block(&meta.finish())?;
+
+        let index = self.index_block.finish();
+        let index = self.write_block(&index)?;
+
+        let footer = Footer { metaindex, index }.encode();
+        self.dst.write_all(&footer)?;
+        self.offset += footer.len() as u64;
+        self.dst.flush()?;
+        Ok((self.dst, self.offset))
+    }
+
+    fn flush_data_block(&mut self) -> Result<()> {
+        if self.data_block.is_empty() {
+            return Ok(());
+        }
+        let block = self.data_block.finish();
+        self.pending_index = Some(self.write_block(&block)?);
+        Ok(())
+    }
+
+    fn write_block(&mut self, contents: &[u8]) -> Result<BlockHandle> {
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: contents.len() as u64,
+        };
+        let trailer = block_trailer(contents);
+        self.dst.write_all(contents)?;
+        self.dst.write_all(&trailer)?;
+        self.offset += (contents.len() + trailer.len()) as u64;
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::ValueType;
+    use crate::table::block::Block;
+    use crate::table::format::{Footer, BLOCK_TRAILER_SIZE, FOOTER_SIZE};
+
+    /// Reads the block at `handle` out of an in-memory table.
+    fn block_at(table: &[u8], handle: &BlockHandle) -> Block {
+        let start = handle.offset as usize;
+        let end = start + handle.size as usize;
+        assert_eq!(
+            &table[end..end + BLOCK_TRAILER_SIZE],
+            block_trailer(&table[start..end])
+        );
+        Block::new(table[start..end].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_table_builder() {
+        let icmp = InternalKeyComparator::default();
+        let mut b = TableBuilder::new(Vec::new(), icmp.clone()).with_block_size(256);
+        let keys: Vec<Vec<u8>> = (0..500)
+            .map(|i| {
+                ParsedInternalKey::new(
+                    format!("key{:04}", i).as_bytes(),
+                    1000 - i,
+                    ValueType::TypeValue,
+                )
+                .encode()
+            })
+            .collect();
+        for (i, k) in keys.iter().enumerate() {
+            b.add(k, format!("value{}", i).as_bytes()).unwrap();
+        }
+        assert_eq!(b.num_entries(), 500);
+        let (table, size) = b.finish().unwrap();
+        assert_eq!(size, table.len() as u64);
+
+        let footer = Footer::decode(&table[table.len() - FOOTER_SIZE..]).unwrap();
+
+        let mut meta = block_at(&table, &footer.metaindex).iter();
+        meta.seek_to_first();
+        assert_eq!(meta.key(), LARGEST_SEQUENCE_KEY);
+        assert_eq!(meta.value(), 1000u64.to_le_bytes());
+
+        // Walk the index and every data block it points to.
+        let mut index = block_at(&table, &footer.index).iter();
+        index.seek_to_first();
+        let mut i = 0;
+        let mut blocks = 0;
+        while index.valid() {
+            let (handle, _) = BlockHandle::decode(index.value()).unwrap();
+            let mut data = block_at(&table, &handle).iter();
+            data.seek_to_first();
+            while data.valid() {
+                assert_eq!(data.key(), &keys[i][..]);
+                assert_eq!(data.value(), format!("value{}", i).as_bytes());
+                i += 1;
+                data.next();
+            }
+            // The index key is the last key of its block.
+            assert_eq!(index.key(), &keys[i - 1][..]);
+            blocks += 1;
+            index.next();
+        }
+        assert_eq!(i, 500);
+        assert!(blocks > 1);
+    }
+}
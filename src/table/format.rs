@@ -0,0 +1,169 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use byteorder::{ByteOrder, LittleEndian};
+use integer_encoding::VarInt;
+
+use crate::key::VarintExt;
+use crate::log::{self, err, StatusCode};
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// Compression type byte plus masked CRC32C.
+pub const BLOCK_TRAILER_SIZE: usize = 5;
+
+/// Two block handles padded to their maximum length, then the magic number.
+pub const FOOTER_SIZE: usize = 2 * BlockHandle::MAX_ENCODED_LEN + 8;
+
+const TABLE_MAGIC: u64 = 0x7764_6973_7462_6c31; // "wdistbl1"
+
+const NO_COMPRESSION: u8 = 0;
+
+/// Location of a block within a table file, not counting its trailer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl BlockHandle {
+    /// Two varint-encoded `u64`s.
+    pub const MAX_ENCODED_LEN: usize = 10 + 10;
+
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        dst.extend_varint(self.offset as usize);
+        dst.extend_varint(self.size as usize);
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::MAX_ENCODED_LEN);
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    /// Decodes a handle from the front of `src`, returning it and the number of
+    /// bytes read.
+    pub fn decode(src: &[u8]) -> Option<(BlockHandle, usize)> {
+        let (offset, n) = u64::decode_var(src)?;
+        let (size, m) = u64::decode_var(src.get(n..)?)?;
+        Some((BlockHandle { offset, size }, n + m))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Footer {
+    pub metaindex: BlockHandle,
+    pub index: BlockHandle,
+}
+
+impl Footer {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FOOTER_SIZE);
+        self.metaindex.encode_to(&mut buf);
+        self.index.encode_to(&mut buf);
+        buf.resize(FOOTER_SIZE - 8, 0);
+        buf.extend_from_slice(&TABLE_MAGIC.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(src: &[u8]) -> Result<Footer> {
+        if src.len() != FOOTER_SIZE
+            || LittleEndian::read_u64(&src[FOOTER_SIZE - 8..]) != TABLE_MAGIC
+        {
+            return err(
+                StatusCode::Corruption,
+                "not a table file (bad magic number)",
+            );
+        }
+        let decoded = BlockHandle::decode(src).and_then(|(metaindex, n)| {
+            BlockHandle::decode(&src[n..]).map(|(index, _)| Footer { metaindex, index })
+        });
+        match decoded {
+            Some(footer) => Ok(footer),
+            None => err(StatusCode::Corruption, "bad block handle in footer"),
+        }
+    }
+}
+
+/// Builds the trailer written after `contents`.
+pub fn block_trailer(contents: &[u8]) -> [u8; BLOCK_TRAILER_SIZE] {
+    let mut digest = log::digest();
+    digest.update(contents);
+    digest.update(&[NO_COMPRESSION]);
+
+    let mut trailer = [NO_COMPRESSION; BLOCK_TRAILER_SIZE];
+    LittleEndian::write_u32(&mut trailer[1..], log::mask_crc(digest.finalize()));
+    trailer
+}
+
+/// Reads the block at `handle` and verifies its checksum.
+pub fn read_block(file: &File, handle: &BlockHandle) -> Result<Vec<u8>> {
+    let len = handle.size as usize;
+    let mut buf = vec![0; len + BLOCK_TRAILER_SIZE];
+    file.read_exact_at(&mut buf, handle.offset)?;
+
+    let (contents, trailer) = buf.split_at(len);
+    if trailer[0] != NO_COMPRESSION {
+        return err(StatusCode::Corruption, "unknown block compression type");
+    }
+    if block_trailer(contents) != trailer {
+        return err(StatusCode::Corruption, "block checksum mismatch");
+    }
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Reads the footer at the end of a table file of `file_size` bytes.
+pub fn read_footer(file: &File, file_size: u64) -> Result<Footer> {
+    if file_size < FOOTER_SIZE as u64 {
+        return err(StatusCode::Corruption, "file is too short to be a table");
+    }
+    let mut buf = [0; FOOTER_SIZE];
+    file.read_exact_at(&mut buf, file_size - FOOTER_SIZE as u64)?;
+    Footer::decode(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_handle() {
+        let h = BlockHandle {
+            offset: 1 << 40,
+            size: 300,
+        };
+        let mut buf = h.encode();
+        buf.push(0xff);
+        assert_eq!(BlockHandle::decode(&buf), Some((h, buf.len() - 1)));
+        assert_eq!(BlockHandle::decode(&buf[..2]), None);
+    }
+
+    #[test]
+    fn test_footer() {
+        let footer = Footer {
+            metaindex: BlockHandle {
+                offset: 10,
+                size: 20,
+            },
+            index: BlockHandle {
+                offset: 35,
+                size: 1000,
+            },
+        };
+        let buf = footer.encode();
+        assert_eq!(buf.len(), FOOTER_SIZE);
+        assert_eq!(Footer::decode(&buf).unwrap(), footer);
+
+        let mut bad = buf.clone();
+        bad[FOOTER_SIZE - 1] ^= 1;
+        assert!(Footer::decode(&bad).is_err());
+    }
+
+    #[test]
+    fn test_block_trailer() {
+        let trailer = block_trailer(b"contents");
+        assert_eq!(trailer[0], NO_COMPRESSION);
+        assert_ne!(trailer, block_trailer(b"content5"));
+    }
+}
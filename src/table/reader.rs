@@ -0,0 +1,395 @@
+use std::cmp::Ordering;
+use std::fs::File;
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::filter::{FilterCounters, FilterPolicy};
+use crate::iterator::InternalIterator;
+use crate::key::{InternalKeyComparator, ParsedInternalKey, ValueType};
+use crate::log::{err, StatusCode};
+use crate::memtable::LookupResult;
+use crate::table::block::{Block, BlockIter};
+use crate::table::format::{self, BlockHandle};
+use crate::table::{filter_block_key, LARGEST_SEQUENCE_KEY};
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// An open table file. The index and filter blocks are kept in memory; data
+/// blocks are read and checksummed on every lookup.
+pub struct Table {
+    file: File,
+    icmp: InternalKeyComparator,
+    index: Block,
+    largest_sequence: u64,
+    /// The policy and its filter, if the table has one for this policy.
+    filter: Option<(Arc<dyn FilterPolicy>, Vec<u8>)>,
+    filter_counters: Arc<FilterCounters>,
+}
+
+impl Table {
+    /// Opens a table of `file_size` bytes, reading its footer, metaindex and
+    /// index blocks, and the filter block written by `filter_policy` if any.
+    pub fn open(
+        file: File,
+        file_size: u64,
+        icmp: InternalKeyComparator,
+        filter_policy: Option<Arc<dyn FilterPolicy>>,
+    ) -> Result<Table> {
+        let footer = format::read_footer(&file, file_size)?;
+        let index = Block::new(format::read_block(&file, &footer.index)?)?;
+
+        let meta = Block::new(format::read_block(&file, &footer.metaindex)?)?;
+        let mut it = meta.iter();
+        let bytewise = |a: &[u8], b: &[u8]| a.cmp(b);
+
+        let mut filter = None;
+        if let Some(policy) = filter_policy {
+            let key = filter_block_key(policy.as_ref());
+            it.seek(&key, bytewise);
+            if it.valid() && it.key() == key {
+                let Some((handle, _)) = BlockHandle::decode(it.value()) else {
+                    return err(StatusCode::Corruption, "bad filter block handle");
+                };
+                filter = Some((policy, format::read_block(&file, &handle)?));
+            }
+        }
+
+        it.seek(LARGEST_SEQUENCE_KEY, bytewise);
+        if !it.valid() || it.key() != LARGEST_SEQUENCE_KEY || it.value().len() != 8 {
+            let msg = "table has no largest sequence property";
+            return err(StatusCode::Corruption, msg);
+        }
+        let largest_sequence = LittleEndian::read_u64(it.value());
+
+        Ok(Table {
+            file,
+            icmp,
+            index,
+            largest_sequence,
+            filter,
+            filter_counters: Arc::default(),
+        })
+    }
+
+    /// Records filter outcomes in `counters` instead of counters private to
+    /// this table.
+    pub fn with_filter_counters(mut self, counters: Arc<FilterCounters>) -> Self {
+        self.filter_counters = counters;
+        self
+    }
+
+    pub fn has_filter(&self) -> bool {
+        self.filter.is_some()
+    }
+
+    pub fn filter_counters(&self) -> &Arc<FilterCounters> {
+        &self.filter_counters
+    }
+
+    /// Highest sequence number of any entry in the table.
+    pub fn largest_sequence(&self) -> u64 {
+        self.largest_sequence
+    }
+
+    /// Looks up the newest version of `user_key` whose sequence number is at
+    /// most `seq`, like `MemTable::get`. The filter, if any, is consulted
+    /// before any data block is read.
+    pub fn get(&self, user_key: &[u8], seq: u64) -> Result<Option<LookupResult>> {
+        let Some((policy, filter)) = &self.filter else {
+            return self.search(user_key, seq);
+        };
+        if !policy.key_may_match(user_key, filter) {
+            self.filter_counters.record_hit();
+            return Ok(None);
+        }
+        let found = self.search(user_key, seq)?;
+        self.filter_counters.record_miss(found.is_some());
+        Ok(found)
+    }
+
+    fn search(&self, user_key: &[u8], seq: u64) -> Result<Option<LookupResult>> {
+        let lookup = ParsedInternalKey::new(user_key, seq, ValueType::TypeValue).encode();
+
+        // Index keys are the last key of each block, so the first one at or
+        // after `lookup` names the only block that can hold it.
+        let mut index = self.index.iter();
+        index.seek(&lookup, |a, b| self.icmp.compare(a, b));
+        if !index.valid() {
+            return Ok(None);
+        }
+        let mut it = self.block_iter(index.value())?;
+        it.seek(&lookup, |a, b| self.icmp.compare(a, b));
+        if !it.valid() {
+            return Ok(None);
+        }
+
+        let Some(found) = ParsedInternalKey::decode(it.key()) else {
+            return err(StatusCode::Corruption, "bad internal key in table");
+        };
+        if self
+            .icmp
+            .user_comparator()
+            .compare(found.user_key, user_key)
+            != Ordering::Equal
+        {
+            return Ok(None);
+        }
+        Ok(Some(match found.value_type {
+            ValueType::TypeValue => LookupResult::Value(it.value().to_vec()),
+            ValueType::TypeDeletion => LookupResult::Deleted,
+        }))
+    }
+
+    /// Reads the data block named by an encoded handle from the index.
+    fn block_iter(&self, handle: &[u8]) -> Result<BlockIter> {
+        let Some((handle, _)) = BlockHandle::decode(handle) else {
+            return err(StatusCode::Corruption, "bad block handle in index");
+        };
+        Ok(Block::new(format::read_block(&self.file, &handle)?)?.iter())
+    }
+}
+
+/// Iterates over every entry of a table in order, reading data blocks as it
+/// goes.
+pub struct TableIter {
+    table: Arc<Table>,
+    index: BlockIter,
+    data: Option<BlockIter>,
+    err: Option<std::io::Error>,
+}
+
+impl Table {
+    pub fn iter(self: &Arc<Self>) -> TableIter {
+        TableIter {
+            table: self.clone(),
+            index: self.index.iter(),
+            data: None,
+            err: None,
+        }
+    }
+}
+
+impl TableIter {
+    /// Loads the data block the index is positioned at.
+    fn init_data_block(&mut self) {
+        self.data = None;
+        if self.index.valid() && self.err.is_none() {
+            match self.table.block_iter(self.index.value()) {
+                Ok(it) => self.data = Some(it),
+                Err(e) => self.err = Some(e),
+            }
+        }
+    }
+
+    /// Moves past exhausted data blocks to the first entry of the next one.
+    fn skip_empty_blocks(&mut self) {
+        while self.data.as_ref().is_some_and(|d| !d.valid()) {
+            self.index.next();
+            self.init_data_block();
+            if let Some(data) = &mut self.data {
+                data.seek_to_first();
+            }
+        }
+    }
+}
+
+impl InternalIterator for TableIter {
+    fn valid(&self) -> bool {
+        self.err.is_none() && self.data.as_ref().is_some_and(|d| d.valid())
+    }
+
+    fn seek_to_first(&mut self) {
+        self.index.seek_to_first();
+        self.init_data_block();
+        if let Some(data) = &mut self.data {
+            data.seek_to_first();
+        }
+        self.skip_empty_blocks();
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        let icmp = &self.table.icmp;
+        self.index.seek(target, |a, b| icmp.compare(a, b));
+        self.init_data_block();
+        let icmp = &self.table.icmp;
+        if let Some(data) = &mut self.data {
+            data.seek(target, |a, b| icmp.compare(a, b));
+        }
+        self.skip_empty_blocks();
+    }
+
+    fn next(&mut self) {
+        if let Some(data) = &mut self.data {
+            data.next();
+        }
+        self.skip_empty_blocks();
+    }
+
+    fn key(&self) -> &[u8] {
+        self.data.as_ref().unwrap().key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.data.as_ref().unwrap().value()
+    }
+
+    fn status(&self) -> Result<()> {
+        match &self.err {
+            Some(e) => Err(std::io::Error::new(e.kind(), e.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::builder::TableBuilder;
+
+    fn build(
+        entries: &[(String, u64, ValueType, &str)],
+        policy: Option<Arc<dyn FilterPolicy>>,
+    ) -> Vec<u8> {
+        let mut b =
+            TableBuilder::new(Vec::new(), InternalKeyComparator::default()).with_block_size(64);
+        if let Some(policy) = policy {
+            b = b.with_filter_policy(policy);
+        }
+        for (k, seq, t, v) in entries {
+            let key = ParsedInternalKey::new(k.as_bytes(), *seq, *t).encode();
+            b.add(&key, v.as_bytes()).unwrap();
+        }
+        b.finish().unwrap().0
+    }
+
+    fn open(name: &str, contents: &[u8], policy: Option<Arc<dyn FilterPolicy>>) -> Result<Table> {
+        let path = std::env::temp_dir().join(format!("wdis-{}-{}.ldb", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let file = File::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        Table::open(
+            file,
+            contents.len() as u64,
+            InternalKeyComparator::default(),
+            policy,
+        )
+    }
+
+    fn entries() -> Vec<(String, u64, ValueType, &'static str)> {
+        use ValueType::*;
+        let mut entries = vec![
+            ("a".to_string(), 3, TypeValue, "a3"),
+            ("key".to_string(), 300, TypeValue, "v300"),
+            ("key".to_string(), 7, TypeDeletion, ""),
+            ("key".to_string(), 5, TypeValue, "v5"),
+            ("key".to_string(), 1, TypeValue, "v1"),
+        ];
+        entries.extend((0..200).map(|i| (format!("m{:03}", i), 2, TypeValue, "filler")));
+        entries.push(("z".to_string(), 9, TypeValue, "z9"));
+        entries
+    }
+
+    #[test]
+    fn test_table_get() {
+        let table = open("table_get", &build(&entries(), None), None).unwrap();
+        assert_eq!(table.largest_sequence(), 300);
+
+        let value = |v: &str| Some(LookupResult::Value(v.as_bytes().to_vec()));
+        assert_eq!(table.get(b"a", 3).unwrap(), value("a3"));
+        assert_eq!(table.get(b"key", 0).unwrap(), None);
+        assert_eq!(table.get(b"key", 4).unwrap(), value("v1"));
+        assert_eq!(table.get(b"key", 6).unwrap(), value("v5"));
+        assert_eq!(table.get(b"key", 7).unwrap(), Some(LookupResult::Deleted));
+        assert_eq!(table.get(b"key", 300).unwrap(), value("v300"));
+        assert_eq!(table.get(b"m150", 2).unwrap(), value("filler"));
+        assert_eq!(table.get(b"z", 9).unwrap(), value("z9"));
+        assert_eq!(table.get(b"z", 8).unwrap(), None);
+        assert_eq!(table.get(b"kez", 300).unwrap(), None);
+        assert_eq!(table.get(b"zz", 300).unwrap(), None);
+    }
+
+    #[test]
+    fn test_table_corruption() {
+        let mut contents = build(&entries(), None);
+        assert!(open("table_short", &contents[..10], None).is_err());
+
+        // A flipped bit in a data block fails its checksum on lookup.
+        contents[2] ^= 1;
+        let table = open("table_corrupt", &contents, None).unwrap();
+        assert!(table.get(b"a", 3).is_err());
+        assert!(table.get(b"z", 9).is_ok());
+    }
+
+    #[test]
+    fn test_table_filter() {
+        use crate::filter::{BloomFilterPolicy, FilterStats};
+
+        let policy: Arc<dyn FilterPolicy> = Arc::new(BloomFilterPolicy::new(10));
+        let contents = build(&entries(), Some(policy.clone()));
+        let table = open("table_filter", &contents, Some(policy.clone())).unwrap();
+        assert!(table.has_filter());
+
+        // Present keys always pass the filter, whatever their sequence.
+        assert!(table.get(b"key", 300).unwrap().is_some());
+        assert!(table.get(b"key", 7).unwrap().is_some());
+        assert_eq!(table.get(b"key", 0).unwrap(), None);
+        let stats = table.filter_counters().stats();
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.false_positives, 1);
+
+        for i in 0..100 {
+            assert_eq!(
+                table.get(format!("n{:03}", i).as_bytes(), 10).unwrap(),
+                None
+            );
+        }
+        let stats = table.filter_counters().stats();
+        assert!(stats.hits > 90);
+        assert_eq!(stats.hits + stats.misses, 103);
+
+        // Tables without a filter are still readable, just never filtered.
+        let table = open("table_no_filter", &build(&entries(), None), Some(policy)).unwrap();
+        assert!(!table.has_filter());
+        assert_eq!(table.get(b"nope", 10).unwrap(), None);
+        assert_eq!(table.filter_counters().stats(), FilterStats::default());
+    }
+
+    #[test]
+    fn test_table_iter() {
+        let entries = entries();
+        let table = Arc::new(open("table_iter", &build(&entries, None), None).unwrap());
+        let mut it = table.iter();
+        assert!(!it.valid());
+        it.seek_to_first();
+        for (k, seq, t, v) in &entries {
+            assert!(it.valid());
+            assert_eq!(
+                it.key(),
+                ParsedInternalKey::new(k.as_bytes(), *seq, *t).encode()
+            );
+            assert_eq!(it.value(), v.as_bytes());
+            it.next();
+        }
+        assert!(!it.valid());
+        assert!(it.status().is_ok());
+
+        it.seek(&ParsedInternalKey::new(b"key", 6, ValueType::TypeValue).encode());
+        assert_eq!(it.value(), b"v5");
+        it.seek(&ParsedInternalKey::new(b"m199", 1, ValueType::TypeValue).encode());
+        assert_eq!(it.value(), b"z9");
+        it.seek(&ParsedInternalKey::new(b"zz", 1, ValueType::TypeValue).encode());
+        assert!(!it.valid());
+
+        // A corrupt block stops iteration with an error.
+        let mut contents = build(&entries, None);
+        contents[100] ^= 1;
+        let table = Arc::new(open("table_iter_corrupt", &contents, None).unwrap());
+        let mut it = table.iter();
+        it.seek_to_first();
+        while it.valid() {
+            it.next();
+        }
+        assert!(it.status().is_err());
+    }
+}
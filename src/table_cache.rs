@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::filename;
+use crate::filter::{FilterCounters, FilterPolicy};
+use crate::key::InternalKeyComparator;
+use crate::table::builder::TableBuilder;
+use crate::table::reader::Table;
+use crate::version::FileMetaData;
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// Creates the table files of one database and keeps the ones in use open.
+pub(crate) struct TableCache {
+    dir: PathBuf,
+    icmp: InternalKeyComparator,
+    filter_policy: Option<Arc<dyn FilterPolicy>>,
+    /// Shared by every table, so the totals outlive individual files.
+    filter_counters: Arc<FilterCounters>,
+    open: Mutex<HashMap<u64, Arc<Table>>>,
+}
+
+impl TableCache {
+    pub(crate) fn new(
+        dir: &Path,
+        icmp: InternalKeyComparator,
+        filter_policy: Option<Arc<dyn FilterPolicy>>,
+    ) -> TableCache {
+        TableCache {
+            dir: dir.to_path_buf(),
+            icmp,
+            filter_policy,
+            filter_counters: Arc::default(),
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn filter_counters(&self) -> &FilterCounters {
+        &self.filter_counters
+    }
+
+    /// Returns table `number`, opening it on first use.
+    pub(crate) fn get(&self, number: u64) -> Result<Arc<Table>> {
+        if let Some(table) = self.open.lock().unwrap().get(&number) {
+            return Ok(table.clone());
+        }
+        let file = File::open(filename::table_file_name(&self.dir, number))?;
+        let file_size = file.metadata()?.len();
+        let table = Table::open(
+            file,
+            file_size,
+            self.icmp.clone(),
+            self.filter_policy.clone(),
+        )?
+        .with_filter_counters(self.filter_counters.clone());
+
+        let table = Arc::new(table);
+        self.open.lock().unwrap().insert(number, table.clone());
+        Ok(table)
+    }
+
+    /// Forgets table `number`, once it is no longer part of the database.
+    pub(crate) fn evict(&self, number: u64) {
+        self.open.lock().unwrap().remove(&number);
+    }
+
+    /// Starts writing table file `number`.
+    pub(crate) fn create(&self, number: u64) -> Result<TableWriter> {
+        let temp = filename::temp_file_name(&self.dir, number);
+        let file = BufWriter::new(File::create(&temp)?);
+        let mut builder = TableBuilder::new(file, self.icmp.clone());
+        if let Some(policy) = &self.filter_policy {
+            builder = builder.with_filter_policy(policy.clone());
+        }
+        Ok(TableWriter {
+            dir: self.dir.clone(),
+            number,
+            builder,
+            smallest: Vec::new(),
+            largest: Vec::new(),
+        })
+    }
+}
+
+/// A table file being written.
+///
+/// The table is written under a temporary name and renamed once synced, so a
+/// table file that exists is always complete.
+pub(crate) struct TableWriter {
+    dir: PathBuf,
+    number: u64,
+    builder: TableBuilder<BufWriter<File>>,
+    smallest: Vec<u8>,
+    largest: Vec<u8>,
+}
+
+impl TableWriter {
+    /// Adds an entry; keys must be added in increasing order.
+    pub(crate) fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if self.builder.num_entries() == 0 {
+            self.smallest = key.to_vec();
+        }
+        self.largest.clear();
+        self.largest.extend_from_slice(key);
+        self.builder.add(key, value)
+    }
+
+    pub(crate) fn file_size(&self) -> u64 {
+        self.builder.file_size()
+    }
+
+    /// Finishes the table, syncs it and moves it to its final name.
+    pub(crate) fn finish(self) -> Result<FileMetaData> {
+        let (writer, file_size) = self.builder.finish()?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+
+        fs::rename(
+            filename::temp_file_name(&self.dir, self.number),
+            filename::table_file_name(&self.dir, self.number),
+        )?;
+        File::open(&self.dir)?.sync_all()?;
+        Ok(FileMetaData {
+            number: self.number,
+            file_size,
+            smallest: self.smallest,
+            largest: self.largest,
+        })
+    }
+
+    /// Gives up on the table and removes what was written of it.
+    pub(crate) fn abandon(self) {
+        let _ = fs::remove_file(filename::temp_file_name(&self.dir, self.number));
+    }
+}
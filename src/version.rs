@@ -0,0 +1,851 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+
+use integer_encoding::VarInt;
+
+use crate::filename;
+use crate::iterator::InternalIterator;
+use crate::key::{self, InternalKeyComparator, ParsedInternalKey, ValueType, VarintExt};
+use crate::log::{err, LogReader, LogWriter, StatusCode};
+use crate::memtable::LookupResult;
+use crate::options::{CompactionStrategy, Options, SizeTieredOptions};
+use crate::table::reader::{Table, TableIter};
+use crate::table_cache::TableCache;
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+pub(crate) const NUM_LEVELS: usize = 7;
+
+/// Each level from 1 up may hold this many times the bytes of the one before.
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+/// What the manifest records about a table file. `smallest` and `largest` are
+/// internal keys.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct FileMetaData {
+    pub(crate) number: u64,
+    pub(crate) file_size: u64,
+    pub(crate) smallest: Vec<u8>,
+    pub(crate) largest: Vec<u8>,
+}
+
+impl FileMetaData {
+    fn smallest_user_key(&self) -> &[u8] {
+        key::extract_user_key(&self.smallest)
+    }
+
+    fn largest_user_key(&self) -> &[u8] {
+        key::extract_user_key(&self.largest)
+    }
+}
+
+/// A table file that is part of a version.
+pub(crate) struct TableFile {
+    pub(crate) meta: FileMetaData,
+    pub(crate) table: Arc<Table>,
+}
+
+/// A change to the set of table files, as recorded in the manifest.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct VersionEdit {
+    pub(crate) comparator: Option<String>,
+    /// Logs older than this one have been flushed and are no longer needed.
+    pub(crate) log_number: Option<u64>,
+    pub(crate) next_file_number: Option<u64>,
+    pub(crate) last_sequence: Option<u64>,
+    /// (level, file number)
+    pub(crate) deleted_files: Vec<(usize, u64)>,
+    pub(crate) new_files: Vec<(usize, FileMetaData)>,
+}
+
+const TAG_COMPARATOR: usize = 1;
+const TAG_LOG_NUMBER: usize = 2;
+const TAG_NEXT_FILE_NUMBER: usize = 3;
+const TAG_LAST_SEQUENCE: usize = 4;
+const TAG_DELETED_FILE: usize = 6;
+const TAG_NEW_FILE: usize = 7;
+
+impl VersionEdit {
+    /// Encodes the edit as a series of varint tags, each followed by its fields.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let put_bytes = |buf: &mut Vec<u8>, b: &[u8]| {
+            buf.extend_varint(b.len());
+            buf.extend_from_slice(b);
+        };
+        if let Some(name) = &self.comparator {
+            buf.extend_varint(TAG_COMPARATOR);
+            put_bytes(&mut buf, name.as_bytes());
+        }
+        for (tag, value) in [
+            (TAG_LOG_NUMBER, self.log_number),
+            (TAG_NEXT_FILE_NUMBER, self.next_file_number),
+            (TAG_LAST_SEQUENCE, self.last_sequence),
+        ] {
+            if let Some(v) = value {
+                buf.extend_varint(tag);
+                buf.extend_varint(v as usize);
+            }
+        }
+        for &(level, number) in &self.deleted_files {
+            buf.extend_varint(TAG_DELETED_FILE);
+            buf.extend_varint(level);
+            buf.extend_varint(number as usize);
+        }
+        for (level, f) in &self.new_files {
+            buf.extend_varint(TAG_NEW_FILE);
+            buf.extend_varint(*level);
+            buf.extend_varint(f.number as usize);
+            buf.extend_varint(f.file_size as usize);
+            put_bytes(&mut buf, &f.smallest);
+            put_bytes(&mut buf, &f.largest);
+        }
+        buf
+    }
+
+    pub(crate) fn decode(mut src: &[u8]) -> Result<VersionEdit> {
+        fn varint(src: &mut &[u8]) -> Option<u64> {
+            let (v, n) = u64::decode_var(src)?;
+            *src = &src[n..];
+            Some(v)
+        }
+        fn level(src: &mut &[u8]) -> Option<usize> {
+            varint(src).map(|l| l as usize).filter(|&l| l < NUM_LEVELS)
+        }
+        fn bytes<'a>(src: &mut &'a [u8]) -> Option<&'a [u8]> {
+            let len = varint(src)? as usize;
+            let b = src.get(..len)?;
+            *src = &src[len..];
+            Some(b)
+        }
+
+        let mut edit = VersionEdit::default();
+        while !src.is_empty() {
+            let ok = (|| {
+                match varint(&mut src)? as usize {
+                    TAG_COMPARATOR => {
+                        let name = std::str::from_utf8(bytes(&mut src)?).ok()?;
+                        edit.comparator = Some(name.to_string());
+                    }
+                    TAG_LOG_NUMBER => edit.log_number = Some(varint(&mut src)?),
+                    TAG_NEXT_FILE_NUMBER => edit.next_file_number = Some(varint(&mut src)?),
+                    TAG_LAST_SEQUENCE => edit.last_sequence = Some(varint(&mut src)?),
+                    TAG_DELETED_FILE => {
+                        let level = level(&mut src)?;
+                        edit.deleted_files.push((level, varint(&mut src)?));
+                    }
+                    TAG_NEW_FILE => {
+                        let level = level(&mut src)?;
+                        let meta = FileMetaData {
+                            number: varint(&mut src)?,
+                            file_size: varint(&mut src)?,
+                            smallest: bytes(&mut src)?.to_vec(),
+                            largest: bytes(&mut src)?.to_vec(),
+                        };
+                        edit.new_files.push((level, meta));
+                    }
+                    _ => return None,
+                }
+                Some(())
+            })();
+            if ok.is_none() {
+                return err(StatusCode::Corruption, "bad version edit");
+            }
+        }
+        Ok(edit)
+    }
+}
+
+/// The table files making up the database at one point in time. Versions are
+/// immutable; every edit produces a new one.
+#[derive(Default)]
+pub(crate) struct Version {
+    /// Level 0 files may overlap and are ordered newest first, by the newest
+    /// sequence number they hold. The files of every other level are disjoint
+    /// and ordered by key.
+    pub(crate) files: [Vec<Arc<TableFile>>; NUM_LEVELS],
+}
+
+impl Version {
+    /// Looks up the newest version of `user_key` visible at `seq`, searching
+    /// level 0 newest first and then one file per deeper level.
+    pub(crate) fn get(
+        &self,
+        icmp: &InternalKeyComparator,
+        user_key: &[u8],
+        seq: u64,
+    ) -> Result<Option<LookupResult>> {
+        let ucmp = icmp.user_comparator();
+        let covers = |f: &FileMetaData| {
+            ucmp.compare(user_key, f.smallest_user_key()) != Ordering::Less
+                && ucmp.compare(user_key, f.largest_user_key()) != Ordering::Greater
+        };
+
+        for f in &self.files[0] {
+            if covers(&f.meta) {
+                if let Some(found) = f.table.get(user_key, seq)? {
+                    return Ok(Some(found));
+                }
+            }
+        }
+
+        let lookup = ParsedInternalKey::new(user_key, seq, ValueType::TypeValue).encode();
+        for files in &self.files[1..] {
+            let i =
+                files.partition_point(|f| icmp.compare(&f.meta.largest, &lookup) == Ordering::Less);
+            if let Some(f) = files.get(i).filter(|f| covers(&f.meta)) {
+                if let Some(found) = f.table.get(user_key, seq)? {
+                    return Ok(Some(found));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Files in `level` holding any user key in `smallest..=largest`.
+    pub(crate) fn overlapping_files(
+        &self,
+        icmp: &InternalKeyComparator,
+        level: usize,
+        smallest: &[u8],
+        largest: &[u8],
+    ) -> Vec<Arc<TableFile>> {
+        let ucmp = icmp.user_comparator();
+        self.files[level]
+            .iter()
+            .filter(|f| {
+                ucmp.compare(f.meta.largest_user_key(), smallest) != Ordering::Less
+                    && ucmp.compare(f.meta.smallest_user_key(), largest) != Ordering::Greater
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn level_bytes(&self, level: usize) -> u64 {
+        self.files[level].iter().map(|f| f.meta.file_size).sum()
+    }
+
+    /// Numbers of every table file in this version.
+    pub(crate) fn live_files(&self) -> HashSet<u64> {
+        self.files.iter().flatten().map(|f| f.meta.number).collect()
+    }
+
+    /// Iterators over every file, to be merged: one per level 0 file, newest
+    /// first, then one per deeper level.
+    pub(crate) fn iterators(&self, icmp: &InternalKeyComparator) -> Vec<Box<dyn InternalIterator>> {
+        let mut iters: Vec<Box<dyn InternalIterator>> = Vec::new();
+        for f in &self.files[0] {
+            iters.push(Box::new(f.table.iter()));
+        }
+        for files in self.files[1..].iter().filter(|files| !files.is_empty()) {
+            iters.push(Box::new(LevelIter {
+                files: files.clone(),
+                icmp: icmp.clone(),
+                index: 0,
+                current: None,
+            }));
+        }
+        iters
+    }
+
+    /// Returns this version with `edit` applied, opening any new files.
+    fn apply(
+        &self,
+        edit: &VersionEdit,
+        icmp: &InternalKeyComparator,
+        tables: &TableCache,
+    ) -> Result<Version> {
+        let mut files = self.files.clone();
+        for &(level, number) in &edit.deleted_files {
+            files[level].retain(|f| f.meta.number != number);
+        }
+        for (level, meta) in &edit.new_files {
+            files[*level].push(Arc::new(TableFile {
+                meta: meta.clone(),
+                table: tables.get(meta.number)?,
+            }));
+        }
+        // A size-tiered merge writes old data to a new file number, so order by
+        // what the files hold rather than by when they were written.
+        files[0].sort_by_key(|f| std::cmp::Reverse((f.table.largest_sequence(), f.meta.number)));
+        for level in &mut files[1..] {
+            level.sort_by(|a, b| icmp.compare(&a.meta.smallest, &b.meta.smallest));
+        }
+        Ok(Version { files })
+    }
+}
+
+/// Iterates over the disjoint files of a level as one sorted sequence, reading
+/// one table at a time.
+struct LevelIter {
+    files: Vec<Arc<TableFile>>,
+    icmp: InternalKeyComparator,
+    index: usize,
+    current: Option<TableIter>,
+}
+
+impl LevelIter {
+    fn open(&mut self, index: usize) {
+        self.index = index;
+        self.current = self.files.get(index).map(|f| f.table.iter());
+    }
+
+    /// Moves on to the next file while the current one is exhausted. Stops at
+    /// a file that failed, so its error is reported.
+    fn skip_exhausted_files(&mut self) {
+        while let Some(it) = &self.current {
+            if it.valid() || it.status().is_err() {
+                return;
+            }
+            self.open(self.index + 1);
+            if let Some(it) = &mut self.current {
+                it.seek_to_first();
+            }
+        }
+    }
+}
+
+impl InternalIterator for LevelIter {
+    fn valid(&self) -> bool {
+        self.current.as_ref().is_some_and(|it| it.valid())
+    }
+
+    fn seek_to_first(&mut self) {
+        self.open(0);
+        if let Some(it) = &mut self.current {
+            it.seek_to_first();
+        }
+        self.skip_exhausted_files();
+    }
+
+    fn seek(&mut self, target: &[u8]) {
+        let index = self
+            .files
+            .partition_point(|f| self.icmp.compare(&f.meta.largest, target) == Ordering::Less);
+        self.open(index);
+        if let Some(it) = &mut self.current {
+            it.seek(target);
+        }
+        self.skip_exhausted_files();
+    }
+
+    fn next(&mut self) {
+        if let Some(it) = &mut self.current {
+            it.next();
+        }
+        self.skip_exhausted_files();
+    }
+
+    fn key(&self) -> &[u8] {
+        self.current.as_ref().unwrap().key()
+    }
+
+    fn value(&self) -> &[u8] {
+        self.current.as_ref().unwrap().value()
+    }
+
+    fn status(&self) -> Result<()> {
+        self.current.as_ref().map_or(Ok(()), |it| it.status())
+    }
+}
+
+/// A set of files to merge from `level` into `output_level`: the next level
+/// down, or level 0 itself for a size-tiered merge of runs.
+pub(crate) struct Compaction {
+    pub(crate) level: usize,
+    pub(crate) output_level: usize,
+    /// Files from `level` and from `level + 1`.
+    pub(crate) inputs: [Vec<Arc<TableFile>>; 2],
+    /// Outputs are split once they reach this size. A size-tiered merge writes
+    /// one run, so it is never split.
+    pub(crate) max_output_file_size: u64,
+    version: Arc<Version>,
+}
+
+impl Compaction {
+    /// A single file with nothing to merge into can simply move down a level.
+    pub(crate) fn is_trivial_move(&self) -> bool {
+        self.output_level > self.level && self.inputs[0].len() == 1 && self.inputs[1].is_empty()
+    }
+
+    /// Whether no file outside the inputs holds `user_key` below the output,
+    /// in which case a tombstone for it has nothing left to hide.
+    pub(crate) fn is_base_level_for_key(
+        &self,
+        icmp: &InternalKeyComparator,
+        user_key: &[u8],
+    ) -> bool {
+        if self.output_level == 0 {
+            // Runs left out of the merge may be older than it.
+            let inputs: HashSet<u64> = self.inputs[0].iter().map(|f| f.meta.number).collect();
+            let outside = self
+                .version
+                .overlapping_files(icmp, 0, user_key, user_key)
+                .into_iter()
+                .any(|f| !inputs.contains(&f.meta.number));
+            if outside {
+                return false;
+            }
+        }
+        (self.output_level + 1..NUM_LEVELS).all(|level| {
+            self.version
+                .overlapping_files(icmp, level, user_key, user_key)
+                .is_empty()
+        })
+    }
+
+    /// Records the removal of every input file.
+    pub(crate) fn add_input_deletions(&self, edit: &mut VersionEdit) {
+        for (i, inputs) in self.inputs.iter().enumerate() {
+            for f in inputs {
+                edit.deleted_files.push((self.level + i, f.meta.number));
+            }
+        }
+    }
+}
+
+/// How much table data flushes and compactions have written, to compare the
+/// write amplification of compaction strategies.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionStats {
+    pub strategy: CompactionStrategy,
+    /// Bytes of tables written from memtables: every byte written to a table
+    /// for the first time.
+    pub bytes_flushed: u64,
+    /// Bytes of tables read by compactions.
+    pub bytes_read: u64,
+    /// Bytes of tables written by compactions.
+    pub bytes_written: u64,
+    /// Compactions that merged files. Files moved down a level as they are
+    /// are not counted, since they cost no I/O.
+    pub compactions: u64,
+}
+
+impl CompactionStats {
+    pub(crate) fn new(strategy: CompactionStrategy) -> CompactionStats {
+        CompactionStats {
+            strategy,
+            bytes_flushed: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            compactions: 0,
+        }
+    }
+
+    /// Table bytes written per byte flushed, or 0 before the first flush.
+    pub fn write_amplification(&self) -> f64 {
+        if self.bytes_flushed == 0 {
+            return 0.0;
+        }
+        (self.bytes_flushed + self.bytes_written) as f64 / self.bytes_flushed as f64
+    }
+}
+
+/// The current version plus the manifest that records how it came to be.
+///
+/// The manifest is a log (in the `log::LogWriter` format) of `VersionEdit`s.
+/// A new one is started, beginning with a snapshot of the current version,
+/// every time the database is opened, and CURRENT names the one in use.
+pub(crate) struct VersionSet {
+    dir: PathBuf,
+    icmp: InternalKeyComparator,
+    tables: Arc<TableCache>,
+    current: Arc<Version>,
+    /// Versions replaced while still in use, by iterators or compactions. Their
+    /// files must stay on disk until they are dropped.
+    old_versions: Vec<Weak<Version>>,
+    next_file_number: u64,
+    log_number: u64,
+    last_sequence: u64,
+    manifest: Option<LogWriter<BufWriter<File>>>,
+    manifest_number: u64,
+    /// Per level, the largest key of the last compaction, so the next one
+    /// starts after it and compactions rotate through the key space.
+    compact_pointers: [Vec<u8>; NUM_LEVELS],
+    strategy: CompactionStrategy,
+    level0_compaction_trigger: usize,
+    max_bytes_for_level_base: u64,
+    max_file_size: u64,
+}
+
+impl VersionSet {
+    /// Loads the version named by CURRENT, or starts an empty one if there is
+    /// no CURRENT yet. Nothing is written until the first `log_and_apply`.
+    pub(crate) fn recover(
+        dir: &Path,
+        icmp: InternalKeyComparator,
+        tables: Arc<TableCache>,
+        options: &Options,
+    ) -> Result<VersionSet> {
+        let mut set = VersionSet {
+            dir: dir.to_path_buf(),
+            icmp,
+            tables,
+            current: Arc::default(),
+            old_versions: Vec::new(),
+            next_file_number: 1,
+            log_number: 0,
+            last_sequence: 0,
+            manifest: None,
+            manifest_number: 0,
+            compact_pointers: Default::default(),
+            strategy: options.compaction_strategy,
+            // Merging a single run into itself would never end.
+            level0_compaction_trigger: match options.compaction_strategy {
+                CompactionStrategy::Leveled => options.level0_compaction_trigger.max(1),
+                CompactionStrategy::SizeTiered(_) => options.level0_compaction_trigger.max(2),
+            },
+            max_bytes_for_level_base: options.max_bytes_for_level_base.max(1),
+            max_file_size: options.max_file_size,
+        };
+
+        let current = match fs::read_to_string(filename::current_file_name(dir)) {
+            Ok(current) => current,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(set),
+            Err(e) => return Err(e),
+        };
+        let Some((number, filename::FileType::Manifest)) =
+            filename::parse_file_name(current.trim_end_matches('\n'))
+        else {
+            return err(StatusCode::Corruption, "CURRENT does not name a manifest");
+        };
+
+        // Replay the edits into plain file lists; only the files that survive
+        // every edit need to be opened.
+        let mut levels: [Vec<FileMetaData>; NUM_LEVELS] = Default::default();
+        let (mut log_number, mut next_file_number, mut last_sequence) = (None, None, None);
+        let file = File::open(filename::manifest_file_name(dir, number))?;
+        let mut reader = LogReader::new(BufReader::new(file), true);
+        let mut record = Vec::new();
+        while reader.read(&mut record)? > 0 {
+            let edit = VersionEdit::decode(&record)?;
+            if let Some(name) = &edit.comparator {
+                if name != set.icmp.user_comparator().name() {
+                    let msg = format!("database was created with comparator {}", name);
+                    return err(StatusCode::Corruption, &msg);
+                }
+            }
+            for &(level, number) in &edit.deleted_files {
+                levels[level].retain(|f| f.number != number);
+            }
+            for (level, meta) in edit.new_files {
+                levels[level].push(meta);
+            }
+            log_number = edit.log_number.or(log_number);
+            next_file_number = edit.next_file_number.or(next_file_number);
+            last_sequence = edit.last_sequence.or(last_sequence);
+        }
+
+        let (Some(log_number), Some(next_file_number), Some(last_sequence)) =
+            (log_number, next_file_number, last_sequence)
+        else {
+            return err(
+                StatusCode::Corruption,
+                "manifest is missing required fields",
+            );
+        };
+        let mut snapshot = VersionEdit::default();
+        for (level, files) in levels.into_iter().enumerate() {
+            snapshot
+                .new_files
+                .extend(files.into_iter().map(|f| (level, f)));
+        }
+        set.current = Arc::new(Version::default().apply(&snapshot, &set.icmp, &set.tables)?);
+        set.log_number = log_number;
+        set.next_file_number = next_file_number.max(number + 1);
+        set.last_sequence = last_sequence;
+        Ok(set)
+    }
+
+    pub(crate) fn current(&self) -> Arc<Version> {
+        self.current.clone()
+    }
+
+    /// Numbers of the table files in the current version or in any older
+    /// version still in use.
+    pub(crate) fn live_files(&mut self) -> HashSet<u64> {
+        let mut live = self.current.live_files();
+        self.old_versions.retain(|version| match version.upgrade() {
+            Some(version) => {
+                live.extend(version.live_files());
+                true
+            }
+            None => false,
+        });
+        live
+    }
+
+    pub(crate) fn new_file_number(&mut self) -> u64 {
+        self.next_file_number += 1;
+        self.next_file_number - 1
+    }
+
+    /// Makes sure numbers up to `number`, found on disk, are never reused.
+    pub(crate) fn mark_file_number_used(&mut self, number: u64) {
+        self.next_file_number = self.next_file_number.max(number + 1);
+    }
+
+    /// The oldest log still needed to recover the memtables.
+    pub(crate) fn log_number(&self) -> u64 {
+        self.log_number
+    }
+
+    /// The last sequence number recorded in the manifest.
+    pub(crate) fn last_sequence(&self) -> u64 {
+        self.last_sequence
+    }
+
+    pub(crate) fn manifest_number(&self) -> u64 {
+        self.manifest_number
+    }
+
+    /// Applies `edit` to the current version, records it in the manifest and
+    /// makes the result current. The manifest is synced before the new version
+    /// is installed, so a version that readers can see survives a crash.
+    pub(crate) fn log_and_apply(
+        &mut self,
+        edit: &mut VersionEdit,
+        last_sequence: u64,
+    ) -> Result<()> {
+        let mut new_manifest = None;
+        if self.manifest.is_none() {
+            new_manifest = Some(self.new_file_number());
+        }
+        edit.log_number = edit.log_number.or(Some(self.log_number));
+        edit.next_file_number = Some(self.next_file_number);
+        edit.last_sequence = Some(last_sequence);
+        let version = self.current.apply(edit, &self.icmp, &self.tables)?;
+
+        if let Some(number) = new_manifest {
+            let file = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(filename::manifest_file_name(&self.dir, number))?;
+            let mut writer = LogWriter::new(BufWriter::new(file));
+            writer.add_record(&self.snapshot().encode())?;
+            self.manifest = Some(writer);
+        }
+        let manifest = self.manifest.as_mut().unwrap();
+        manifest.add_record(&edit.encode())?;
+        manifest.flush()?;
+        manifest.get_ref().get_ref().sync_data()?;
+
+        if let Some(number) = new_manifest {
+            filename::set_current_file(&self.dir, number)?;
+            self.manifest_number = number;
+        }
+        let old = std::mem::replace(&mut self.current, Arc::new(version));
+        if Arc::strong_count(&old) > 1 {
+            self.old_versions.push(Arc::downgrade(&old));
+        }
+        self.log_number = edit.log_number.unwrap();
+        self.last_sequence = last_sequence;
+        Ok(())
+    }
+
+    /// An edit that recreates the current version from nothing.
+    fn snapshot(&self) -> VersionEdit {
+        let mut edit = VersionEdit {
+            comparator: Some(self.icmp.user_comparator().name().to_string()),
+            ..VersionEdit::default()
+        };
+        for (level, files) in self.current.files.iter().enumerate() {
+            for f in files {
+                edit.new_files.push((level, f.meta.clone()));
+            }
+        }
+        edit
+    }
+
+    fn max_bytes_for_level(&self, level: usize) -> u64 {
+        (1..level).fold(self.max_bytes_for_level_base, |bytes, _| {
+            bytes.saturating_mul(LEVEL_SIZE_MULTIPLIER)
+        })
+    }
+
+    /// The level most in need of compaction and how badly, where 1.0 means it
+    /// has reached its limit.
+    fn compaction_score(&self) -> (usize, f64) {
+        let mut best = (0, 0.0);
+        // The last level has nowhere to compact into, and size-tiered
+        // compaction only ever merges level 0.
+        let levels = match self.strategy {
+            CompactionStrategy::Leveled => NUM_LEVELS - 1,
+            CompactionStrategy::SizeTiered(_) => 1,
+        };
+        for level in 0..levels {
+            let score = if level == 0 {
+                // Level 0 is limited by file count: every file is searched on reads.
+                self.current.files[0].len() as f64 / self.level0_compaction_trigger as f64
+            } else {
+                self.current.level_bytes(level) as f64 / self.max_bytes_for_level(level) as f64
+            };
+            if score > best.1 {
+                best = (level, score);
+            }
+        }
+        best
+    }
+
+    #[cfg(test)]
+    pub(crate) fn needs_compaction(&self) -> bool {
+        self.compaction_score().1 >= 1.0
+    }
+
+    /// Chooses the next compaction, if any level is over its limit.
+    pub(crate) fn pick_compaction(&mut self) -> Option<Compaction> {
+        let (level, score) = self.compaction_score();
+        if score < 1.0 {
+            return None;
+        }
+        match self.strategy {
+            CompactionStrategy::Leveled => Some(self.pick_leveled(level)),
+            CompactionStrategy::SizeTiered(options) => Some(self.pick_size_tiered(options)),
+        }
+    }
+
+    /// Picks files of `level` and the files they overlap one level down.
+    fn pick_leveled(&mut self, level: usize) -> Compaction {
+        let version = self.current.clone();
+        let icmp = &self.icmp;
+        let ucmp = icmp.user_comparator();
+
+        // Start with the first file past where the last compaction of this
+        // level stopped, wrapping around to the beginning.
+        let files = &version.files[level];
+        let pointer = &self.compact_pointers[level];
+        let first = files
+            .iter()
+            .find(|f| {
+                pointer.is_empty() || icmp.compare(&f.meta.largest, pointer) == Ordering::Greater
+            })
+            .unwrap_or(&files[0]);
+        let mut inputs = vec![first.clone()];
+
+        let range = |files: &[Arc<TableFile>]| {
+            let mut smallest = files[0].meta.smallest_user_key();
+            let mut largest = files[0].meta.largest_user_key();
+            for f in files {
+                if ucmp.compare(f.meta.smallest_user_key(), smallest) == Ordering::Less {
+                    smallest = f.meta.smallest_user_key();
+                }
+                if ucmp.compare(f.meta.largest_user_key(), largest) == Ordering::Greater {
+                    largest = f.meta.largest_user_key();
+                }
+            }
+            (smallest.to_vec(), largest.to_vec())
+        };
+
+        // Level 0 files overlap each other, so take every one that overlaps,
+        // growing the range until it stops changing.
+        if level == 0 {
+            loop {
+                let (smallest, largest) = range(&inputs);
+                let overlapping = version.overlapping_files(icmp, 0, &smallest, &largest);
+                if overlapping.len() == inputs.len() {
+                    break;
+                }
+                inputs = overlapping;
+            }
+        }
+
+        let (smallest, largest) = range(&inputs);
+        let next = version.overlapping_files(icmp, level + 1, &smallest, &largest);
+        self.compact_pointers[level] = inputs
+            .iter()
+            .map(|f| &f.meta.largest)
+            .max_by(|a, b| icmp.compare(a, b))
+            .unwrap()
+            .clone();
+
+        Compaction {
+            level,
+            output_level: level + 1,
+            inputs: [inputs, next],
+            max_output_file_size: self.max_file_size,
+            version,
+        }
+    }
+
+    /// Picks consecutive level 0 runs to merge into one.
+    ///
+    /// Going from the newest run to older ones, a run joins while it is at most
+    /// `size_ratio` percent bigger than the runs already picked together, so
+    /// small fresh runs are merged until they are as big as the next one. If no
+    /// `min_merge_width` runs qualify, the newest runs are merged anyway, just
+    /// enough to bring the run count back under the trigger.
+    fn pick_size_tiered(&self, options: SizeTieredOptions) -> Compaction {
+        let runs = &self.current.files[0];
+        let max_width = options.max_merge_width.max(2);
+        let min_width = options.min_merge_width.clamp(2, max_width);
+
+        let mut picked = None;
+        for start in 0..runs.len() {
+            let mut total = runs[start].meta.file_size;
+            let mut end = start + 1;
+            while end < runs.len() && end - start < max_width {
+                let size = runs[end].meta.file_size;
+                if size as u128 * 100 > total as u128 * (100 + options.size_ratio as u128) {
+                    break;
+                }
+                total += size;
+                end += 1;
+            }
+            if end - start >= min_width {
+                picked = Some(start..end);
+                break;
+            }
+        }
+        let picked = picked.unwrap_or_else(|| {
+            let width = runs.len() + 2 - self.level0_compaction_trigger;
+            0..width.clamp(2, max_width).min(runs.len())
+        });
+
+        Compaction {
+            level: 0,
+            output_level: 0,
+            inputs: [runs[picked].to_vec(), Vec::new()],
+            max_output_file_size: u64::MAX,
+            version: self.current.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(number: u64, smallest: &str, largest: &str) -> FileMetaData {
+        FileMetaData {
+            number,
+            file_size: 1000 + number,
+            smallest: ParsedInternalKey::new(smallest.as_bytes(), 9, ValueType::TypeValue).encode(),
+            largest: ParsedInternalKey::new(largest.as_bytes(), 1, ValueType::TypeDeletion)
+                .encode(),
+        }
+    }
+
+    #[test]
+    fn test_version_edit_roundtrip() {
+        let edit = VersionEdit {
+            comparator: Some("wdis.BytewiseComparator".to_string()),
+            log_number: Some(12),
+            next_file_number: Some(1 << 40),
+            last_sequence: Some(u64::MAX >> 8),
+            deleted_files: vec![(0, 3), (6, 4)],
+            new_files: vec![(1, meta(5, "a", "m")), (2, meta(6, "n", "z"))],
+        };
+        let encoded = edit.encode();
+        assert_eq!(VersionEdit::decode(&encoded).unwrap(), edit);
+        assert_eq!(VersionEdit::decode(&[]).unwrap(), VersionEdit::default());
+
+        assert!(VersionEdit::decode(&encoded[..encoded.len() - 1]).is_err());
+        assert!(VersionEdit::decode(&[99]).is_err());
+        // Levels past the last one are rejected.
+        assert!(VersionEdit::decode(&[TAG_DELETED_FILE as u8, NUM_LEVELS as u8, 1]).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use byteorder::{ByteOrder, LittleEndian};
+use integer_encoding::VarInt;
+
+use crate::key::{ValueType, VarintExt};
+use crate::log::{err, StatusCode};
+use crate::memtable::MemTable;
+
+type Result<T> = std::result::Result<T, std::io::Error>;
+
+/// `seq: u64 LE | count: u32 LE`
+const HEADER_SIZE: usize = 12;
+
+/// A set of puts and deletes applied atomically.
+///
+/// The serialized form is what gets written to the log as a single record:
+///
+/// ```text
+/// seq: u64 LE | count: u32 LE | entry*
+/// entry := TypeValue    varint key len | key | varint value len | value
+///        | TypeDeletion varint key len | key
+/// ```
+///
+/// Entry `i` is applied with sequence number `seq + i`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteBatch {
+    rep: Vec<u8>,
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch {
+            rep: vec![0; HEADER_SIZE],
+        }
+    }
+
+    /// Parses a serialized batch, checking that every entry is well formed and
+    /// that the entry count matches the header.
+    pub fn from_contents(rep: Vec<u8>) -> Result<WriteBatch> {
+        if rep.len() < HEADER_SIZE {
+            return err(StatusCode::Corruption, "write batch too small");
+        }
+        let batch = WriteBatch { rep };
+
+        let mut entries = &batch.rep[HEADER_SIZE..];
+        let mut found = 0;
+        while !entries.is_empty() {
+            match parse_entry(entries) {
+                Some((_, rest)) => entries = rest,
+                None => return err(StatusCode::Corruption, "malformed write batch entry"),
+            }
+            found += 1;
+        }
+        if found != batch.count() {
+            return err(StatusCode::Corruption, "write batch has wrong count");
+        }
+        Ok(batch)
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.set_count(self.count() + 1);
+        self.rep.push(ValueType::TypeValue as u8);
+        self.rep.extend_varint(key.len());
+        self.rep.extend_from_slice(key);
+        self.rep.extend_varint(value.len());
+        self.rep.extend_from_slice(value);
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.set_count(self.count() + 1);
+        self.rep.push(ValueType::TypeDeletion as u8);
+        self.rep.extend_varint(key.len());
+        self.rep.extend_from_slice(key);
+    }
+
+    /// Appends every entry of `other`, keeping this batch's sequence number.
+    pub fn append(&mut self, other: &WriteBatch) {
+        self.set_count(self.count() + other.count());
+        self.rep.extend_from_slice(&other.rep[HEADER_SIZE..]);
+    }
+
+    pub fn clear(&mut self) {
+        self.rep.clear();
+        self.rep.resize(HEADER_SIZE, 0);
+    }
+
+    /// Number of entries in the batch.
+    pub fn count(&self) -> u32 {
+        LittleEndian::read_u32(&self.rep[8..HEADER_SIZE])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    /// Sequence number assigned to the first entry.
+    pub fn sequence(&self) -> u64 {
+        LittleEndian::read_u64(&self.rep[..8])
+    }
+
+    pub fn set_sequence(&mut self, seq: u64) {
+        LittleEndian::write_u64(&mut self.rep[..8], seq);
+    }
+
+    /// The serialized batch.
+    pub fn contents(&self) -> &[u8] {
+        &self.rep
+    }
+
+    pub fn approximate_size(&self) -> usize {
+        self.rep.len()
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            entries: &self.rep[HEADER_SIZE..],
+        }
+    }
+
+    /// Adds every entry to `mem` with consecutive sequence numbers starting at
+    /// `sequence()`. Stops at the first entry that does not fit.
+    pub fn insert_into(&self, mem: &MemTable) -> Result<()> {
+        for (seq, (t, key, value)) in (self.sequence()..).zip(self.iter()) {
+            mem.add(seq, t, key, value)?;
+        }
+        Ok(())
+    }
+
+    fn set_count(&mut self, count: u32) {
+        LittleEndian::write_u32(&mut self.rep[8..HEADER_SIZE], count);
+    }
+}
+
+/// A `(type, key, value)` entry. Deletions have an empty value.
+pub type Entry<'a> = (ValueType, &'a [u8], &'a [u8]);
+
+pub struct Iter<'a> {
+    entries: &'a [u8],
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Entry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (entry, rest) = parse_entry(self.entries)?;
+        self.entries = rest;
+        Some(entry)
+    }
+}
+
+/// Parses the entry at the front of `buf`, returning it and the remaining bytes.
+fn parse_entry(buf: &[u8]) -> Option<(Entry<'_>, &[u8])> {
+    fn slice(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+        let (len, n) = usize::decode_var(buf)?;
+        let end = n.checked_add(len)?;
+        Some((buf.get(n..end)?, &buf[end..]))
+    }
+
+    let t = ValueType::try_from(*buf.first()?).ok()?;
+    let (key, rest) = slice(&buf[1..])?;
+    match t {
+        ValueType::TypeValue => {
+            let (value, rest) = slice(rest)?;
+            Some(((t, key, value), rest))
+        }
+        ValueType::TypeDeletion => Some(((t, key, &[]), rest)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memtable::LookupResult;
+
+    #[test]
+    fn test_write_batch() {
+        let mut b = WriteBatch::new();
+        assert!(b.is_empty());
+        b.put(b"k1", b"v1");
+        b.delete(b"k2");
+        b.put(b"k1", b"v1'");
+        b.set_sequence(100);
+        assert_eq!(b.count(), 3);
+        assert_eq!(b.sequence(), 100);
+
+        let entries: Vec<_> = b.iter().collect();
+        assert_eq!(
+            entries,
+            vec![
+                (ValueType::TypeValue, &b"k1"[..], &b"v1"[..]),
+                (ValueType::TypeDeletion, &b"k2"[..], &b""[..]),
+                (ValueType::TypeValue, &b"k1"[..], &b"v1'"[..]),
+            ]
+        );
+
+        let decoded = WriteBatch::from_contents(b.contents().to_vec()).unwrap();
+        assert_eq!(decoded, b);
+
+        b.clear();
+        assert_eq!(b.count(), 0);
+        assert_eq!(b.approximate_size(), HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_write_batch_append() {
+        let mut a = WriteBatch::new();
+        a.put(b"a", b"1");
+        a.set_sequence(7);
+        let mut b = WriteBatch::new();
+        b.delete(b"b");
+        b.put(b"c", b"3");
+        b.set_sequence(50);
+
+        a.append(&b);
+        assert_eq!(a.count(), 3);
+        assert_eq!(a.sequence(), 7);
+        assert_eq!(a.iter().map(|(_, k, _)| k).collect::<Vec<_>>(), vec![b"a", b"b", b"c"]);
+    }
+
+    #[test]
+    fn test_write_batch_corrupt() {
+        let mut b = WriteBatch::new();
+        b.put(b"key", b"value");
+        b.delete(b"gone");
+        let rep = b.contents().to_vec();
+
+        assert!(WriteBatch::from_contents(rep[..HEADER_SIZE - 1].to_vec()).is_err());
+        // Any cut inside the entries leaves a malformed entry or a short count.
+        for cut in HEADER_SIZE..rep.len() {
+            assert!(WriteBatch::from_contents(rep[..cut].to_vec()).is_err());
+        }
+        let mut wrong_count = rep.clone();
+        wrong_count[8] = 3;
+        assert!(WriteBatch::from_contents(wrong_count).is_err());
+    }
+
+    #[test]
+    fn test_write_batch_insert_into() {
+        let mut b = WriteBatch::new();
+        b.put(b"a", b"1");
+        b.put(b"a", b"2");
+        b.delete(b"b");
+        b.set_sequence(10);
+
+        let mem = MemTable::new();
+        mem.add(1, ValueType::TypeValue, b"b", b"old").unwrap();
+        b.insert_into(&mem).unwrap();
+        assert_eq!(mem.get(b"a", 10), Some(LookupResult::Value(b"1".to_vec())));
+        assert_eq!(mem.get(b"a", 11), Some(LookupResult::Value(b"2".to_vec())));
+        assert_eq!(mem.get(b"b", 11), Some(LookupResult::Value(b"old".to_vec())));
+        assert_eq!(mem.get(b"b", 12), Some(LookupResult::Deleted));
+    }
+}
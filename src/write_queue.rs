@@ -0,0 +1,272 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Condvar, Mutex, PoisonError};
+
+use crate::write_batch::WriteBatch;
+
+type Result<T> = std::result::Result<T, io::Error>;
+
+/// Group sizes are bucketed by powers of two: 1, 2, 3-4, 5-8, ...
+const HISTOGRAM_BUCKETS: usize = 12;
+
+/// How concurrent writes were grouped into log records.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    /// Log records written, one per group.
+    pub groups: u64,
+    /// Batches committed through those groups.
+    pub batches: u64,
+    /// Largest number of batches committed as one group.
+    pub largest_group: usize,
+    /// `histogram[i]` counts groups of `2^(i-1) + 1 ..= 2^i` batches; the last
+    /// bucket also holds every larger group.
+    pub histogram: [u64; HISTOGRAM_BUCKETS],
+}
+
+impl WriteStats {
+    pub fn average_group_size(&self) -> f64 {
+        match self.groups {
+            0 => 0.0,
+            groups => self.batches as f64 / groups as f64,
+        }
+    }
+
+    fn record(&mut self, size: usize) {
+        self.groups += 1;
+        self.batches += size as u64;
+        self.largest_group = self.largest_group.max(size);
+        let bucket = size.next_power_of_two().trailing_zeros() as usize;
+        self.histogram[bucket.min(HISTOGRAM_BUCKETS - 1)] += 1;
+    }
+}
+
+struct Pending {
+    id: u64,
+    batch: WriteBatch,
+}
+
+type GroupResult = std::result::Result<(), (io::ErrorKind, String)>;
+
+struct QueueState {
+    next_id: u64,
+    pending: VecDeque<Pending>,
+    leader_active: bool,
+    /// Outcome of finished writes, removed by their owners. `io::Error` is not
+    /// `Clone`, so a failed group hands each member the kind and message.
+    done: HashMap<u64, GroupResult>,
+    stats: WriteStats,
+}
+
+/// A leader/follower group commit queue.
+///
+/// Writers enqueue their batch and wait. Whenever no commit is in progress, one
+/// waiting writer becomes the leader: it takes up to `max_group_size` batches
+/// from the front of the queue, merges them into a single batch, commits it once
+/// and then wakes every writer whose batch was part of the group.
+pub(crate) struct WriteQueue {
+    state: Mutex<QueueState>,
+    cv: Condvar,
+    max_group_size: usize,
+}
+
+impl WriteQueue {
+    pub(crate) fn new(max_group_size: usize) -> WriteQueue {
+        WriteQueue {
+            state: Mutex::new(QueueState {
+                next_id: 0,
+                pending: VecDeque::new(),
+                leader_active: false,
+                done: HashMap::new(),
+                stats: WriteStats::default(),
+            }),
+            cv: Condvar::new(),
+            max_group_size: max_group_size.max(1),
+        }
+    }
+
+    /// Queues `batch` and blocks until a leader has committed it. `commit` is
+    /// called with a merged group of batches, and is only ever run by one
+    /// thread at a time.
+    pub(crate) fn write<F>(&self, batch: WriteBatch, mut commit: F) -> Result<()>
+    where
+        F: FnMut(WriteBatch) -> Result<()>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.pending.push_back(Pending { id, batch });
+
+        loop {
+            if let Some(result) = state.done.remove(&id) {
+                return result.map_err(|(kind, msg)| io::Error::new(kind, msg));
+            }
+            if state.leader_active || state.pending.is_empty() {
+                state = self.cv.wait(state).unwrap();
+                continue;
+            }
+
+            // Become the leader for the batches at the front of the queue.
+            state.leader_active = true;
+            let n = state.pending.len().min(self.max_group_size);
+            let group: Vec<Pending> = state.pending.drain(..n).collect();
+            drop(state);
+
+            let mut leader = Leader {
+                queue: self,
+                id,
+                ids: group.iter().map(|p| p.id).collect(),
+                result: None,
+            };
+            let mut batches = group.into_iter().map(|p| p.batch);
+            let mut merged = batches.next().unwrap();
+            for b in batches {
+                merged.append(&b);
+            }
+            leader.result = Some(commit(merged).map_err(|e| (e.kind(), e.to_string())));
+            drop(leader);
+
+            state = self.state.lock().unwrap();
+        }
+    }
+
+    pub(crate) fn stats(&self) -> WriteStats {
+        self.state.lock().unwrap().stats.clone()
+    }
+}
+
+/// Hands the outcome of a group to its members and steps down when dropped,
+/// which also happens if `commit` panics: the members then get an error
+/// instead of waiting forever, and the next leader may start.
+struct Leader<'a> {
+    queue: &'a WriteQueue,
+    /// The leader's own batch, whose outcome nobody waits for if it panics.
+    id: u64,
+    ids: Vec<u64>,
+    /// `None` until `commit` returns.
+    result: Option<GroupResult>,
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        let mut state = self
+            .queue
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let result = match self.result.take() {
+            Some(result) => {
+                state.stats.record(self.ids.len());
+                result
+            }
+            None => {
+                self.ids.retain(|&member| member != self.id);
+                Err((io::ErrorKind::Other, "write leader panicked".to_string()))
+            }
+        };
+        for &member in &self.ids {
+            state.done.insert(member, result.clone());
+        }
+        state.leader_active = false;
+        self.queue.cv.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_write_queue_groups() {
+        let queue = Arc::new(WriteQueue::new(8));
+        let committed = Arc::new(Mutex::new(0u32));
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let queue = queue.clone();
+                let committed = committed.clone();
+                thread::spawn(move || {
+                    for j in 0..100 {
+                        let mut batch = WriteBatch::new();
+                        batch.put(format!("{}-{}", i, j).as_bytes(), b"v");
+                        queue
+                            .write(batch, |merged| {
+                                assert!(merged.count() as usize <= 8);
+                                *committed.lock().unwrap() += merged.count();
+                                Ok(())
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let stats = queue.stats();
+        assert_eq!(*committed.lock().unwrap(), 800);
+        assert_eq!(stats.batches, 800);
+        assert!(stats.groups <= 800);
+        assert!(stats.largest_group <= 8);
+        assert_eq!(stats.histogram.iter().sum::<u64>(), stats.groups);
+    }
+
+    #[test]
+    fn test_write_queue_leader_panic() {
+        let queue = Arc::new(WriteQueue::new(4));
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let first = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                queue.write(WriteBatch::new(), |_| {
+                    released.recv().unwrap();
+                    Ok(())
+                })
+            })
+        };
+        let wait_for = |ready: &dyn Fn(&QueueState) -> bool| {
+            while !ready(&queue.state.lock().unwrap()) {
+                thread::yield_now();
+            }
+        };
+        wait_for(&|state| state.leader_active);
+
+        // Two writers queue up behind the first and form the next group,
+        // whose leader panics whichever of them it is.
+        let panicking: Vec<_> = (0..2)
+            .map(|_| {
+                let queue = queue.clone();
+                thread::spawn(move || queue.write(WriteBatch::new(), |_| panic!("commit failed")))
+            })
+            .collect();
+        wait_for(&|state| state.pending.len() == 2);
+        release.send(()).unwrap();
+        first.join().unwrap().unwrap();
+
+        let outcomes: Vec<_> = panicking.into_iter().map(|h| h.join()).collect();
+        assert_eq!(outcomes.iter().filter(|o| o.is_err()).count(), 1);
+        let follower = outcomes.into_iter().find_map(|o| o.ok()).unwrap();
+        assert_eq!(follower.unwrap_err().to_string(), "write leader panicked");
+
+        assert!(queue.write(WriteBatch::new(), |_| Ok(())).is_ok());
+        assert_eq!(queue.stats().groups, 2);
+        assert!(queue.state.lock().unwrap().done.is_empty());
+    }
+
+    #[test]
+    fn test_write_queue_error() {
+        let queue = WriteQueue::new(4);
+        let err = queue
+            .write(WriteBatch::new(), |_| Err(io::Error::other("disk full")))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "disk full");
+
+        assert!(queue.write(WriteBatch::new(), |_| Ok(())).is_ok());
+        let stats = queue.stats();
+        assert_eq!(stats.groups, 2);
+        assert_eq!(stats.histogram[0], 2);
+        assert_eq!(stats.average_group_size(), 1.0);
+    }
+}